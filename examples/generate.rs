@@ -0,0 +1,51 @@
+use std::env;
+use std::io::{self, BufWriter, Write};
+
+/// Generates a CSV input of the given number of rows, 2 million by default, for benchmarks; the rows
+/// are the same on every run, a mix of deposits and withdrawals of 1000 clients with some of them
+/// disputed, resolved and charged back, padded with spaces as in the specification
+///
+/// `cargo run --release --example generate -- 2000000 > transactions.csv`
+fn main() -> io::Result<()> {
+    let rows: u64 = match env::args().nth(1) {
+        Some(rows) => rows.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "rows must be a number"))?,
+        None => 2_000_000,
+    };
+    let mut sink = BufWriter::new(io::stdout().lock());
+    let mut random = Random(0x2545_f491_4f6c_dd1d);
+
+    writeln!(sink, "type, client, tx, amount")?;
+    for tx in 1..=rows {
+        let client = random.below(1000) + 1;
+        match random.below(100) {
+            0..=69 => {
+                let amount = random.below(99_999) + 1;
+                writeln!(sink, "deposit, {}, {}, {}.{:04}", client, tx, amount / 10_000, amount % 10_000)?;
+            },
+            // Smaller, so most of them are applied
+            70..=89 => writeln!(sink, "withdrawal, {}, {}, 0.{:04}", client, tx, random.below(9_999) + 1)?,
+            // Of some earlier transaction, which may not be the client's
+            kind => {
+                let operation = match kind {
+                    90..=95 => "dispute",
+                    96..=98 => "resolve",
+                    _ => "chargeback",
+                };
+                writeln!(sink, "{}, {}, {},", operation, client, random.below(tx) + 1)?;
+            },
+        }
+    }
+    sink.flush()
+}
+
+/// Xorshift generator, so the input is reproducible without depending on a random crate
+struct Random(u64);
+
+impl Random {
+    fn below(&mut self, bound: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % bound
+    }
+}
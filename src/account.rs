@@ -1,61 +1,316 @@
-use std::collections::{HashMap};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+use std::time::Duration;
 
-use log::trace;
+use log::{trace, warn};
 use rust_decimal::{Decimal};
 use serde::Serialize;
+use parse_display::{Display, FromStr};
 
+use crate::codec::{self, Fields};
 use crate::errors::TransactionSystemError;
-use crate::instructions::{Instruction, Transaction, Operation};
+use crate::history::TxHistory;
+use crate::instructions::{Instruction, Transaction, TransactionState, Operation, Administration, LockReason};
 use crate::result::Result;
 
+/// Matrix of instruction types a locked (frozen) account still accepts; refused ones fail with
+/// `AccountLockedError`. By default a frozen account can't be funded nor drained, but disputes
+/// opened before the lock can still be resolved or charged back. Administrative instructions are
+/// always accepted.
+///
+/// Parsed from the comma separated types accepted, like `resolve,chargeback`, or `none`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockedPolicy {
+    pub deposit: bool,
+    pub withdrawal: bool,
+    pub dispute: bool,
+    pub resolve: bool,
+    pub chargeback: bool,
+}
+
+impl Default for LockedPolicy {
+    fn default() -> Self {
+        Self { deposit: false, withdrawal: false, dispute: false, resolve: true, chargeback: true }
+    }
+}
+
+impl LockedPolicy {
+    pub fn accepts(&self, instruction: &Instruction) -> bool {
+        match instruction {
+            Instruction::Deposit(_)    => self.deposit,
+            Instruction::Withdrawal(_) => self.withdrawal,
+            Instruction::Dispute(_)    => self.dispute,
+            Instruction::Resolve(_)    => self.resolve,
+            Instruction::Chargeback(_) => self.chargeback,
+            Instruction::Freeze(_) | Instruction::Unlock(_) => true,
+        }
+    }
+}
+
+impl FromStr for LockedPolicy {
+    type Err = TransactionSystemError;
+
+    fn from_str(text: &str) -> std::result::Result<Self, Self::Err> {
+        let mut policy = Self { deposit: false, withdrawal: false, dispute: false, resolve: false, chargeback: false };
+        if text == "none" {
+            return Ok(policy);
+        }
+        for typ in text.split(',') {
+            let accepted = match typ.trim() {
+                "deposit"    => &mut policy.deposit,
+                "withdrawal" => &mut policy.withdrawal,
+                "dispute"    => &mut policy.dispute,
+                "resolve"    => &mut policy.resolve,
+                "chargeback" => &mut policy.chargeback,
+                _ => return Err(TransactionSystemError::ArgumentsError(format!("unknown instruction type {:?}", typ))),
+            };
+            *accepted = true;
+        }
+        Ok(policy)
+    }
+}
+
+/// Funds movement for disputes of withdrawals, which are recorded with negative amounts
+#[derive(Debug, Display, FromStr, Default, Clone, Copy, PartialEq, Eq)]
+#[display(style = "kebab-case")]
+pub enum WithdrawalDisputePolicy {
+    /// Dispute holds the withdrawn amount as a provisional credit, increasing held and total funds.
+    /// Resolve drops the credit as the withdrawal stands, chargeback releases it to available funds.
+    #[default]
+    ProvisionalCredit,
+    /// Withdrawal is disputed with its negative amount the same way as deposit, so held funds go
+    /// below zero; kept for compatibility with results of earlier versions.
+    Negated,
+}
+
+/// Handling of disputes of deposits for which the available funds don't suffice anymore
+#[derive(Debug, Display, FromStr, Default, Clone, Copy, PartialEq, Eq)]
+#[display(style = "kebab-case")]
+pub enum OverdraftPolicy {
+    /// Whole disputed amount is held, driving available funds below zero; a warning is logged
+    #[default]
+    AllowNegative,
+    /// Dispute fails with `InsufficientFundsError`
+    RejectIfInsufficient,
+    /// Only what's available is held, the shortfall is recorded as the account's receivable
+    HoldPartial,
+}
+
+/// How an applied instruction affected the account, when it deserves notice: disputes of deposits
+/// exceeding the available funds, under `OverdraftPolicy::AllowNegative` or `HoldPartial`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    #[default]
+    Applied,
+    /// Whole disputed amount held, driving available funds below zero
+    NegativeAvailable,
+    /// Only available funds held, the shortfall recorded as receivable
+    HeldPartially { shortfall: Decimal },
+}
+
+impl Outcome {
+    /// Stable code of the notice, as reported among rejects, `None` for instructions plainly applied
+    pub fn code(&self) -> Option<&'static str> {
+        match self {
+            Outcome::Applied => None,
+            Outcome::NegativeAvailable => Some("NEGATIVE_AVAILABLE"),
+            Outcome::HeldPartially { .. } => Some("HELD_PARTIALLY"),
+        }
+    }
+}
+
+/// How long deposits and withdrawals are kept in the account history to be disputed; disputes of
+/// transactions evicted from it fail with `EvictedTransactionError`. Transactions under dispute are
+/// kept until it's settled, whatever the retention.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Retention {
+    #[default]
+    Unlimited,
+    /// Only the given number of the latest transactions of every account are kept
+    LastPerAccount(usize),
+    /// Transactions are kept while no more than the given number of instructions were processed
+    /// after them, counting the instructions of all the accounts
+    Instructions(u64),
+}
+
+/// Rules the accounts follow while applying instructions
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Policy {
+    pub locked: LockedPolicy,
+    pub withdrawal_dispute: WithdrawalDisputePolicy,
+    pub overdraft: OverdraftPolicy,
+    pub retention: Retention,
+    /// Time after the transaction past which its disputes fail with `ExpiredDisputeError`; enforced
+    /// only when both the transaction and the dispute have timestamps
+    pub dispute_window: Option<Duration>,
+}
+
+impl Policy {
+    fn credits_withdrawal(&self, entry: &Transaction) -> bool {
+        entry.amount().is_sign_negative() && self.withdrawal_dispute == WithdrawalDisputePolicy::ProvisionalCredit
+    }
+
+    /// The dispute window the dispute came past, if it did
+    fn expired_window(&self, entry: &Transaction, dispute: &Operation) -> Option<Duration> {
+        let window = self.dispute_window?;
+        let elapsed = dispute.timestamp()?.since(entry.timestamp()?)?;
+        (elapsed > window).then_some(window)
+    }
+}
+
+/// The reason of account's lock with the operator who put it, if any
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct Lock {
+    reason: LockReason,
+    operator: Option<u32>,
+}
+
+impl Lock {
+    pub fn reason(&self) -> LockReason {
+        self.reason
+    }
+
+    pub fn operator(&self) -> Option<u32> {
+        self.operator
+    }
+}
+
 #[derive(Serialize, Debug, Default)]
 pub struct Account {
     available: Decimal,
     held: Decimal,
     total: Decimal,
-    locked: bool,
+    lock: Option<Lock>,
+    receivable: Decimal,
+    #[serde(skip)]
+    txhistory: TxHistory,
+    /// Parts of disputed amounts not held due to insufficient funds, by transaction
     #[serde(skip)]
-    txhistory: HashMap<u32, Transaction>,
+    shortfalls: HashMap<u32, Decimal>,
+}
+
+/// Funds of the account, or changes to them
+#[derive(Debug, Default, Clone, Copy)]
+struct Funds {
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    receivable: Decimal,
+}
+
+impl Funds {
+    fn checked_add(&self, change: &Funds) -> Option<Funds> {
+        // Unchanged funds are kept as they are, not to alter their scale
+        let add = |value: Decimal, change: Decimal| {
+            if change.is_zero() { Some(value) } else { value.checked_add(change) }
+        };
+
+        Some(Funds {
+            available: add(self.available, change.available)?,
+            held: add(self.held, change.held)?,
+            total: add(self.total, change.total)?,
+            receivable: add(self.receivable, change.receivable)?,
+        })
+    }
 }
 
 impl Account {
+    /// Computes funds after the change without modifying the account, so either all of them are
+    /// updated with `set_funds()` or, if any would overflow, none of them
+    fn changed_funds(&self, change: Funds, client: u16, tx: u32) -> std::result::Result<Funds, TransactionSystemError> {
+        let funds = Funds {
+            available: self.available,
+            held: self.held,
+            total: self.total,
+            receivable: self.receivable,
+        };
+        funds.checked_add(&change).ok_or(TransactionSystemError::BalanceOverflowError { client, tx })
+    }
+
+    fn set_funds(&mut self, funds: Funds) {
+        self.available = funds.available;
+        self.held = funds.held;
+        self.total = funds.total;
+        self.receivable = funds.receivable;
+    }
+
     fn deposit(&mut self, data: Transaction) -> Result {
         trace!("client {} tx {} deposits {}", data.client(), data.tx(), data.amount());
-        self.available += data.amount();
-        self.total += data.amount();
-        self.txhistory.insert(data.tx(), data);
+        let change = Funds { available: data.amount(), total: data.amount(), ..Default::default() };
+        let funds = self.changed_funds(change, data.client(), data.tx())?;
+        self.set_funds(funds);
+        self.txhistory.insert(&data);
 
         Ok(())
     }
 
     fn withdrawal(&mut self, mut data: Transaction) -> Result {
         trace!("client {} tx {} attempts withdraw {}", data.client(), data.tx(), data.amount());
-        let mut available = self.available;
-        available -= data.amount();
-        if available >= Decimal::new(0, 0) {
-            self.available = available;
-            self.total -= data.amount();
+        let change = Funds { available: -data.amount(), total: -data.amount(), ..Default::default() };
+        let funds = self.changed_funds(change, data.client(), data.tx())?;
+        if funds.available >= Decimal::new(0, 0) {
+            self.set_funds(funds);
             data.negate(); // That way we record transaction with the negative sign
-            self.txhistory.insert(data.tx(), data);
+            self.txhistory.insert(&data);
 
             Ok(())
         } else {
-            Err(TransactionSystemError::TransactionError{ 
-                message: "attempt to withdraw more than available".to_owned(),
+            Err(TransactionSystemError::WithdrawalOverdraftError{
+                available: self.available(),
                 transaction: data
             })
         }
     }
 
-    fn dispute(&mut self, data: Operation) -> Result {
+    fn dispute(&mut self, data: Operation, policy: &Policy) -> std::result::Result<Outcome, TransactionSystemError> {
         trace!("client {} tx {} receives dispute", data.client(), data.tx());
         // Refer to `README.md` for information about disputes repeated for the same transaction
-        if let Some(entry) = self.txhistory.get(&data.tx()) {
-            entry.try_set_disputed().and_then( |_| {
-                self.available -= entry.amount();
-                self.held += entry.amount();
-                Ok(())
-            })
+        if let Some(entry) = self.txhistory.get(data.client(), data.tx()) {
+            if let Some(window) = policy.expired_window(&entry, &data) {
+                return Err(TransactionSystemError::ExpiredDisputeError{ window, operation: data });
+            }
+            let amount = entry.amount();
+            let zero = Decimal::new(0, 0);
+            let overflow = || TransactionSystemError::BalanceOverflowError { client: data.client(), tx: data.tx() };
+            let credit = policy.credits_withdrawal(&entry);
+            let shortfall = if credit {
+                zero
+            } else {
+                amount.checked_sub(self.available.max(zero)).ok_or_else(overflow)?.max(zero)
+            };
+            if shortfall > zero && policy.overdraft == OverdraftPolicy::RejectIfInsufficient {
+                return Err(TransactionSystemError::InsufficientFundsError{
+                    available: self.available,
+                    operation: data
+                });
+            }
+            let partial = shortfall > zero && policy.overdraft == OverdraftPolicy::HoldPartial;
+
+            let change = if credit {
+                Funds { held: -amount, total: -amount, ..Default::default() }
+            } else if partial {
+                let unheld = shortfall.checked_sub(amount).ok_or_else(overflow)?;
+                Funds { available: unheld, held: -unheld, receivable: shortfall, ..Default::default() }
+            } else {
+                Funds { available: -amount, held: amount, ..Default::default() }
+            };
+            let funds = self.changed_funds(change, data.client(), data.tx())?;
+            entry.try_set_disputed()?;
+            self.txhistory.set_state(data.tx(), entry.state());
+
+            let outcome = if partial {
+                trace!("client {} tx {} holds {} short, recorded as receivable", data.client(), data.tx(), shortfall);
+                self.shortfalls.insert(data.tx(), shortfall);
+                Outcome::HeldPartially { shortfall }
+            } else if shortfall > zero {
+                warn!("client {} tx {} dispute drives available funds below zero", data.client(), data.tx());
+                Outcome::NegativeAvailable
+            } else {
+                Outcome::Applied
+            };
+            self.set_funds(funds);
+            Ok(outcome)
         } else {
             Err(TransactionSystemError::OperationError{
                 message: "attempt to dispute non-existing transaction".to_owned(),
@@ -64,16 +319,27 @@ impl Account {
         }
     }
 
-    fn resolve(&mut self, data: Operation) -> Result {
+    fn resolve(&mut self, data: Operation, policy: &Policy) -> Result {
         trace!("client {} tx {} resolves dispute", data.client(), data.tx());
         // Refer to `README.md` for information about resolves for transactions without disputes started
-        if let Some(entry) = self.txhistory.get(&data.tx()) {
-            entry.try_set_resolved().and_then(|_| {
-                self.available += entry.amount();
-                self.held -= entry.amount();
-                Ok(())
-            })
+        if let Some(entry) = self.txhistory.get(data.client(), data.tx()) {
+            let amount = entry.amount();
+            let change = if policy.credits_withdrawal(&entry) {
+                Funds { held: amount, total: amount, ..Default::default() }
+            } else {
+                // The shortfall is no longer owed as the deposit stands
+                let shortfall = self.shortfalls.get(&data.tx()).copied().unwrap_or_default();
+                let held = amount.checked_sub(shortfall)
+                    .ok_or(TransactionSystemError::BalanceOverflowError { client: data.client(), tx: data.tx() })?;
+                Funds { available: held, held: -held, receivable: -shortfall, ..Default::default() }
+            };
+            let funds = self.changed_funds(change, data.client(), data.tx())?;
+            entry.try_set_resolved()?;
+            self.txhistory.set_state(data.tx(), entry.state());
 
+            self.shortfalls.remove(&data.tx());
+            self.set_funds(funds);
+            Ok(())
         } else {
             Err(TransactionSystemError::OperationError{
                 message: "attempt to resolve non-existing transaction".to_owned(),
@@ -82,16 +348,28 @@ impl Account {
         }
     }
 
-    fn chargeback(&mut self, data: Operation) -> Result {
+    fn chargeback(&mut self, data: Operation, policy: &Policy) -> Result {
         trace!("client {} tx {} charges back of the dispute", data.client(), data.tx());
         // Refer to `README.md` for information about chargebacks for transactions without disputes started
-        if let Some(entry) = self.txhistory.get(&data.tx()) {
-            entry.try_set_chargedback().and_then(|_| {
-                self.locked = true;
-                self.total -= entry.amount();
-                self.held -= entry.amount();
-                Ok(())
-            })
+        if let Some(entry) = self.txhistory.get(data.client(), data.tx()) {
+            let amount = entry.amount();
+            let change = if policy.credits_withdrawal(&entry) {
+                Funds { available: -amount, held: amount, ..Default::default() }
+            } else {
+                // The shortfall stays receivable as the client owes it after the reversal
+                let shortfall = self.shortfalls.get(&data.tx()).copied().unwrap_or_default();
+                let unheld = shortfall.checked_sub(amount)
+                    .ok_or(TransactionSystemError::BalanceOverflowError { client: data.client(), tx: data.tx() })?;
+                Funds { held: unheld, total: unheld, ..Default::default() }
+            };
+            let funds = self.changed_funds(change, data.client(), data.tx())?;
+            entry.try_set_chargedback()?;
+            self.txhistory.set_state(data.tx(), entry.state());
+
+            self.shortfalls.remove(&data.tx());
+            self.lock.get_or_insert(Lock { reason: LockReason::Chargeback, operator: None });
+            self.set_funds(funds);
+            Ok(())
         } else {
             Err(TransactionSystemError::OperationError{
                 message: "attempt to chargeback non-existing transaction".to_owned(),
@@ -100,13 +378,79 @@ impl Account {
         }
     }
 
-    pub fn apply(&mut self, instruction: Instruction) -> Result {
+    fn freeze(&mut self, data: Administration) -> Result {
+        trace!("client {} tx {} frozen by operator {} for {}", data.client(), data.tx(), data.operator(), data.reason());
+        if self.lock.is_none() {
+            self.lock = Some(Lock { reason: data.reason(), operator: Some(data.operator()) });
+            Ok(())
+        } else {
+            Err(TransactionSystemError::AdministrationError{
+                message: "attempt to freeze already locked account".to_owned(),
+                administration: data
+            })
+        }
+    }
+
+    fn unlock(&mut self, data: Administration) -> Result {
+        trace!("client {} tx {} unlocked by operator {} for {}", data.client(), data.tx(), data.operator(), data.reason());
+        if self.lock.take().is_some() {
+            Ok(())
+        } else {
+            Err(TransactionSystemError::AdministrationError{
+                message: "attempt to unlock not locked account".to_owned(),
+                administration: data
+            })
+        }
+    }
+
+    pub fn apply(&mut self, instruction: Instruction, policy: &Policy) -> std::result::Result<Outcome, TransactionSystemError> {
+        self.apply_at(instruction, policy, 0)
+    }
+
+    /// Applies the instruction with the given sequence number of all the instructions processed,
+    /// the clock of `Retention::Instructions`
+    pub fn apply_at(&mut self, instruction: Instruction, policy: &Policy, sequence: u64) -> std::result::Result<Outcome, TransactionSystemError> {
+        self.txhistory.retain(policy.retention, sequence);
+        let recorded = instruction.transaction().map(Transaction::tx);
+        let outcome = self.execute(instruction, policy)?;
+
+        if let Some(tx) = recorded {
+            self.txhistory.record(tx, sequence);
+        }
+        self.txhistory.retain(policy.retention, sequence);
+        Ok(outcome)
+    }
+
+    fn execute(&mut self, instruction: Instruction, policy: &Policy) -> std::result::Result<Outcome, TransactionSystemError> {
+        if let Some(lock) = self.lock() {
+            if !policy.locked.accepts(&instruction) {
+                trace!("client {} locked for {} by operator {:?}, instruction refused",
+                    instruction.client(), lock.reason(), lock.operator());
+                return Err(TransactionSystemError::AccountLockedError { instruction });
+            }
+        }
+
         match instruction {
             Instruction::Deposit(data)    => self.deposit(data),
             Instruction::Withdrawal(data) => self.withdrawal(data),
-            Instruction::Dispute(data)    => self.dispute(data),
-            Instruction::Resolve(data)    => self.resolve(data),
-            Instruction::Chargeback(data) => self.chargeback(data),
+            Instruction::Dispute(data)    => return self.dispute(data, policy),
+            Instruction::Resolve(data)    => self.resolve(data, policy),
+            Instruction::Chargeback(data) => self.chargeback(data, policy),
+            Instruction::Freeze(data)     => self.freeze(data),
+            Instruction::Unlock(data)     => self.unlock(data),
+        }?;
+        Ok(Outcome::Applied)
+    }
+
+    /// Account carried over with its balances only, without transaction history;
+    /// a locked one is locked for manual reason by no particular operator
+    pub fn opening(available: Decimal, held: Decimal, total: Decimal, locked: bool) -> Self {
+        Self {
+            available,
+            held,
+            total,
+            lock: locked.then_some(Lock { reason: LockReason::Manual, operator: None }),
+            ..Default::default()
         }
     }
 
@@ -122,16 +466,210 @@ impl Account {
         self.total
     }
     
+    /// Funds the client owes for disputed deposits which couldn't be held in full
+    pub fn receivable(&self) -> Decimal {
+        self.receivable
+    }
+
     pub fn locked(&self) -> bool {
-        self.locked
+        self.lock.is_some()
+    }
+
+    pub fn lock(&self) -> Option<&Lock> {
+        self.lock.as_ref()
+    }
+
+    /// Writes the account with its whole history as text lines, to be read back with `decode()`
+    pub fn encode(&self, client: u16, sink: &mut impl Write) -> Result {
+        self.encode_funds(client, sink)?;
+        let sequences = self.txhistory.sequences();
+        for transaction in self.txhistory.iter(client) {
+            Self::encode_transaction(&transaction, sequences.get(&transaction.tx()).copied().unwrap_or(0), sink)?;
+        }
+        self.encode_shortfalls(sink)
+    }
+
+    /// Writes the changes made to the account since they were last written, to be read back with
+    /// `decode_changes()` after the ones written before: the funds, the transactions inserted or changed
+    /// and `drop` lines of the ones removed. The whole account is written instead when it's asked for
+    /// or the changes aren't tracked yet, as they are from then on; gives whether it was written whole.
+    pub(crate) fn encode_changes(&mut self, client: u16, whole: bool, sink: &mut impl Write) -> std::result::Result<bool, TransactionSystemError> {
+        let changes = self.txhistory.take_changes();
+        let Some(changes) = changes.filter(|_| !whole) else {
+            self.txhistory.track_changes();
+            self.encode(client, sink)?;
+            return Ok(true);
+        };
+
+        self.encode_funds(client, sink)?;
+        let sequences = self.txhistory.sequences_of(&changes);
+        for tx in changes {
+            match self.txhistory.get(client, tx) {
+                Some(transaction) => Self::encode_transaction(&transaction, sequences.get(&tx).copied().unwrap_or(0), sink)?,
+                None => writeln!(sink, "drop {}", tx)?,
+            }
+        }
+        self.encode_shortfalls(sink)?;
+        Ok(false)
+    }
+
+    fn encode_funds(&self, client: u16, sink: &mut impl Write) -> Result {
+        let lock = self.lock.map(|lock| format!("{}:{}", lock.reason, codec::optional(lock.operator)));
+        writeln!(sink, "account {} {} {} {} {} {}",
+            client, self.available, self.held, self.total, self.receivable, codec::optional(lock))?;
+        Ok(())
+    }
+
+    fn encode_transaction(transaction: &Transaction, sequence: u64, sink: &mut impl Write) -> Result {
+        write!(sink, "tx {} {} {} {}", transaction.tx(), transaction.amount(), transaction.state(), sequence)?;
+        match transaction.timestamp() {
+            Some(timestamp) => writeln!(sink, " {}", timestamp)?,
+            None => writeln!(sink)?,
+        }
+        Ok(())
+    }
+
+    fn encode_shortfalls(&self, sink: &mut impl Write) -> Result {
+        let mut shortfalls: Vec<(&u32, &Decimal)> = self.shortfalls.iter().collect();
+        shortfalls.sort();
+        for (tx, shortfall) in shortfalls {
+            writeln!(sink, "shortfall {} {}", tx, shortfall)?;
+        }
+
+        writeln!(sink, "end")?;
+        Ok(())
+    }
+
+    /// Reads the account written with `encode()`, gives `None` at the end of the source
+    pub fn decode(source: &mut impl BufRead) -> std::result::Result<Option<(u16, Account)>, TransactionSystemError> {
+        let mut line = String::new();
+        if source.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        Self::decode_rest(&line, source).map(Some)
+    }
+
+    /// Reads the account which first line is already read from the source
+    pub(crate) fn decode_rest(line: &str, source: &mut impl BufRead) -> std::result::Result<(u16, Account), TransactionSystemError> {
+        let mut account = Account::default();
+        let mut order = BTreeMap::new();
+        let client = account.decode_segment(line, source, &mut order)?;
+        account.txhistory.track(order.into_iter().collect());
+        Ok((client, account))
+    }
+
+    /// Reads the account back from all the changes written with `encode_changes()`, one after another,
+    /// to the end of the source; gives `None` when there are none. Its changes are tracked from then on.
+    pub(crate) fn decode_changes(source: &mut impl BufRead) -> std::result::Result<Option<(u16, Account)>, TransactionSystemError> {
+        let mut account = Account::default();
+        let mut order = BTreeMap::new();
+        let mut client = None;
+        let mut line = String::new();
+        while source.read_line(&mut line)? > 0 {
+            let segment = account.decode_segment(&line, source, &mut order)?;
+            if client.is_some_and(|client| client != segment) {
+                return Err(Fields::new(&line).corrupted("changes of another client"));
+            }
+            client = Some(segment);
+            line.clear();
+        }
+        account.txhistory.track(order.into_iter().collect());
+        account.txhistory.track_changes();
+        Ok(client.map(|client| (client, account)))
+    }
+
+    /// Reads the funds of the account from the last of the changes written with `encode_changes()`,
+    /// leaving out the history; the transactions added from then on are tracked as changes
+    pub(crate) fn decode_funds(source: &mut impl BufRead) -> std::result::Result<Option<(u16, Account)>, TransactionSystemError> {
+        Ok(Self::decode_changes(source)?.map(|(client, mut account)| {
+            account.txhistory = TxHistory::default();
+            account.txhistory.track_changes();
+            (client, account)
+        }))
+    }
+
+    /// Reads the funds, transactions and shortfalls of the account up to the `end` line into the account,
+    /// replacing the ones it has; transactions are noted with their sequences, or forgotten when dropped
+    fn decode_segment(&mut self, line: &str, source: &mut impl BufRead, order: &mut BTreeMap<u32, u64>) -> std::result::Result<u16, TransactionSystemError> {
+        let mut fields = Fields::new(line);
+        fields.expect("account")?;
+        let client: u16 = fields.parse()?;
+        self.available = fields.parse()?;
+        self.held = fields.parse()?;
+        self.total = fields.parse()?;
+        self.receivable = fields.parse()?;
+        self.lock = match fields.optional::<String>()? {
+            Some(lock) => {
+                let (reason, operator) = lock.split_once(':').ok_or_else(|| fields.corrupted("invalid lock"))?;
+                let mut lockfields = Fields::new(operator);
+                Some(Lock {
+                    reason: reason.parse().map_err(|_| fields.corrupted("invalid lock reason"))?,
+                    operator: lockfields.optional()?,
+                })
+            },
+            None => None,
+        };
+        self.shortfalls.clear();
+
+        loop {
+            let mut line = String::new();
+            if source.read_line(&mut line)? == 0 {
+                return Err(Fields::new(&line).corrupted("unexpected end of account"));
+            }
+
+            let mut fields = Fields::new(&line);
+            match fields.next_str()? {
+                "tx" => {
+                    let tx: u32 = fields.parse()?;
+                    let amount: Decimal = fields.parse()?;
+                    let state: TransactionState = fields.parse()?;
+                    // Written without the sequence by earlier versions, the timestamp is there only when known
+                    let sequence = fields.trailing()?.unwrap_or(0);
+                    let timestamp = fields.trailing()?;
+                    self.txhistory.insert(&Transaction::new(client, tx, amount).with_state(state).with_timestamp(timestamp));
+                    order.insert(tx, sequence);
+                },
+                "drop" => {
+                    let tx: u32 = fields.parse()?;
+                    self.txhistory.remove(tx);
+                    order.remove(&tx);
+                },
+                "shortfall" => {
+                    self.shortfalls.insert(fields.parse()?, fields.parse()?);
+                },
+                "end" => break,
+                tag => return Err(fields.corrupted(&format!("unexpected {}", tag))),
+            }
+        }
+        Ok(client)
+    }
+
+    /// Ids of transactions kept in the history of the account
+    pub fn transactions(&self) -> impl Iterator<Item = u32> + '_ {
+        self.txhistory.ids()
+    }
+
+    /// Sequences of the instructions recording the transactions kept in the history, 0 of the ones
+    /// recorded without retention
+    pub(crate) fn recorded(&self) -> Vec<u64> {
+        self.txhistory.recorded()
+    }
+
+    /// Evicts the transactions out of the retention as of the instruction with the given sequence,
+    /// as `apply_at()` does, though the instruction is not the account's
+    pub fn expire(&mut self, retention: Retention, sequence: u64) {
+        self.txhistory.retain(retention, sequence);
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::io;
+    use std::time::Duration;
     use rust_decimal::{Decimal, prelude::FromPrimitive};
-    use crate::instructions::{Transaction, Operation};
-    use super::Account;
+    use crate::instructions::{Instruction, Transaction, TransactionState, Operation, Administration, LockReason};
+    use crate::errors::TransactionSystemError;
+    use super::{Account, Lock, LockedPolicy, Outcome, OverdraftPolicy, Policy, Retention, WithdrawalDisputePolicy};
 
     #[test]
     fn deposit() {
@@ -140,7 +678,7 @@ mod test {
         assert_eq!(account.available, Decimal::from_i32(0).unwrap());
         assert_eq!(account.held, Decimal::from_i32(0).unwrap());
         assert_eq!(account.total, Decimal::from_i32(0).unwrap());
-        assert_eq!(account.locked, false);
+        assert!(!account.locked());
 
         let data = Transaction::new(1, 1, Decimal::from_i32(30).unwrap() );
         assert!(account.deposit(data).is_ok());
@@ -152,12 +690,14 @@ mod test {
 
     #[test]
     fn withdrawal() {
-        let mut account = Account::default();
+        let mut account = Account {
+            available: Decimal::new(1000, 1),
+            total: Decimal::from_i32(120).unwrap(),
+            ..Default::default()
+        };
 
-        account.available = Decimal::new(1000, 1);
         assert_eq!(account.held, Decimal::from_i32(0).unwrap());
-        account.total = Decimal::from_i32(120).unwrap();
-        assert_eq!(account.locked, false);
+        assert!(!account.locked());
 
         let data = Transaction::new(1, 1, Decimal::from_i32(30).unwrap() );
         assert!(account.withdrawal(data).is_ok());
@@ -173,18 +713,20 @@ mod test {
 
     #[test]
     fn dispute() {
-        let mut account = Account::default();
+        let mut account = Account {
+            available: Decimal::new(1500, 1),
+            total: Decimal::from_i32(150).unwrap(),
+            ..Default::default()
+        };
 
-        account.available = Decimal::new(1500, 1);
         assert_eq!(account.held, Decimal::from_i32(0).unwrap());
-        account.total = Decimal::from_i32(150).unwrap();
-        assert_eq!(account.locked, false);
+        assert!(!account.locked());
 
         let data = Transaction::new(1, 1, Decimal::from_i32(50).unwrap() );
         assert!(account.deposit(data).is_ok());
 
         let data = Operation::new(1, 1);
-        assert!(account.dispute(data).is_ok());
+        assert_eq!(account.dispute(data, &Policy::default()).ok(), Some(Outcome::Applied));
 
         assert_eq!(account.available, Decimal::from_i32(150).unwrap());
         assert_eq!(account.held, Decimal::from_i32(50).unwrap());
@@ -193,21 +735,23 @@ mod test {
 
     #[test]
     fn resolve() {
-        let mut account = Account::default();
+        let mut account = Account {
+            available: Decimal::new(1500, 1),
+            total: Decimal::from_i32(150).unwrap(),
+            ..Default::default()
+        };
 
-        account.available = Decimal::new(1500, 1);
         assert_eq!(account.held, Decimal::from_i32(0).unwrap());
-        account.total = Decimal::from_i32(150).unwrap();
-        assert_eq!(account.locked, false);
+        assert!(!account.locked());
 
         let data = Transaction::new(1, 1, Decimal::from_i32(50).unwrap() );
         assert!(account.deposit(data).is_ok());
 
         let data = Operation::new(1, 1);
-        assert!(account.dispute(data).is_ok());
+        assert!(account.dispute(data, &Policy::default()).is_ok());
 
         let data = Operation::new(1, 1);
-        assert!(account.resolve(data).is_ok());
+        assert!(account.resolve(data, &Policy::default()).is_ok());
 
         assert_eq!(account.available, Decimal::from_i32(200).unwrap());
         assert_eq!(account.held, Decimal::from_i32(0).unwrap());
@@ -216,25 +760,423 @@ mod test {
 
     #[test]
     fn chargeback() {
-        let mut account = Account::default();
+        let mut account = Account {
+            available: Decimal::new(1500, 1),
+            total: Decimal::from_i32(150).unwrap(),
+            ..Default::default()
+        };
 
-        account.available = Decimal::new(1500, 1);
         assert_eq!(account.held, Decimal::from_i32(0).unwrap());
-        account.total = Decimal::from_i32(150).unwrap();
-        assert_eq!(account.locked, false);
+        assert!(!account.locked());
 
         let data = Transaction::new(1, 1, Decimal::from_i32(50).unwrap() );
         assert!(account.deposit(data).is_ok());
 
         let data = Operation::new(1, 1);
-        assert!(account.dispute(data).is_ok());
+        assert!(account.dispute(data, &Policy::default()).is_ok());
 
         let data = Operation::new(1, 1);
-        assert!(account.chargeback(data).is_ok());
+        assert!(account.chargeback(data, &Policy::default()).is_ok());
 
         assert_eq!(account.available, Decimal::from_i32(150).unwrap());
         assert_eq!(account.held, Decimal::from_i32(0).unwrap());
         assert_eq!(account.total, Decimal::from_i32(150).unwrap());
-        assert!(account.locked);
+        assert!(account.locked());
+        assert_eq!(account.lock().map(|lock| lock.reason()), Some(LockReason::Chargeback));
+    }
+
+    #[test]
+    fn locked_account_refuses_instructions() {
+        let mut account = Account {
+            available: Decimal::from_i32(100).unwrap(),
+            total: Decimal::from_i32(100).unwrap(),
+            lock: Some(Lock { reason: LockReason::Chargeback, operator: None }),
+            ..Default::default()
+        };
+        let policy = Policy::default();
+
+        let instruction = Instruction::Deposit(Transaction::new(1, 1, Decimal::from_i32(30).unwrap()));
+        assert!(matches!(account.apply(instruction, &policy), Err(TransactionSystemError::AccountLockedError{..})));
+
+        let instruction = Instruction::Withdrawal(Transaction::new(1, 2, Decimal::from_i32(30).unwrap()));
+        assert!(matches!(account.apply(instruction, &policy), Err(TransactionSystemError::AccountLockedError{..})));
+
+        let instruction = Instruction::Dispute(Operation::new(1, 1));
+        assert!(matches!(account.apply(instruction, &policy), Err(TransactionSystemError::AccountLockedError{..})));
+
+        assert_eq!(account.available, Decimal::from_i32(100).unwrap());
+        assert_eq!(account.total, Decimal::from_i32(100).unwrap());
+    }
+
+    #[test]
+    fn locked_account_follows_policy_matrix() {
+        let mut account = Account {
+            available: Decimal::from_i32(100).unwrap(),
+            total: Decimal::from_i32(100).unwrap(),
+            lock: Some(Lock { reason: LockReason::Chargeback, operator: None }),
+            ..Default::default()
+        };
+        let policy = Policy {
+            locked: LockedPolicy { deposit: true, ..Default::default() },
+            ..Default::default()
+        };
+
+        let instruction = Instruction::Deposit(Transaction::new(1, 1, Decimal::from_i32(30).unwrap()));
+        assert!(account.apply(instruction, &policy).is_ok());
+
+        let instruction = Instruction::Withdrawal(Transaction::new(1, 2, Decimal::from_i32(30).unwrap()));
+        assert!(matches!(account.apply(instruction, &policy), Err(TransactionSystemError::AccountLockedError{..})));
+
+        assert_eq!(account.available, Decimal::from_i32(130).unwrap());
+        assert_eq!(account.total, Decimal::from_i32(130).unwrap());
+    }
+
+    #[test]
+    fn freeze_and_unlock() {
+        let mut account = Account::default();
+        let policy = Policy::default();
+
+        let instruction = Instruction::Unlock(Administration::new(1, 1, 7, LockReason::Manual));
+        assert!(matches!(account.apply(instruction, &policy), Err(TransactionSystemError::AdministrationError{..})));
+
+        let instruction = Instruction::Freeze(Administration::new(1, 2, 7, LockReason::Fraud));
+        assert!(account.apply(instruction, &policy).is_ok());
+        assert!(account.locked());
+        assert_eq!(account.lock().map(|lock| lock.reason()), Some(LockReason::Fraud));
+        assert_eq!(account.lock().and_then(|lock| lock.operator()), Some(7));
+
+        let instruction = Instruction::Freeze(Administration::new(1, 3, 8, LockReason::Manual));
+        assert!(matches!(account.apply(instruction, &policy), Err(TransactionSystemError::AdministrationError{..})));
+        assert_eq!(account.lock().map(|lock| lock.reason()), Some(LockReason::Fraud));
+
+        let instruction = Instruction::Unlock(Administration::new(1, 4, 8, LockReason::Manual));
+        assert!(account.apply(instruction, &policy).is_ok());
+        assert!(!account.locked());
+
+        let instruction = Instruction::Deposit(Transaction::new(1, 5, Decimal::from_i32(30).unwrap()));
+        assert!(account.apply(instruction, &policy).is_ok());
+        assert_eq!(account.available, Decimal::from_i32(30).unwrap());
+    }
+
+    fn disputed_withdrawal(policy: &Policy) -> Account {
+        let mut account = Account {
+            available: Decimal::from_i32(100).unwrap(),
+            total: Decimal::from_i32(100).unwrap(),
+            ..Default::default()
+        };
+
+        let data = Transaction::new(1, 1, Decimal::from_i32(40).unwrap() );
+        assert!(account.withdrawal(data).is_ok());
+
+        let data = Operation::new(1, 1);
+        assert!(account.dispute(data, policy).is_ok());
+
+        account
+    }
+
+    #[test]
+    fn withdrawal_dispute_provisional_credit() {
+        let policy = Policy::default();
+
+        let account = disputed_withdrawal(&policy);
+        assert_eq!(account.available, Decimal::from_i32(60).unwrap());
+        assert_eq!(account.held, Decimal::from_i32(40).unwrap());
+        assert_eq!(account.total, Decimal::from_i32(100).unwrap());
+
+        let mut account = disputed_withdrawal(&policy);
+        assert!(account.resolve(Operation::new(1, 1), &policy).is_ok());
+        assert_eq!(account.available, Decimal::from_i32(60).unwrap());
+        assert_eq!(account.held, Decimal::from_i32(0).unwrap());
+        assert_eq!(account.total, Decimal::from_i32(60).unwrap());
+
+        let mut account = disputed_withdrawal(&policy);
+        assert!(account.chargeback(Operation::new(1, 1), &policy).is_ok());
+        assert_eq!(account.available, Decimal::from_i32(100).unwrap());
+        assert_eq!(account.held, Decimal::from_i32(0).unwrap());
+        assert_eq!(account.total, Decimal::from_i32(100).unwrap());
+        assert!(account.locked());
+    }
+
+    #[test]
+    fn withdrawal_dispute_negated() {
+        let policy = Policy { withdrawal_dispute: WithdrawalDisputePolicy::Negated, ..Default::default() };
+
+        let account = disputed_withdrawal(&policy);
+        assert_eq!(account.available, Decimal::from_i32(100).unwrap());
+        assert_eq!(account.held, Decimal::from_i32(-40).unwrap());
+        assert_eq!(account.total, Decimal::from_i32(60).unwrap());
+
+        let mut account = disputed_withdrawal(&policy);
+        assert!(account.chargeback(Operation::new(1, 1), &policy).is_ok());
+        assert_eq!(account.available, Decimal::from_i32(100).unwrap());
+        assert_eq!(account.held, Decimal::from_i32(0).unwrap());
+        assert_eq!(account.total, Decimal::from_i32(100).unwrap());
+    }
+
+    fn spent_deposit() -> Account {
+        let mut account = Account::default();
+
+        let data = Transaction::new(1, 1, Decimal::from_i32(100).unwrap() );
+        assert!(account.deposit(data).is_ok());
+
+        let data = Transaction::new(1, 2, Decimal::from_i32(70).unwrap() );
+        assert!(account.withdrawal(data).is_ok());
+
+        account
+    }
+
+    #[test]
+    fn overdraft_allow_negative() {
+        let policy = Policy::default();
+
+        let mut account = spent_deposit();
+        assert_eq!(account.dispute(Operation::new(1, 1), &policy).ok(), Some(Outcome::NegativeAvailable));
+        assert_eq!(account.available, Decimal::from_i32(-70).unwrap());
+        assert_eq!(account.held, Decimal::from_i32(100).unwrap());
+        assert_eq!(account.total, Decimal::from_i32(30).unwrap());
+        assert_eq!(account.receivable, Decimal::from_i32(0).unwrap());
+    }
+
+    #[test]
+    fn overdraft_reject_if_insufficient() {
+        let policy = Policy { overdraft: OverdraftPolicy::RejectIfInsufficient, ..Default::default() };
+
+        let mut account = spent_deposit();
+        let result = account.dispute(Operation::new(1, 1), &policy);
+        assert!(matches!(result, Err(TransactionSystemError::InsufficientFundsError{..})));
+        assert_eq!(account.available, Decimal::from_i32(30).unwrap());
+        assert_eq!(account.held, Decimal::from_i32(0).unwrap());
+
+        // Transaction remains undisputed, so the resolve fails
+        assert!(account.resolve(Operation::new(1, 1), &policy).is_err());
+    }
+
+    #[test]
+    fn overdraft_hold_partial() {
+        let policy = Policy { overdraft: OverdraftPolicy::HoldPartial, ..Default::default() };
+
+        let mut account = spent_deposit();
+        let outcome = account.apply(Instruction::Dispute(Operation::new(1, 1)), &policy);
+        assert_eq!(outcome.ok(), Some(Outcome::HeldPartially { shortfall: Decimal::from_i32(70).unwrap() }));
+        assert_eq!(account.available, Decimal::from_i32(0).unwrap());
+        assert_eq!(account.held, Decimal::from_i32(30).unwrap());
+        assert_eq!(account.total, Decimal::from_i32(30).unwrap());
+        assert_eq!(account.receivable(), Decimal::from_i32(70).unwrap());
+
+        assert!(account.resolve(Operation::new(1, 1), &policy).is_ok());
+        assert_eq!(account.available, Decimal::from_i32(30).unwrap());
+        assert_eq!(account.held, Decimal::from_i32(0).unwrap());
+        assert_eq!(account.receivable(), Decimal::from_i32(0).unwrap());
+
+        assert!(account.dispute(Operation::new(1, 1), &policy).is_ok());
+        assert!(account.chargeback(Operation::new(1, 1), &policy).is_ok());
+        assert_eq!(account.available, Decimal::from_i32(0).unwrap());
+        assert_eq!(account.held, Decimal::from_i32(0).unwrap());
+        assert_eq!(account.total, Decimal::from_i32(0).unwrap());
+        assert_eq!(account.receivable(), Decimal::from_i32(70).unwrap());
+    }
+
+    #[test]
+    fn overflow_leaves_account_intact() {
+        let mut account = Account {
+            available: Decimal::MAX,
+            total: Decimal::MAX,
+            ..Default::default()
+        };
+
+        let data = Transaction::new(1, 1, Decimal::from_i32(1).unwrap() );
+        assert!(matches!(account.deposit(data), Err(TransactionSystemError::BalanceOverflowError{ client: 1, tx: 1 })));
+        assert_eq!(account.available, Decimal::MAX);
+        assert_eq!(account.total, Decimal::MAX);
+        assert!(account.txhistory.is_empty());
+
+        let mut account = Account {
+            held: Decimal::MAX,
+            total: Decimal::MAX,
+            ..Default::default()
+        };
+
+        let data = Transaction::new(1, 2, Decimal::from_i32(5).unwrap() );
+        assert!(matches!(account.deposit(data), Err(TransactionSystemError::BalanceOverflowError{ client: 1, tx: 2 })));
+
+        account.total = Decimal::from_i32(0).unwrap();
+        let data = Transaction::new(1, 2, Decimal::from_i32(5).unwrap() );
+        assert!(account.deposit(data).is_ok());
+
+        let data = Operation::new(1, 2);
+        assert!(matches!(account.dispute(data, &Policy::default()), Err(TransactionSystemError::BalanceOverflowError{ client: 1, tx: 2 })));
+        assert_eq!(account.available, Decimal::from_i32(5).unwrap());
+        assert_eq!(account.held, Decimal::MAX);
+
+        // Transaction remains undisputed
+        let data = Operation::new(1, 2);
+        assert!(matches!(account.resolve(data, &Policy::default()), Err(TransactionSystemError::TransactionStateError{..})));
+
+        // Shortfall of a negated withdrawal dispute overflows computing what's missing
+        let policy = Policy { withdrawal_dispute: WithdrawalDisputePolicy::Negated, ..Default::default() };
+        let mut account = Account::default();
+        for instruction in [Instruction::Deposit(Transaction::new(1, 1, Decimal::MAX)),
+                            Instruction::Withdrawal(Transaction::new(1, 2, Decimal::MAX)),
+                            Instruction::Deposit(Transaction::new(1, 3, Decimal::MAX))] {
+            assert!(account.apply(instruction, &policy).is_ok());
+        }
+        let result = account.apply(Instruction::Dispute(Operation::new(1, 2)), &policy);
+        assert!(matches!(result, Err(TransactionSystemError::BalanceOverflowError{ client: 1, tx: 2 })));
+        assert_eq!(account.available, Decimal::MAX);
+        assert_eq!(account.held, Decimal::ZERO);
+        let hold_partial = Policy { overdraft: OverdraftPolicy::HoldPartial, ..policy };
+        let result = account.apply(Instruction::Dispute(Operation::new(1, 2)), &hold_partial);
+        assert!(matches!(result, Err(TransactionSystemError::BalanceOverflowError{ client: 1, tx: 2 })));
+        assert_eq!(account.txhistory.get(1, 2).map(|transaction| transaction.state()), Some(TransactionState::Undisputed));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn encode_and_decode() {
+        let policy = Policy { overdraft: OverdraftPolicy::HoldPartial, ..Default::default() };
+        let mut account = spent_deposit();
+        assert!(account.deposit(Transaction::new(1, 3, Decimal::new(255, 1))).is_ok());
+        assert!(account.dispute(Operation::new(1, 1), &policy).is_ok());
+        let instruction = Instruction::Freeze(Administration::new(1, 4, 9, LockReason::Fraud));
+        assert!(account.apply(instruction, &policy).is_ok());
+
+        let mut encoded = Vec::new();
+        account.encode(1, &mut encoded).expect("failed to encode");
+        assert_eq!(String::from_utf8_lossy(&encoded), indoc::indoc!("
+            account 1 0.0 55.5 55.5 44.5 fraud:9
+            tx 1 100 disputed 0
+            tx 2 -70 undisputed 0
+            tx 3 25.5 undisputed 0
+            shortfall 1 44.5
+            end
+        "));
+
+        let mut source = io::Cursor::new(encoded);
+        let (client, decoded) = Account::decode(&mut source).expect("failed to decode").expect("no account decoded");
+        assert!(Account::decode(&mut source).expect("failed to decode").is_none());
+        assert_eq!(client, 1);
+
+        let mut reencoded = Vec::new();
+        decoded.encode(client, &mut reencoded).expect("failed to encode");
+        assert_eq!(String::from_utf8_lossy(&reencoded), String::from_utf8_lossy(&source.into_inner()));
+    }
+
+    #[test]
+    fn encode_and_decode_changes() {
+        let policy = Policy { retention: Retention::LastPerAccount(2), ..Default::default() };
+        let mut account = spent_deposit();
+        let mut encoded = Vec::new();
+        assert!(account.encode_changes(1, false, &mut encoded).expect("failed to encode"));
+
+        let instruction = Instruction::Deposit(Transaction::new(1, 3, Decimal::new(5, 0)));
+        assert!(account.apply_at(instruction, &policy, 7).is_ok());
+        let mut changes = Vec::new();
+        assert!(!account.encode_changes(1, false, &mut changes).expect("failed to encode"));
+        assert_eq!(String::from_utf8_lossy(&changes), indoc::indoc!("
+            account 1 35 0 35 0 -
+            drop 1
+            tx 3 5 undisputed 7
+            end
+        "));
+
+        encoded.extend(changes);
+        let (client, mut decoded) = Account::decode_changes(&mut encoded.as_slice()).expect("failed to decode").expect("no account decoded");
+        assert_eq!(client, 1);
+        assert_eq!(decoded.transactions().collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!(decoded.recorded(), vec![0, 7]);
+        let mut unchanged = Vec::new();
+        assert!(!decoded.encode_changes(1, false, &mut unchanged).expect("failed to encode"));
+        assert_eq!(String::from_utf8_lossy(&unchanged), "account 1 35 0 35 0 -\nend\n");
+
+        let (_, funds) = Account::decode_funds(&mut encoded.as_slice()).expect("failed to decode").expect("no account decoded");
+        assert_eq!((funds.total(), funds.transactions().count()), (Decimal::new(35, 0), 0));
+    }
+
+    #[test]
+    fn decode_corrupted() {
+        for encoded in ["account 1 0 0 0\n", "account 1 0 0 0 0 -\ntx 1 5\nend\n", "account 1 0 0 0 0 -\n", "tx 1 5 disputed\n"] {
+            let result = Account::decode(&mut io::Cursor::new(encoded));
+            assert!(matches!(result, Err(TransactionSystemError::CorruptedDataError{..})), "decoded {}", encoded);
+        }
+    }
+
+    #[test]
+    fn decode_without_sequences() {
+        let encoded = "account 1 5 0 5 0 -\ntx 1 5 undisputed\nend\n";
+        let (_, account) = Account::decode(&mut io::Cursor::new(encoded)).expect("failed to decode").expect("no account decoded");
+        assert_eq!(account.txhistory.sequences().get(&1), Some(&0));
+    }
+
+    fn deposit_at(account: &mut Account, tx: u32, policy: &Policy, sequence: u64) {
+        let instruction = Instruction::Deposit(Transaction::new(1, tx, Decimal::from_i32(10).unwrap()));
+        assert!(account.apply_at(instruction, policy, sequence).is_ok());
+    }
+
+    fn held_tx(account: &Account) -> Vec<u32> {
+        let mut txs: Vec<u32> = account.transactions().collect();
+        txs.sort();
+        txs
+    }
+
+    #[test]
+    fn retention_of_last_transactions() {
+        let policy = Policy { retention: Retention::LastPerAccount(2), ..Default::default() };
+        let mut account = Account::default();
+        for tx in 1..=3 {
+            deposit_at(&mut account, tx, &policy, tx as u64);
+        }
+        assert_eq!(held_tx(&account), [2, 3]);
+
+        // Disputed transaction is kept past the retention until the dispute is settled
+        let dispute = Instruction::Dispute(Operation::new(1, 2));
+        assert!(account.apply_at(dispute, &policy, 4).is_ok());
+        deposit_at(&mut account, 5, &policy, 5);
+        assert_eq!(held_tx(&account), [2, 3, 5]);
+        let resolve = Instruction::Resolve(Operation::new(1, 2));
+        assert!(account.apply_at(resolve, &policy, 6).is_ok());
+        assert_eq!(held_tx(&account), [3, 5]);
+        assert_eq!(account.available, Decimal::from_i32(40).unwrap());
+    }
+
+    #[test]
+    fn retention_window_of_instructions() {
+        let policy = Policy { retention: Retention::Instructions(10), ..Default::default() };
+        let mut account = Account::default();
+        deposit_at(&mut account, 1, &policy, 1);
+        deposit_at(&mut account, 2, &policy, 5);
+
+        let dispute = Instruction::Dispute(Operation::new(1, 1));
+        assert!(matches!(account.apply_at(dispute, &policy, 12), Err(TransactionSystemError::OperationError{..})));
+        assert_eq!(held_tx(&account), [2]);
+        let dispute = Instruction::Dispute(Operation::new(1, 2));
+        assert!(account.apply_at(dispute, &policy, 15).is_ok());
+        assert_eq!(account.held, Decimal::from_i32(10).unwrap());
+    }
+
+    #[test]
+    fn dispute_window() {
+        let policy = Policy { dispute_window: Some(Duration::from_secs(120 * 86_400)), ..Default::default() };
+        let at = |text: &str| text.parse().ok();
+        let mut account = Account::default();
+        for (tx, timestamp) in [(1, at("2023-01-01T00:00:00Z")), (2, None), (3, at("2023-03-01T00:00:00Z"))] {
+            let deposit = Transaction::new(1, tx, Decimal::from_i32(10).unwrap()).with_timestamp(timestamp);
+            assert!(account.apply(Instruction::Deposit(deposit), &policy).is_ok());
+        }
+
+        let dispute = |tx| Instruction::Dispute(Operation::new(1, tx).with_timestamp(at("2023-05-01T00:00:01Z")));
+        assert!(matches!(account.apply(dispute(1), &policy), Err(TransactionSystemError::ExpiredDisputeError{..})));
+        // Age of a transaction without timestamp is unknown, its disputes are let through
+        assert!(account.apply(dispute(2), &policy).is_ok());
+        assert!(account.apply(dispute(3), &policy).is_ok());
+        assert_eq!(account.held, Decimal::from_i32(20).unwrap());
+
+        let dispute = Instruction::Dispute(Operation::new(1, 1));
+        assert!(account.apply(dispute, &policy).is_ok());
+
+        let mut encoded = Vec::new();
+        account.encode(1, &mut encoded).expect("failed to encode");
+        let (_, decoded) = Account::decode(&mut io::Cursor::new(&encoded)).expect("failed to decode").expect("no account decoded");
+        let timestamps: Vec<_> = decoded.txhistory.iter(1).map(|transaction| transaction.timestamp()).collect();
+        assert_eq!(timestamps, [at("2023-01-01T00:00:00Z"), None, at("2023-03-01T00:00:00Z")]);
+        assert!(String::from_utf8_lossy(&encoded).contains("tx 1 10 disputed 0 2023-01-01T00:00:00Z\n"));
+    }
+}
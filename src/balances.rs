@@ -0,0 +1,129 @@
+use std::fs::File;
+use std::io::Read;
+use csv::{ErrorKind, Position, ReaderBuilder, StringRecord, Trim};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Deserializer};
+
+use crate::account::Account;
+use crate::compression;
+use crate::errors::TransactionSystemError;
+
+/// Opening balance of the client's account, in the same shape as `output::Output`
+#[derive(Debug, Deserialize)]
+pub struct Balance {
+    client: u16,
+    #[serde(deserialize_with = "deserialize_amount")]
+    available: Decimal,
+    #[serde(deserialize_with = "deserialize_amount")]
+    held: Decimal,
+    #[serde(deserialize_with = "deserialize_amount")]
+    total: Decimal,
+    locked: bool,
+}
+
+/// Amounts are parsed from their text, as going through floats could break the equality of balances
+fn deserialize_amount<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Decimal, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    text.parse().map_err(|_| serde::de::Error::custom(format!("invalid amount {}", text)))
+}
+
+impl Balance {
+    pub fn client(&self) -> u16 {
+        self.client
+    }
+
+    /// Turns the balance into the account, as long as `total == available + held`
+    pub fn into_account(self) -> Result<Account, TransactionSystemError> {
+        if self.available.checked_add(self.held) != Some(self.total) {
+            return Err(TransactionSystemError::ValidationError {
+                message: "total isn't the sum of available and held funds".to_owned(),
+                record: format!("{:?}", self),
+            });
+        }
+        Ok(Account::opening(self.available, self.held, self.total, self.locked))
+    }
+}
+
+/// Balances row with its position, holding the balance or the reason it couldn't be read
+#[derive(Debug)]
+pub struct BalanceRow {
+    pub position: Option<Position>,
+    pub balance: Result<Balance, TransactionSystemError>,
+}
+
+/// CSV input of opening balances with a header row
+pub struct BalancesReader<R: Read> {
+    name: String,
+    reader: csv::Reader<R>,
+    headers: StringRecord,
+    record: StringRecord,
+}
+
+impl<R: Read> BalancesReader<R> {
+    pub fn new(name: impl Into<String>, input: R) -> Result<Self, TransactionSystemError> {
+        let mut reader = ReaderBuilder::new()
+            .trim(Trim::All)
+            .from_reader(input);
+        let headers = reader.headers()?.clone();
+        Ok(Self { name: name.into(), reader, headers, record: StringRecord::new() })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Gives the next row or `None` at the end of input, like `InstructionSource::next_row()`
+    pub fn next_row(&mut self) -> Result<Option<BalanceRow>, TransactionSystemError> {
+        match self.reader.read_record(&mut self.record) {
+            Ok(false) => Ok(None),
+            Ok(true) => Ok(Some(BalanceRow {
+                position: self.record.position().cloned(),
+                balance: self.record.deserialize(Some(&self.headers)).map_err(Into::into),
+            })),
+            Err(error) if matches!(error.kind(), ErrorKind::Io(_)) => Err(error.into()),
+            Err(error) => Ok(Some(BalanceRow {
+                position: error.position().cloned(),
+                balance: Err(error.into()),
+            })),
+        }
+    }
+}
+
+/// Opens the balances file, decompressed on the fly when needed
+pub fn open(path: &str) -> Result<BalancesReader<Box<dyn Read>>, TransactionSystemError> {
+    BalancesReader::new(path, compression::decompress(path, File::open(path)?)?)
+}
+
+#[cfg(test)]
+mod test {
+    use indoc::indoc;
+    use rust_decimal::Decimal;
+    use super::BalancesReader;
+
+    #[test]
+    fn balances() {
+        let mut reader = BalancesReader::new("balances.csv", indoc!("
+            client, available, held,  total, locked
+                 1,    0.1000,  0.2, 0.3000,   true
+                 2,        10,    5,     14,  false
+                 3,        10,    x,     14,  false
+        ").as_bytes()).expect("failed to read headers");
+
+        let row = reader.next_row().expect("failed to read").expect("missing row");
+        let balance = row.balance.expect("failed to parse");
+        assert_eq!(balance.client(), 1);
+        let account = balance.into_account().expect("consistent balance refused");
+        assert_eq!((account.available(), account.held(), account.total()), (Decimal::new(1, 1), Decimal::new(2, 1), Decimal::new(3, 1)));
+        assert!(account.locked());
+
+        let row = reader.next_row().expect("failed to read").expect("missing row");
+        let error = row.balance.expect("failed to parse").into_account().expect_err("inconsistent balance accepted");
+        assert_eq!(error.code(), "INVALID_INSTRUCTION");
+
+        let row = reader.next_row().expect("failed to read").expect("missing row");
+        assert_eq!(row.balance.expect_err("malformed row accepted").code(), "MALFORMED_ROW");
+        assert_eq!(row.position.map(|position| position.line()), Some(4));
+
+        assert!(reader.next_row().expect("failed to read").is_none());
+    }
+}
@@ -0,0 +1,355 @@
+use std::time::Duration;
+use transation_system::account::{Policy, Retention};
+use transation_system::errors::TransactionSystemError;
+use transation_system::ingestion::ErrorBudget;
+use transation_system::output::{DecimalFormat, OutputFormat};
+use transation_system::rejects::RejectsFormat;
+use transation_system::source::InputFormat;
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+/// Command line arguments: input file names, processed in order with `-` standing for
+/// the standard input, preceded by options
+///
+/// `--input-format <csv|jsonl>` overrides the input format recognized from the file extension,
+/// `--withdrawal-disputes <provisional-credit|negated>` selects `WithdrawalDisputePolicy`,
+/// `--overdraft <allow-negative|reject-if-insufficient|hold-partial>` selects `OverdraftPolicy`,
+/// `--retain-last <transactions>` or `--retain-instructions <instructions>` selects `Retention`
+/// of transactions to be disputed,
+/// `--locked-accepts <types|none>` lists instruction types a frozen account still accepts as `LockedPolicy`,
+/// `--dispute-window <days>` rejects disputes of timestamped transactions coming later than that,
+/// `--continue-on-error` turns on resilient mode, which `--max-errors <count>` and
+/// `--max-error-ratio <ratio>` limit with `ErrorBudget`,
+/// `--rejects <file>` writes rejected rows report, in the format given with
+/// `--rejects-format <csv|jsonl>` or recognized from the file extension,
+/// `--output-format <csv|json|jsonl>` selects the format of the accounts' state written out,
+/// `--decimals <as-is|fixed|normalized>` selects `DecimalFormat` of the amounts,
+/// `--sorted` orders the accounts by client,
+/// `--opening-balances <file>` seeds the accounts with balances in the output CSV shape,
+/// `--restore <file>` starts from the state saved with `--snapshot <file>` by a previous run,
+/// `--store <directory>` keeps accounts on disk instead of memory, caching up to
+/// `--store-cache <accounts>` of them,
+/// `--wal <directory>` logs applied instructions to recover from after a crash, checkpointing
+/// the state every `--checkpoint-interval <instructions>`,
+/// `--threads <count>` applies instructions by worker threads sharded by client, which neither the write-ahead
+/// log nor the store support
+#[derive(Debug, Default)]
+pub struct Arguments {
+    pub inputfiles: Vec<String>,
+    pub input_format: Option<InputFormat>,
+    pub policy: Policy,
+    pub budget: Option<ErrorBudget>,
+    pub rejects: Option<String>,
+    pub rejects_format: Option<RejectsFormat>,
+    pub output_format: OutputFormat,
+    pub decimals: DecimalFormat,
+    pub sorted: bool,
+    pub opening_balances: Option<String>,
+    pub restore: Option<String>,
+    pub snapshot: Option<String>,
+    pub store: Option<String>,
+    pub store_cache: Option<usize>,
+    pub wal: Option<String>,
+    pub checkpoint_interval: Option<u64>,
+    pub threads: Option<usize>,
+}
+
+impl Arguments {
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, TransactionSystemError> {
+        use TransactionSystemError::ArgumentsError;
+
+        let mut arguments = Self::default();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--input-format" => {
+                    arguments.input_format = Some(Self::value(&arg, args.next())?);
+                },
+                "--withdrawal-disputes" => {
+                    arguments.policy.withdrawal_dispute = Self::value(&arg, args.next())?;
+                },
+                "--overdraft" => {
+                    arguments.policy.overdraft = Self::value(&arg, args.next())?;
+                },
+                "--retain-last" => {
+                    arguments.set_retention(Retention::LastPerAccount(Self::value(&arg, args.next())?))?;
+                },
+                "--retain-instructions" => {
+                    arguments.set_retention(Retention::Instructions(Self::value(&arg, args.next())?))?;
+                },
+                "--locked-accepts" => {
+                    arguments.policy.locked = Self::value(&arg, args.next())?;
+                },
+                "--dispute-window" => {
+                    let days: u64 = Self::value(&arg, args.next())?;
+                    arguments.policy.dispute_window = Some(Duration::from_secs(days.saturating_mul(SECONDS_PER_DAY)));
+                },
+                "--continue-on-error" => {
+                    arguments.budget.get_or_insert_with(ErrorBudget::default);
+                },
+                "--max-errors" => {
+                    arguments.budget.get_or_insert_with(ErrorBudget::default).max_errors = Some(Self::value(&arg, args.next())?);
+                },
+                "--max-error-ratio" => {
+                    arguments.budget.get_or_insert_with(ErrorBudget::default).max_ratio = Some(Self::value(&arg, args.next())?);
+                },
+                "--rejects" => {
+                    arguments.rejects = Some(Self::value(&arg, args.next())?);
+                },
+                "--rejects-format" => {
+                    arguments.rejects_format = Some(Self::value(&arg, args.next())?);
+                },
+                "--output-format" => {
+                    arguments.output_format = Self::value(&arg, args.next())?;
+                },
+                "--decimals" => {
+                    arguments.decimals = Self::value(&arg, args.next())?;
+                },
+                "--sorted" => {
+                    arguments.sorted = true;
+                },
+                "--opening-balances" => {
+                    arguments.opening_balances = Some(Self::value(&arg, args.next())?);
+                },
+                "--restore" => {
+                    arguments.restore = Some(Self::value(&arg, args.next())?);
+                },
+                "--snapshot" => {
+                    arguments.snapshot = Some(Self::value(&arg, args.next())?);
+                },
+                "--store" => {
+                    arguments.store = Some(Self::value(&arg, args.next())?);
+                },
+                "--store-cache" => {
+                    arguments.store_cache = Some(Self::value(&arg, args.next())?);
+                },
+                "--wal" => {
+                    arguments.wal = Some(Self::value(&arg, args.next())?);
+                },
+                "--checkpoint-interval" => {
+                    arguments.checkpoint_interval = Some(Self::value(&arg, args.next())?);
+                },
+                "--threads" => {
+                    arguments.threads = Some(Self::value(&arg, args.next())?);
+                },
+                _ if arg.starts_with("--") => {
+                    return Err(ArgumentsError(format!("unknown option {}", arg)));
+                },
+                _ => arguments.inputfiles.push(arg),
+            }
+        }
+
+        if arguments.inputfiles.is_empty() {
+            return Err(ArgumentsError("no input file provided".to_owned()));
+        }
+        if arguments.threads.is_some() && arguments.wal.is_some() {
+            return Err(ArgumentsError("--threads can't be used with --wal".to_owned()));
+        }
+        if arguments.threads.is_some() && arguments.store.is_some() {
+            return Err(ArgumentsError("--threads can't be used with --store".to_owned()));
+        }
+        Ok(arguments)
+    }
+
+    fn set_retention(&mut self, retention: Retention) -> Result<(), TransactionSystemError> {
+        if self.policy.retention != Retention::Unlimited {
+            return Err(TransactionSystemError::ArgumentsError("only one retention can be given".to_owned()));
+        }
+        self.policy.retention = retention;
+        Ok(())
+    }
+
+    fn value<T: std::str::FromStr>(option: &str, value: Option<String>) -> Result<T, TransactionSystemError> {
+        use TransactionSystemError::ArgumentsError;
+
+        let value = value.ok_or_else(|| ArgumentsError(format!("no value provided for {}", option)))?;
+        value.parse().map_err(|_| ArgumentsError(format!("invalid value {} for {}", value, option)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use transation_system::account::{LockedPolicy, OverdraftPolicy, Retention, WithdrawalDisputePolicy};
+    use transation_system::ingestion::ErrorBudget;
+    use transation_system::output::{DecimalFormat, OutputFormat};
+    use transation_system::rejects::RejectsFormat;
+    use transation_system::source::InputFormat;
+    use super::Arguments;
+
+    fn parse(args: &[&str]) -> Result<Arguments, transation_system::TransactionSystemError> {
+        Arguments::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn input_file() {
+        let arguments = parse(&["transactions.csv"]).expect("failed to parse arguments");
+        assert_eq!(arguments.inputfiles, ["transactions.csv"]);
+        assert_eq!(arguments.policy.withdrawal_dispute, WithdrawalDisputePolicy::ProvisionalCredit);
+        assert_eq!(arguments.budget, None);
+        assert_eq!(arguments.input_format, None);
+
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["--overdraft", "hold-partial"]).is_err());
+    }
+
+    #[test]
+    fn multiple_inputs() {
+        let arguments = parse(&["one.csv", "-", "--input-format", "jsonl", "two.jsonl"]).expect("failed to parse arguments");
+        assert_eq!(arguments.inputfiles, ["one.csv", "-", "two.jsonl"]);
+    }
+
+    #[test]
+    fn input_format() {
+        let arguments = parse(&["--input-format", "jsonl", "transactions.txt"]).expect("failed to parse arguments");
+        assert_eq!(arguments.input_format, Some(InputFormat::Jsonl));
+
+        assert!(parse(&["--input-format", "xml", "transactions.txt"]).is_err());
+    }
+
+    #[test]
+    fn withdrawal_disputes() {
+        let arguments = parse(&["--withdrawal-disputes", "negated", "transactions.csv"]).expect("failed to parse arguments");
+        assert_eq!(arguments.policy.withdrawal_dispute, WithdrawalDisputePolicy::Negated);
+
+        assert!(parse(&["--withdrawal-disputes", "whatever", "transactions.csv"]).is_err());
+        assert!(parse(&["transactions.csv", "--withdrawal-disputes"]).is_err());
+        assert!(parse(&["--unknown", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn overdraft() {
+        let arguments = parse(&["--overdraft", "hold-partial", "transactions.csv"]).expect("failed to parse arguments");
+        assert_eq!(arguments.policy.overdraft, OverdraftPolicy::HoldPartial);
+
+        let arguments = parse(&["--overdraft", "reject-if-insufficient", "transactions.csv"]).expect("failed to parse arguments");
+        assert_eq!(arguments.policy.overdraft, OverdraftPolicy::RejectIfInsufficient);
+    }
+
+    #[test]
+    fn error_budget() {
+        let arguments = parse(&["--continue-on-error", "transactions.csv"]).expect("failed to parse arguments");
+        assert_eq!(arguments.budget, Some(ErrorBudget::default()));
+
+        let arguments = parse(&["--max-errors", "10", "--max-error-ratio", "0.01", "transactions.csv"]).expect("failed to parse arguments");
+        assert_eq!(arguments.budget, Some(ErrorBudget { max_errors: Some(10), max_ratio: Some(0.01) }));
+
+        assert!(parse(&["--max-errors", "many", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn rejects() {
+        let arguments = parse(&["--rejects", "rejects.log", "--rejects-format", "jsonl", "transactions.csv"]).expect("failed to parse arguments");
+        assert_eq!(arguments.rejects.as_deref(), Some("rejects.log"));
+        assert_eq!(arguments.rejects_format, Some(RejectsFormat::Jsonl));
+
+        assert!(parse(&["--rejects-format", "xml", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn output_format() {
+        let arguments = parse(&["transactions.csv"]).expect("failed to parse arguments");
+        assert_eq!(arguments.output_format, OutputFormat::Csv);
+
+        let arguments = parse(&["--output-format", "json", "transactions.csv"]).expect("failed to parse arguments");
+        assert_eq!(arguments.output_format, OutputFormat::Json);
+
+        assert!(parse(&["--output-format", "yaml", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn reproducible_output() {
+        let arguments = parse(&["transactions.csv"]).expect("failed to parse arguments");
+        assert_eq!((arguments.decimals, arguments.sorted), (DecimalFormat::AsIs, false));
+
+        let arguments = parse(&["--decimals", "fixed", "--sorted", "transactions.csv"]).expect("failed to parse arguments");
+        assert_eq!((arguments.decimals, arguments.sorted), (DecimalFormat::Fixed, true));
+
+        assert!(parse(&["--decimals", "rounded", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn opening_balances() {
+        let arguments = parse(&["--opening-balances", "closing.csv", "transactions.csv"]).expect("failed to parse arguments");
+        assert_eq!(arguments.opening_balances.as_deref(), Some("closing.csv"));
+    }
+
+    #[test]
+    fn snapshot() {
+        let arguments = parse(&["--restore", "monday.snapshot", "--snapshot", "tuesday.snapshot", "transactions.csv"]).expect("failed to parse arguments");
+        assert_eq!(arguments.restore.as_deref(), Some("monday.snapshot"));
+        assert_eq!(arguments.snapshot.as_deref(), Some("tuesday.snapshot"));
+
+        assert!(parse(&["transactions.csv", "--snapshot"]).is_err());
+    }
+
+    #[test]
+    fn store() {
+        let arguments = parse(&["--store", "accounts", "--store-cache", "1000", "transactions.csv"]).expect("failed to parse arguments");
+        assert_eq!(arguments.store.as_deref(), Some("accounts"));
+        assert_eq!(arguments.store_cache, Some(1000));
+
+        assert!(parse(&["--store-cache", "-1", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn write_ahead_log() {
+        let arguments = parse(&["--wal", "wal", "--checkpoint-interval", "5000", "transactions.csv"]).expect("failed to parse arguments");
+        assert_eq!(arguments.wal.as_deref(), Some("wal"));
+        assert_eq!(arguments.checkpoint_interval, Some(5000));
+
+        assert!(parse(&["--checkpoint-interval", "often", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn threads() {
+        let arguments = parse(&["--threads", "8", "transactions.csv"]).expect("failed to parse arguments");
+        assert_eq!(arguments.threads, Some(8));
+
+        assert!(parse(&["--threads", "many", "transactions.csv"]).is_err());
+        assert!(parse(&["--threads", "8", "--wal", "wal", "transactions.csv"]).is_err());
+        assert!(parse(&["--threads", "8", "--store", "accounts", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn retention() {
+        let arguments = parse(&["transactions.csv"]).expect("failed to parse arguments");
+        assert_eq!(arguments.policy.retention, Retention::Unlimited);
+
+        let arguments = parse(&["--retain-last", "100", "transactions.csv"]).expect("failed to parse arguments");
+        assert_eq!(arguments.policy.retention, Retention::LastPerAccount(100));
+
+        let arguments = parse(&["--retain-instructions", "1000000", "transactions.csv"]).expect("failed to parse arguments");
+        assert_eq!(arguments.policy.retention, Retention::Instructions(1_000_000));
+
+        assert!(parse(&["--retain-last", "100", "--retain-instructions", "1000", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn dispute_window() {
+        let arguments = parse(&["transactions.csv"]).expect("failed to parse arguments");
+        assert_eq!(arguments.policy.dispute_window, None);
+
+        let arguments = parse(&["--dispute-window", "120", "transactions.csv"]).expect("failed to parse arguments");
+        assert_eq!(arguments.policy.dispute_window, Some(Duration::from_secs(120 * 86_400)));
+
+        assert!(parse(&["--dispute-window", "4m", "transactions.csv"]).is_err());
+    }
+
+    #[test]
+    fn locked_accepts() {
+        let arguments = parse(&["transactions.csv"]).expect("failed to parse arguments");
+        assert_eq!(arguments.policy.locked, LockedPolicy::default());
+
+        let arguments = parse(&["--locked-accepts", "deposit,resolve,chargeback", "transactions.csv"]).expect("failed to parse arguments");
+        let expected = LockedPolicy { deposit: true, withdrawal: false, dispute: false, resolve: true, chargeback: true };
+        assert_eq!(arguments.policy.locked, expected);
+
+        let arguments = parse(&["--locked-accepts", "none", "transactions.csv"]).expect("failed to parse arguments");
+        let expected = LockedPolicy { deposit: false, withdrawal: false, dispute: false, resolve: false, chargeback: false };
+        assert_eq!(arguments.policy.locked, expected);
+
+        assert!(parse(&["--locked-accepts", "deposit,freeze", "transactions.csv"]).is_err());
+        assert!(parse(&["--locked-accepts", "", "transactions.csv"]).is_err());
+    }
+}
@@ -0,0 +1,81 @@
+use std::str::{FromStr, SplitWhitespace};
+use crate::errors::TransactionSystemError;
+
+/// Space separated fields of a line of persisted data, where `-` stands for an absent value
+pub struct Fields<'a> {
+    line: &'a str,
+    fields: SplitWhitespace<'a>,
+}
+
+impl<'a> Fields<'a> {
+    pub fn new(line: &'a str) -> Self {
+        Self { line, fields: line.split_whitespace() }
+    }
+
+    pub fn next_str(&mut self) -> Result<&'a str, TransactionSystemError> {
+        self.fields.next().ok_or_else(|| self.corrupted("missing field"))
+    }
+
+    pub fn parse<T: FromStr>(&mut self) -> Result<T, TransactionSystemError> {
+        let field = self.next_str()?;
+        field.parse().map_err(|_| self.corrupted(&format!("invalid field {}", field)))
+    }
+
+    pub fn optional<T: FromStr>(&mut self) -> Result<Option<T>, TransactionSystemError> {
+        match self.next_str()? {
+            "-" => Ok(None),
+            field => field.parse().map(Some).map_err(|_| self.corrupted(&format!("invalid field {}", field))),
+        }
+    }
+
+    /// Field added to the end of lines by a later version of the format, absent in lines written earlier
+    pub fn trailing<T: FromStr>(&mut self) -> Result<Option<T>, TransactionSystemError> {
+        match self.fields.next() {
+            None => Ok(None),
+            Some(field) => field.parse().map(Some).map_err(|_| self.corrupted(&format!("invalid field {}", field))),
+        }
+    }
+
+    pub fn expect(&mut self, tag: &str) -> Result<(), TransactionSystemError> {
+        match self.next_str()? {
+            field if field == tag => Ok(()),
+            field => Err(self.corrupted(&format!("expected {} instead of {}", tag, field))),
+        }
+    }
+
+    pub fn corrupted(&self, message: &str) -> TransactionSystemError {
+        TransactionSystemError::CorruptedDataError {
+            message: format!("{} in line: {}", message, self.line.trim_end()),
+        }
+    }
+}
+
+/// Formats an optional value, written as `-` when absent
+pub fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map_or_else(|| "-".to_owned(), |value| value.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{optional, Fields};
+
+    #[test]
+    fn fields() {
+        let mut fields = Fields::new("tx 12 -1.5 - 7\n");
+        assert!(fields.expect("tx").is_ok());
+        assert_eq!(fields.parse::<u32>().ok(), Some(12));
+        assert_eq!(fields.next_str().ok(), Some("-1.5"));
+        assert_eq!(fields.optional::<u32>().ok(), Some(None));
+        assert_eq!(fields.optional::<u32>().ok(), Some(Some(7)));
+        assert!(fields.next_str().is_err());
+        assert_eq!(fields.trailing::<u32>().ok(), Some(None));
+        assert!(Fields::new("x").trailing::<u32>().is_err());
+
+        let mut fields = Fields::new("tx twelve");
+        assert!(fields.expect("account").is_err());
+        assert!(fields.parse::<u32>().is_err());
+
+        assert_eq!(optional(Some(5)), "5");
+        assert_eq!(optional::<u32>(None), "-");
+    }
+}
@@ -0,0 +1,220 @@
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use flate2::read::MultiGzDecoder;
+use log::debug;
+use ruzstd::decoding::errors::{FrameDecoderError, ReadFrameHeaderError};
+use ruzstd::decoding::{BlockDecodingStrategy, FrameDecoder};
+
+use crate::errors::TransactionSystemError;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+/// Length of the longest magic bytes
+const MAGIC_LENGTH: usize = 4;
+
+/// Compression of the input, decompressed in-process as a stream,
+/// so the decompressed data never has to be held in memory as a whole
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Recognizes the compression by `.gz` or `.zst` extension
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("gz") | Some("gzip") => Compression::Gzip,
+            Some("zst") | Some("zstd") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+
+    /// Recognizes the compression by magic bytes at the beginning of the data
+    pub fn from_magic(data: &[u8]) -> Self {
+        if data.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if data.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+
+/// Zstandard frames decoded one after another, as the `zstd` tool does with concatenated ones,
+/// skipping skippable frames and verifying the checksum of those which have it
+struct ZstdFrames<R: Read> {
+    source: BufReader<R>,
+    decoder: FrameDecoder,
+    framed: bool,
+}
+
+impl<R: Read> ZstdFrames<R> {
+    fn new(source: R) -> Self {
+        Self { source: BufReader::new(source), decoder: FrameDecoder::new(), framed: false }
+    }
+
+    fn invalid(error: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+
+    /// Starts the next frame, giving false at the end of the input
+    fn next_frame(&mut self) -> io::Result<bool> {
+        loop {
+            if self.source.fill_buf()?.is_empty() {
+                return Ok(false);
+            }
+            match self.decoder.reset(&mut self.source) {
+                Ok(()) => return Ok(true),
+                Err(FrameDecoderError::ReadFrameHeaderError(ReadFrameHeaderError::SkipFrame { length, .. })) => {
+                    let skipped = io::copy(&mut Read::by_ref(&mut self.source).take(u64::from(length)), &mut io::sink())?;
+                    if skipped < u64::from(length) {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "skippable zstd frame cut short"));
+                    }
+                },
+                Err(error) => return Err(Self::invalid(error)),
+            }
+        }
+    }
+
+    fn verify(&self) -> io::Result<()> {
+        match self.decoder.get_checksum_from_data() {
+            Some(checksum) if Some(checksum) != self.decoder.get_calculated_checksum() => {
+                Err(io::Error::new(io::ErrorKind::InvalidData, "zstd frame checksum mismatch"))
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<R: Read> Read for ZstdFrames<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.framed {
+                while self.decoder.can_collect() == 0 && !self.decoder.is_finished() {
+                    self.decoder.decode_blocks(&mut self.source, BlockDecodingStrategy::UptoBlocks(1)).map_err(Self::invalid)?;
+                }
+                let length = self.decoder.read(buf)?;
+                if length > 0 {
+                    return Ok(length);
+                }
+                self.verify()?;
+                self.framed = false;
+            }
+            if !self.next_frame()? {
+                return Ok(0);
+            }
+            self.framed = true;
+        }
+    }
+}
+
+/// Decompresses the input when it starts with magic bytes of a known compression,
+/// or when the compression is known from its name; anything else is passed through
+pub fn decompress(name: &str, mut input: impl Read + Send + 'static) -> Result<Box<dyn Read>, TransactionSystemError> {
+    // A single read may give less, as pipes do
+    let mut magic = Vec::with_capacity(MAGIC_LENGTH);
+    (&mut input).take(MAGIC_LENGTH as u64).read_to_end(&mut magic)?;
+    let compression = match Compression::from_magic(&magic) {
+        Compression::None => Compression::from_path(Path::new(name)),
+        compression => compression,
+    };
+    let input = io::Cursor::new(magic).chain(input);
+
+    debug!("Reading {} compressed with {:?}", name, compression);
+    Ok(match compression {
+        Compression::Gzip => Box::new(MultiGzDecoder::new(input)),
+        Compression::Zstd => Box::new(ZstdFrames::new(input)),
+        Compression::None => Box::new(input),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read};
+    use std::path::Path;
+    use super::{decompress, Compression};
+
+    /// `deposit,1,1,1.5` line compressed with `gzip -n`
+    const GZIP_DATA: &[u8] = &[
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x4b, 0x49, 0x2d, 0xc8, 0x2f, 0xce,
+        0x2c, 0xd1, 0x31, 0x04, 0x41, 0x3d, 0x53, 0x2e, 0x00, 0x7f, 0xab, 0x79, 0xe2, 0x10, 0x00, 0x00,
+        0x00,
+    ];
+
+    /// `deposit,1,1,1.5` line compressed with `zstd`
+    const ZSTD_DATA: &[u8] = &[
+        0x28, 0xb5, 0x2f, 0xfd, 0x04, 0x58, 0x81, 0x00, 0x00, 0x64, 0x65, 0x70, 0x6f, 0x73, 0x69, 0x74,
+        0x2c, 0x31, 0x2c, 0x31, 0x2c, 0x31, 0x2e, 0x35, 0x0a, 0xd8, 0xd1, 0xb1, 0x34,
+    ];
+
+    fn read_all(name: &str, data: impl Read + Send + 'static) -> std::io::Result<String> {
+        let mut output = String::new();
+        decompress(name, data).expect("failed to start decompression").read_to_string(&mut output)?;
+        Ok(output)
+    }
+
+    /// Gives the data a byte per read, as a slow pipe may
+    struct Trickle(&'static [u8]);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let length = buf.len().min(self.0.len()).min(1);
+            buf[..length].copy_from_slice(&self.0[..length]);
+            self.0 = &self.0[length..];
+            Ok(length)
+        }
+    }
+
+    #[test]
+    fn detection() {
+        assert_eq!(Compression::from_magic(GZIP_DATA), Compression::Gzip);
+        assert_eq!(Compression::from_magic(ZSTD_DATA), Compression::Zstd);
+        assert_eq!(Compression::from_magic(b"type,client"), Compression::None);
+        assert_eq!(Compression::from_path(Path::new("input.csv.gz")), Compression::Gzip);
+        assert_eq!(Compression::from_path(Path::new("input.csv.zst")), Compression::Zstd);
+        assert_eq!(Compression::from_path(Path::new("input.csv")), Compression::None);
+    }
+
+    #[test]
+    fn streams() {
+        assert_eq!(read_all("input.csv", GZIP_DATA).expect("failed to decompress"), "deposit,1,1,1.5\n");
+        assert_eq!(read_all("input", ZSTD_DATA).expect("failed to decompress"), "deposit,1,1,1.5\n");
+        assert_eq!(read_all("input.csv", &b"deposit,1,1,1.5\n"[..]).expect("failed to read"), "deposit,1,1,1.5\n");
+        assert!(read_all("input.csv", &GZIP_DATA[..20]).is_err());
+        assert_eq!(read_all("input.csv", &b"ab"[..]).expect("failed to read"), "ab");
+    }
+
+    #[test]
+    fn short_reads() {
+        let mut output = String::new();
+        decompress("-", Trickle(ZSTD_DATA)).expect("failed to start decompression").read_to_string(&mut output)
+            .expect("failed to decompress");
+        assert_eq!(output, "deposit,1,1,1.5\n");
+    }
+
+    #[test]
+    fn concatenated() {
+        let gzip = [GZIP_DATA, GZIP_DATA].concat();
+        assert_eq!(read_all("-", io::Cursor::new(gzip)).expect("failed to decompress"), "deposit,1,1,1.5\ndeposit,1,1,1.5\n");
+        // A skippable frame of 4 bytes between the two
+        let skippable: &[u8] = &[0x50, 0x2a, 0x4d, 0x18, 0x04, 0x00, 0x00, 0x00, 0xde, 0xad, 0xbe, 0xef];
+        let zstd = [ZSTD_DATA, skippable, ZSTD_DATA].concat();
+        assert_eq!(read_all("-", io::Cursor::new(zstd)).expect("failed to decompress"), "deposit,1,1,1.5\ndeposit,1,1,1.5\n");
+    }
+
+    #[test]
+    fn corrupted() {
+        assert!(read_all("-", &ZSTD_DATA[..20]).is_err());
+        let mut checksum = ZSTD_DATA.to_vec();
+        *checksum.last_mut().expect("no data") ^= 0xff;
+        assert!(read_all("-", io::Cursor::new(checksum)).is_err());
+        assert!(read_all("input.csv.zst", &b"not really"[..]).is_err());
+        assert!(read_all("input.csv.gz", &b"not really"[..]).is_err());
+    }
+}
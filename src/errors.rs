@@ -1,29 +1,219 @@
 use thiserror::Error;
+use rust_decimal::Decimal;
 use csv::Error as CSVError;
-use crate::instructions::{Transaction, Operation, TransactionState};
+use crate::instructions::{Instruction, Transaction, Operation, Administration, TransactionState};
 use std::io::Error as IOError;
+use std::fmt;
+use std::time::Duration;
 
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum TransactionSystemError {
     #[error("Arguments error")]
     ArgumentsError(String),
-    #[error("CSV processing failure")]
+    #[error("CSV processing failure: {0}")]
     CSVError(#[from] CSVError),
+    #[error("JSON processing failure: {message}")]
+    JSONError {
+        message: String,
+    },
+    #[error("CSV row parsing failure: {message}")]
+    RowError {
+        message: String,
+    },
     #[error("I/O operation failure")]
     IOError(#[from] IOError),
+    #[error("Corrupted persisted data: {message}")]
+    CorruptedDataError {
+        message: String,
+    },
+    #[error("Error budget exceeded with {errors} bad rows out of {rows} read")]
+    ErrorBudgetError {
+        errors: u64,
+        rows: u64,
+    },
+    #[error("Invalid instruction: {message} / {record}")]
+    ValidationError {
+        message: String,
+        record: String,
+    },
     #[error("Transaction processing failure: {message} / {transaction:?}")]
     TransactionError {
         message: String,
         transaction: Transaction,
     },
+    #[error("Transaction id already used by client {client}: {transaction:?}")]
+    DuplicateTransactionError {
+        client: u16,
+        transaction: Transaction,
+    },
     #[error("Operation executing failure: {message} / {operation:?}")]
     OperationError {
         message: String,
         operation: Operation,
     },
-    #[error("Illegal attempt to change state: {oldstate} => {newstate}")]
+    #[error("Transaction evicted from the history by retention policy: {operation:?}")]
+    EvictedTransactionError {
+        operation: Operation,
+    },
+    #[error("Dispute later than {window:?} after the transaction: {operation:?}")]
+    ExpiredDisputeError {
+        window: Duration,
+        operation: Operation,
+    },
+    #[error("Insufficient available funds {available} to hold disputed amount: {operation:?}")]
+    InsufficientFundsError {
+        available: Decimal,
+        operation: Operation,
+    },
+    #[error("Insufficient available funds {available} to withdraw: {transaction:?}")]
+    WithdrawalOverdraftError {
+        available: Decimal,
+        transaction: Transaction,
+    },
+    #[error("Administration executing failure: {message} / {administration:?}")]
+    AdministrationError {
+        message: String,
+        administration: Administration,
+    },
+    #[error("Illegal attempt to change state of client {client} tx {tx}: {oldstate} => {newstate}")]
     TransactionStateError {
+        client: u16,
+        tx: u32,
         oldstate: TransactionState,
         newstate: TransactionState
+    },
+    #[error("Balance overflow of client {client} tx {tx}")]
+    BalanceOverflowError {
+        client: u16,
+        tx: u32,
+    },
+    #[error("Instruction refused by locked account: {instruction:?}")]
+    AccountLockedError {
+        instruction: Instruction,
+    },
+}
+
+/// Kind of the failure with its stable machine-readable code, for tools not to depend on messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    InvalidArguments,
+    MalformedRow,
+    IoFailure,
+    CorruptedData,
+    ErrorBudgetExceeded,
+    InvalidInstruction,
+    TransactionFailure,
+    InsufficientFunds,
+    DuplicateTx,
+    UnknownTx,
+    EvictedTx,
+    ExpiredDispute,
+    IllegalLockTransition,
+    IllegalStateTransition,
+    BalanceOverflow,
+    AccountLocked,
+}
+
+impl ErrorKind {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorKind::InvalidArguments       => "INVALID_ARGUMENTS",
+            ErrorKind::MalformedRow           => "MALFORMED_ROW",
+            ErrorKind::IoFailure              => "IO_FAILURE",
+            ErrorKind::CorruptedData          => "CORRUPTED_DATA",
+            ErrorKind::ErrorBudgetExceeded    => "ERROR_BUDGET_EXCEEDED",
+            ErrorKind::InvalidInstruction     => "INVALID_INSTRUCTION",
+            ErrorKind::TransactionFailure     => "TRANSACTION_FAILURE",
+            ErrorKind::InsufficientFunds      => "INSUFFICIENT_FUNDS",
+            ErrorKind::DuplicateTx            => "DUPLICATE_TX",
+            ErrorKind::UnknownTx              => "UNKNOWN_TX",
+            ErrorKind::EvictedTx              => "EVICTED_TX",
+            ErrorKind::ExpiredDispute         => "EXPIRED_DISPUTE",
+            ErrorKind::IllegalLockTransition  => "ILLEGAL_LOCK_TRANSITION",
+            ErrorKind::IllegalStateTransition => "ILLEGAL_STATE_TRANSITION",
+            ErrorKind::BalanceOverflow        => "BALANCE_OVERFLOW",
+            ErrorKind::AccountLocked          => "ACCOUNT_LOCKED",
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+impl TransactionSystemError {
+    pub fn kind(&self) -> ErrorKind {
+        use TransactionSystemError::*;
+
+        match self {
+            ArgumentsError(_)                 => ErrorKind::InvalidArguments,
+            CSVError(_)                       => ErrorKind::MalformedRow,
+            JSONError{..}                     => ErrorKind::MalformedRow,
+            RowError{..}                      => ErrorKind::MalformedRow,
+            IOError(_)                        => ErrorKind::IoFailure,
+            CorruptedDataError{..}            => ErrorKind::CorruptedData,
+            ErrorBudgetError{..}              => ErrorKind::ErrorBudgetExceeded,
+            ValidationError{..}               => ErrorKind::InvalidInstruction,
+            TransactionError{..}              => ErrorKind::TransactionFailure,
+            DuplicateTransactionError{..}     => ErrorKind::DuplicateTx,
+            OperationError{..}                => ErrorKind::UnknownTx,
+            EvictedTransactionError{..}       => ErrorKind::EvictedTx,
+            ExpiredDisputeError{..}           => ErrorKind::ExpiredDispute,
+            InsufficientFundsError{..}        => ErrorKind::InsufficientFunds,
+            WithdrawalOverdraftError{..}      => ErrorKind::InsufficientFunds,
+            AdministrationError{..}           => ErrorKind::IllegalLockTransition,
+            TransactionStateError{..}         => ErrorKind::IllegalStateTransition,
+            BalanceOverflowError{..}          => ErrorKind::BalanceOverflow,
+            AccountLockedError{..}            => ErrorKind::AccountLocked,
+        }
+    }
+
+    /// Stable machine-readable code of the failure
+    pub fn code(&self) -> &'static str {
+        self.kind().code()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use rust_decimal::Decimal;
+    use crate::instructions::{Operation, Transaction, TransactionState};
+    use super::{ErrorKind, TransactionSystemError};
+
+    #[test]
+    fn codes() {
+        let error = TransactionSystemError::OperationError {
+            message: "attempt to dispute non-existing transaction".to_owned(),
+            operation: Operation::new(1, 2),
+        };
+        assert_eq!(error.kind(), ErrorKind::UnknownTx);
+        assert_eq!(error.code(), "UNKNOWN_TX");
+
+        let error = TransactionSystemError::EvictedTransactionError { operation: Operation::new(1, 2) };
+        assert_eq!(error.code(), "EVICTED_TX");
+
+        let error = TransactionSystemError::ExpiredDisputeError { window: Duration::from_secs(86_400), operation: Operation::new(1, 2) };
+        assert_eq!(error.code(), "EXPIRED_DISPUTE");
+
+        let error = TransactionSystemError::TransactionStateError {
+            client: 1,
+            tx: 2,
+            oldstate: TransactionState::Chargedback,
+            newstate: TransactionState::Disputed,
+        };
+        assert_eq!(error.code(), "ILLEGAL_STATE_TRANSITION");
+        assert_eq!(error.to_string(), "Illegal attempt to change state of client 1 tx 2: chargedback => disputed");
+        assert_eq!(ErrorKind::AccountLocked.to_string(), "ACCOUNT_LOCKED");
+
+        let transaction = || Transaction::new(1, 2, Decimal::new(15, 1));
+        let error = TransactionSystemError::WithdrawalOverdraftError { available: Decimal::new(1, 0), transaction: transaction() };
+        assert_eq!(error.code(), "INSUFFICIENT_FUNDS");
+        let error = TransactionSystemError::TransactionError { message: "failure".to_owned(), transaction: transaction() };
+        assert_eq!(error.kind(), ErrorKind::TransactionFailure);
+        assert_eq!(error.code(), "TRANSACTION_FAILURE");
     }
-}
\ No newline at end of file
+}
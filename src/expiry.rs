@@ -0,0 +1,92 @@
+use std::collections::VecDeque;
+use log::trace;
+
+use crate::account::{Policy, Retention};
+use crate::result::Result;
+use crate::store::AccountStore;
+
+/// Transactions of all the accounts in the order of instructions recording them, so they're evicted
+/// out of `Retention::Instructions` as the clock advances, whether their accounts get any instruction
+/// or not; 16 bytes per transaction within the retention
+///
+/// Seeded from the accounts in the store once the retention is first enforced, and again after
+/// the accounts were replaced.
+#[derive(Debug, Default)]
+pub(crate) struct Expiry {
+    order: Option<VecDeque<(u64, u16)>>,
+}
+
+impl Expiry {
+    /// Notes the transaction of the client was recorded by the instruction with the given sequence
+    pub fn record(&mut self, policy: &Policy, sequence: u64, client: u16) {
+        if let (Retention::Instructions(_), Some(order)) = (policy.retention, self.order.as_mut()) {
+            order.push_back((sequence, client));
+        }
+    }
+
+    /// Evicts transactions out of the retention from the histories of their accounts, as of
+    /// the instruction with the given sequence
+    pub fn expire(&mut self, store: &mut dyn AccountStore, policy: &Policy, sequence: u64) -> Result {
+        let Retention::Instructions(window) = policy.retention else {
+            return Ok(());
+        };
+        if self.order.is_none() {
+            let mut order = Vec::new();
+            store.for_each(&mut |client, account| {
+                order.extend(account.recorded().into_iter().map(|recorded| (recorded, client)));
+                Ok(())
+            })?;
+            order.sort_unstable();
+            self.order = Some(order.into());
+        }
+
+        let order = self.order.get_or_insert_with(VecDeque::new);
+        while let Some(&(recorded, client)) = order.front() {
+            if recorded.saturating_add(window) >= sequence {
+                break;
+            }
+            order.pop_front();
+            trace!("history of client {} expires as of sequence {}", client, sequence);
+            store.get_or_create(client)?.expire(policy.retention, sequence);
+        }
+        Ok(())
+    }
+
+    /// Forgets the transactions, to be seeded again from the accounts which replaced theirs
+    pub fn reset(&mut self) {
+        self.order = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::Decimal;
+    use crate::account::{Policy, Retention};
+    use crate::instructions::{Instruction, Transaction};
+    use crate::store::{AccountStore, MemoryStore};
+    use super::Expiry;
+
+    #[test]
+    fn expires_untouched_accounts() {
+        let policy = Policy { retention: Retention::Instructions(2), ..Default::default() };
+        let mut store = MemoryStore::default();
+        let mut expiry = Expiry::default();
+        for (sequence, client) in [(1, 1), (2, 2), (3, 2), (4, 2)] {
+            expiry.expire(&mut store, &policy, sequence).expect("failed to expire");
+            let deposit = Instruction::Deposit(Transaction::new(client, sequence as u32, Decimal::ONE));
+            store.get_or_create(client).expect("failed to get account").apply_at(deposit, &policy, sequence).expect("failed to deposit");
+            expiry.record(&policy, sequence, client);
+        }
+
+        let transactions = |store: &mut MemoryStore, client| -> Vec<u32> {
+            store.get_or_create(client).expect("failed to get account").transactions().collect()
+        };
+        assert_eq!(transactions(&mut store, 1), [] as [u32; 0]);
+        assert_eq!(transactions(&mut store, 2), [2, 3, 4]);
+
+        // Seeded again from the accounts, as restored
+        let mut expiry = Expiry::default();
+        expiry.expire(&mut store, &policy, 6).expect("failed to expire");
+        assert_eq!(transactions(&mut store, 2), [4]);
+    }
+}
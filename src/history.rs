@@ -0,0 +1,352 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::mem;
+use log::trace;
+use rust_decimal::Decimal;
+
+use crate::account::Retention;
+use crate::instructions::{Transaction, TransactionState};
+use crate::timestamp::Timestamp;
+
+/// Bits of `Entry::flags` holding the state
+const STATE: u8 = 0x03;
+/// Flag of negative amounts, kept apart from the magnitude so even a negative zero survives
+const NEGATIVE: u8 = 0x40;
+/// Flag of amounts which magnitude doesn't fit 64 bits, kept in `TxHistory::spilled` instead
+const SPILLED: u8 = 0x80;
+/// Time of transactions without timestamp, among ones with it
+const UNTIMED: i64 = i64::MIN;
+
+/// Transaction packed into 16 bytes: the amount as its magnitude with scale and sign, the state
+/// and the id; the client is known to the account and not repeated
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    magnitude: u64,
+    tx: u32,
+    scale: u8,
+    flags: u8,
+}
+
+impl Entry {
+    fn state(&self) -> TransactionState {
+        match self.flags & STATE {
+            0 => TransactionState::Undisputed,
+            1 => TransactionState::Disputed,
+            2 => TransactionState::Resolved,
+            _ => TransactionState::Chargedback,
+        }
+    }
+
+    fn set_state(&mut self, state: TransactionState) {
+        let bits = match state {
+            TransactionState::Undisputed => 0,
+            TransactionState::Disputed => 1,
+            TransactionState::Resolved => 2,
+            TransactionState::Chargedback => 3,
+        };
+        self.flags = self.flags & !STATE | bits;
+    }
+}
+
+/// Deposits and withdrawals of an account which may still be disputed
+///
+/// Entries take 16 bytes each in a vector sorted by `tx`; ids growing over time, as they usually do,
+/// are appended, others are inserted moving the entries with greater ids. Lookups are binary searches,
+/// starting with the last entry. Amounts over 64 bits of mantissa, unlikely as they are, are kept aside
+/// in a map. Timestamps take another 8 bytes per entry, in a vector along the entries, once any of
+/// the transactions has one. With a retention other than `Retention::Unlimited` the order of transactions
+/// to evict takes another 16 bytes per entry. Vectors may hold up to as much capacity not used yet again.
+#[derive(Debug, Default)]
+pub(crate) struct TxHistory {
+    entries: Vec<Entry>,
+    spilled: HashMap<u32, Decimal>,
+    /// Seconds since the epoch of the entries at the same indexes, empty until any is timestamped
+    times: Vec<i64>,
+    /// Transactions with sequences of instructions recording them, the oldest first, tracked
+    /// under retention only
+    order: Option<VecDeque<(u32, u64)>>,
+    /// Transactions due for eviction kept until their disputes are settled
+    pinned: Vec<(u32, u64)>,
+    /// Transactions inserted, changed or removed since they were last taken, once it's asked for
+    changed: Option<HashSet<u32>>,
+}
+
+impl TxHistory {
+    fn find(&self, tx: u32) -> std::result::Result<usize, usize> {
+        match self.entries.last() {
+            Some(last) if last.tx < tx => Err(self.entries.len()),
+            Some(last) if last.tx == tx => Ok(self.entries.len() - 1),
+            _ => self.entries.binary_search_by_key(&tx, |entry| entry.tx),
+        }
+    }
+
+    fn change(&mut self, tx: u32) {
+        if let Some(changed) = self.changed.as_mut() {
+            changed.insert(tx);
+        }
+    }
+
+    /// Starts tracking the transactions changed, if it's not tracking them yet
+    pub fn track_changes(&mut self) {
+        self.changed.get_or_insert_with(HashSet::new);
+    }
+
+    /// Takes the ids of the transactions changed, in their order, or `None` unless they are tracked
+    pub fn take_changes(&mut self) -> Option<Vec<u32>> {
+        self.changed.as_mut().map(|changed| {
+            let mut changes: Vec<u32> = changed.drain().collect();
+            changes.sort_unstable();
+            changes
+        })
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Records the transaction with its state, replacing the one with the same `tx`
+    pub fn insert(&mut self, transaction: &Transaction) {
+        let amount = transaction.amount();
+        let mut entry = Entry {
+            magnitude: 0,
+            tx: transaction.tx(),
+            scale: amount.scale() as u8,
+            flags: if amount.is_sign_negative() { NEGATIVE } else { 0 },
+        };
+        entry.set_state(transaction.state());
+        self.change(entry.tx);
+        match u64::try_from(amount.mantissa().unsigned_abs()) {
+            Ok(magnitude) => {
+                entry.magnitude = magnitude;
+                self.spilled.remove(&entry.tx);
+            },
+            Err(_) => {
+                entry.flags |= SPILLED;
+                self.spilled.insert(entry.tx, amount);
+            },
+        }
+
+        let time = transaction.timestamp().map_or(UNTIMED, |timestamp| timestamp.unix());
+        let timed = time != UNTIMED || !self.times.is_empty();
+        if timed {
+            self.times.resize(self.entries.len(), UNTIMED);
+        }
+        match self.find(entry.tx) {
+            Ok(index) => {
+                self.entries[index] = entry;
+                if timed {
+                    self.times[index] = time;
+                }
+            },
+            Err(index) => {
+                self.entries.insert(index, entry);
+                if timed {
+                    self.times.insert(index, time);
+                }
+            },
+        }
+    }
+
+    fn timestamp(&self, index: usize) -> Option<Timestamp> {
+        self.times.get(index).copied().filter(|&time| time != UNTIMED).and_then(Timestamp::from_unix)
+    }
+
+    fn unpack(&self, client: u16, index: usize) -> Transaction {
+        let entry = &self.entries[index];
+        Transaction::new(client, entry.tx, self.amount(entry)).with_state(entry.state()).with_timestamp(self.timestamp(index))
+    }
+
+    fn amount(&self, entry: &Entry) -> Decimal {
+        if entry.flags & SPILLED != 0 {
+            return self.spilled[&entry.tx];
+        }
+        let magnitude = entry.magnitude;
+        let mut amount = Decimal::from_parts(magnitude as u32, (magnitude >> 32) as u32, 0, false, entry.scale as u32);
+        // Set apart, as a negative zero isn't made by `from_parts()`
+        amount.set_sign_negative(entry.flags & NEGATIVE != 0);
+        amount
+    }
+
+    /// Transaction of the client unpacked from the history; changes of its state are kept with `set_state()`
+    pub fn get(&self, client: u16, tx: u32) -> Option<Transaction> {
+        self.find(tx).ok().map(|index| self.unpack(client, index))
+    }
+
+    pub fn set_state(&mut self, tx: u32, state: TransactionState) {
+        if let Ok(index) = self.find(tx) {
+            self.entries[index].set_state(state);
+            self.change(tx);
+        }
+    }
+
+    fn state(&self, tx: u32) -> Option<TransactionState> {
+        self.find(tx).ok().map(|index| self.entries[index].state())
+    }
+
+    pub fn remove(&mut self, tx: u32) {
+        if let Ok(index) = self.find(tx) {
+            self.change(tx);
+            let entry = self.entries.remove(index);
+            if !self.times.is_empty() {
+                self.times.remove(index);
+            }
+            if entry.flags & SPILLED != 0 {
+                self.spilled.remove(&tx);
+            }
+        }
+    }
+
+    /// Transactions of the client unpacked from the history, in the order of ids
+    pub fn iter(&self, client: u16) -> impl Iterator<Item = Transaction> + '_ {
+        (0..self.entries.len()).map(move |index| self.unpack(client, index))
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.entries.iter().map(|entry| entry.tx)
+    }
+
+    /// Sequences of the instructions recording the transactions, known under retention only
+    pub fn sequences(&self) -> HashMap<u32, u64> {
+        self.order.iter().flatten().chain(&self.pinned).copied().collect()
+    }
+
+    /// Sequences of the given transactions, sorted by id, looked up from the latest ones recorded as changes usually are
+    pub fn sequences_of(&self, txs: &[u32]) -> HashMap<u32, u64> {
+        let mut sequences = HashMap::with_capacity(txs.len());
+        let wanted = txs.iter().filter(|&&tx| self.find(tx).is_ok()).count();
+        if wanted == 0 {
+            return sequences;
+        }
+        for &(tx, sequence) in self.pinned.iter().chain(self.order.iter().flat_map(|order| order.iter().rev())) {
+            if txs.binary_search(&tx).is_ok() {
+                sequences.insert(tx, sequence);
+                if sequences.len() == wanted {
+                    break;
+                }
+            }
+        }
+        sequences
+    }
+
+    /// Sequences of the instructions recording the transactions, 0 of the ones recorded without retention
+    pub fn recorded(&self) -> Vec<u64> {
+        match &self.order {
+            Some(order) => order.iter().chain(&self.pinned).map(|&(_, sequence)| sequence).collect(),
+            None => vec![0; self.entries.len()],
+        }
+    }
+
+    /// Notes the transaction was recorded by the instruction with the given sequence
+    pub fn record(&mut self, tx: u32, sequence: u64) {
+        if let Some(order) = self.order.as_mut() {
+            order.push_back((tx, sequence));
+            self.change(tx);
+        }
+    }
+
+    /// Tracks the order of transactions restored with their sequences
+    pub fn track(&mut self, mut order: Vec<(u32, u64)>) {
+        order.sort_by_key(|&(_, sequence)| sequence);
+        self.order = Some(order.into());
+        self.pinned.clear();
+    }
+
+    /// Evicts transactions out of the retention, as of the instruction with the given sequence
+    pub fn retain(&mut self, retention: Retention, sequence: u64) {
+        if retention == Retention::Unlimited {
+            // Nothing to evict, the order isn't paid for
+            self.order = None;
+            self.pinned = Vec::new();
+            return;
+        }
+        let mut order = match self.order.take() {
+            Some(order) => order,
+            // History recorded without retention has no sequences, it's the oldest there is
+            None => self.entries.iter().map(|entry| (entry.tx, 0)).collect(),
+        };
+
+        while let Some(&(tx, recorded)) = order.front() {
+            let expired = match retention {
+                Retention::Unlimited => false,
+                Retention::LastPerAccount(count) => order.len() > count,
+                Retention::Instructions(window) => recorded.saturating_add(window) < sequence,
+            };
+            if !expired {
+                break;
+            }
+            order.pop_front();
+            if self.state(tx) == Some(TransactionState::Disputed) {
+                self.pinned.push((tx, recorded));
+            } else {
+                trace!("tx {} evicted from history", tx);
+                self.remove(tx);
+            }
+        }
+        self.order = Some(order);
+
+        let mut pinned = mem::take(&mut self.pinned);
+        pinned.retain(|&(tx, _)| {
+            let disputed = self.state(tx) == Some(TransactionState::Disputed);
+            if !disputed {
+                self.remove(tx);
+            }
+            disputed
+        });
+        self.pinned = pinned;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::mem::size_of;
+    use rust_decimal::Decimal;
+    use crate::instructions::{Transaction, TransactionState};
+    use super::{Entry, TxHistory};
+
+    #[test]
+    fn packed_entries() {
+        assert_eq!(size_of::<Entry>(), 16);
+
+        let mut history = TxHistory::default();
+        let mut negative_zero = Decimal::new(0, 2);
+        negative_zero.set_sign_negative(true);
+        let amounts = [Decimal::new(15000, 4), Decimal::new(-7, 0), negative_zero, Decimal::MAX, Decimal::MIN];
+        for (tx, amount) in amounts.iter().enumerate().rev() {
+            history.insert(&Transaction::new(1, tx as u32, *amount).with_state(TransactionState::Disputed));
+        }
+        history.set_state(1, TransactionState::Chargedback);
+        assert_eq!(history.ids().count(), amounts.len());
+
+        for (tx, amount) in amounts.iter().enumerate() {
+            let transaction = history.get(1, tx as u32).expect("missing transaction");
+            assert_eq!(transaction.amount().to_string(), amount.to_string());
+            assert_eq!(transaction.amount().is_sign_negative(), amount.is_sign_negative());
+        }
+        let states: Vec<_> = history.iter(1).map(|transaction| (transaction.tx(), transaction.state())).collect();
+        assert_eq!(states[..2], [(0, TransactionState::Disputed), (1, TransactionState::Chargedback)]);
+
+        history.remove(3);
+        assert!(history.get(1, 3).is_none());
+        assert_eq!(history.spilled.len(), 1);
+        assert!(history.get(1, 5).is_none());
+    }
+
+    #[test]
+    fn timestamps() {
+        let mut history = TxHistory::default();
+        let deposit = |tx, timestamp: Option<&str>| {
+            Transaction::new(1, tx, Decimal::ONE).with_timestamp(timestamp.and_then(|text| text.parse().ok()))
+        };
+        history.insert(&deposit(2, None));
+        assert!(history.times.is_empty());
+        history.insert(&deposit(4, Some("2023-04-01T10:30:00Z")));
+        history.insert(&deposit(1, Some("2023-03-01T10:30:00Z")));
+        history.insert(&deposit(3, None));
+
+        let timestamps: Vec<_> = history.iter(1).map(|transaction| transaction.timestamp().map(|timestamp| timestamp.to_string())).collect();
+        assert_eq!(timestamps, [Some("2023-03-01T10:30:00Z".to_owned()), None, None, Some("2023-04-01T10:30:00Z".to_owned())]);
+        history.remove(1);
+        assert_eq!(history.times.len(), 3);
+        assert_eq!(history.get(1, 4).and_then(|transaction| transaction.timestamp()), "2023-04-01T10:30:00Z".parse().ok());
+    }
+}
@@ -0,0 +1,198 @@
+use std::fmt;
+use std::io::Write;
+use csv::Position;
+
+/// Rows read before the ratio of bad rows is checked along the way, so a few bad rows at the
+/// beginning of the input don't abort the processing
+pub const MIN_RATIO_ROWS: u64 = 1000;
+
+/// Limits of bad input rows tolerated in resilient mode before the processing is aborted;
+/// the count is checked on every bad row, the ratio to all rows read too once `MIN_RATIO_ROWS`
+/// are read, and once more when the input is consumed
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ErrorBudget {
+    pub max_errors: Option<u64>,
+    pub max_ratio: Option<f64>,
+}
+
+/// Input row skipped due to parsing or validation failure
+#[derive(Debug)]
+pub struct BadRow {
+    input: String,
+    line: u64,
+    byte: u64,
+    message: String,
+}
+
+impl BadRow {
+    pub fn new(input: &str, position: Option<&Position>, message: String) -> Self {
+        let (line, byte) = position.map_or((0, 0), |position| (position.line(), position.byte()));
+        Self { input: input.to_owned(), line, byte, message }
+    }
+
+    pub fn input(&self) -> &str {
+        &self.input
+    }
+
+    pub fn line(&self) -> u64 {
+        self.line
+    }
+
+    pub fn byte(&self) -> u64 {
+        self.byte
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for BadRow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{} (byte {}): {}", self.input, self.line, self.byte, self.message)
+    }
+}
+
+/// Statistics of the input consumed so far; bad rows are only counted, each written out to the sink,
+/// if any, as soon as it's recorded
+#[derive(Default)]
+pub struct Ingestion {
+    rows: u64,
+    errors: u64,
+    sink: Option<Box<dyn Write>>,
+}
+
+impl fmt::Debug for Ingestion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ingestion").field("rows", &self.rows).field("errors", &self.errors).finish()
+    }
+}
+
+impl Ingestion {
+    /// Ingestion writing bad rows out to the sink, a line each
+    pub fn with_sink(sink: Box<dyn Write>) -> Self {
+        Self { sink: Some(sink), ..Default::default() }
+    }
+
+    pub fn count_row(&mut self) {
+        self.rows += 1;
+    }
+
+    pub fn record(&mut self, badrow: &BadRow) -> std::io::Result<()> {
+        self.errors += 1;
+        match &mut self.sink {
+            Some(sink) => writeln!(sink, "{}", badrow),
+            None => Ok(()),
+        }
+    }
+
+    /// Continues with the counts of a run recovered from the write-ahead log
+    pub(crate) fn resume(&mut self, rows: u64, errors: u64) {
+        self.rows = rows;
+        self.errors = errors;
+    }
+
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.errors
+    }
+
+    pub fn exceeds_count(&self, budget: &ErrorBudget) -> bool {
+        budget.max_errors.is_some_and(|max_errors| self.errors() > max_errors)
+    }
+
+    /// Whether the bad rows counted so far exceed the budget, by the count or, after the minimum
+    /// of rows read, by the ratio
+    pub fn exceeds(&self, budget: &ErrorBudget) -> bool {
+        self.exceeds_count(budget) || self.rows >= MIN_RATIO_ROWS && self.exceeds_ratio(budget)
+    }
+
+    pub fn exceeds_ratio(&self, budget: &ErrorBudget) -> bool {
+        match budget.max_ratio {
+            Some(max_ratio) if self.rows > 0 => self.errors() as f64 / self.rows as f64 > max_ratio,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+    use super::{BadRow, ErrorBudget, Ingestion, MIN_RATIO_ROWS};
+
+    fn ingestion(rows: u64, errors: u64) -> Ingestion {
+        let mut ingestion = Ingestion::default();
+        (0..rows).for_each(|_| ingestion.count_row());
+        for _ in 0..errors {
+            ingestion.record(&BadRow::new("test.csv", None, "bad row".to_owned())).expect("failed to record");
+        }
+        ingestion
+    }
+
+    /// Shares the written buffer, as the ingestion owns its sink
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).expect("failed to stringify the buffer")
+        }
+    }
+
+    #[test]
+    fn streamed_badrows() {
+        let buffer = SharedBuffer::default();
+        let mut ingestion = Ingestion::with_sink(Box::new(buffer.clone()));
+        ingestion.record(&BadRow::new("test.csv", None, "bad row".to_owned())).expect("failed to record");
+        assert_eq!(buffer.contents(), "test.csv:0 (byte 0): bad row\n");
+        ingestion.record(&BadRow::new("test.csv", None, "worse row".to_owned())).expect("failed to record");
+        assert_eq!(buffer.0.borrow().iter().filter(|&&c| c == b'\n').count(), 2);
+        assert_eq!(ingestion.errors(), 2);
+    }
+
+    #[test]
+    fn unlimited_budget() {
+        let budget = ErrorBudget::default();
+        assert!(!ingestion(10, 10).exceeds_count(&budget));
+        assert!(!ingestion(10, 10).exceeds_ratio(&budget));
+    }
+
+    #[test]
+    fn max_errors() {
+        let budget = ErrorBudget { max_errors: Some(2), ..Default::default() };
+        assert!(!ingestion(10, 2).exceeds_count(&budget));
+        assert!(ingestion(10, 3).exceeds_count(&budget));
+    }
+
+    #[test]
+    fn max_ratio() {
+        let budget = ErrorBudget { max_ratio: Some(0.25), ..Default::default() };
+        assert!(!ingestion(0, 0).exceeds_ratio(&budget));
+        assert!(!ingestion(8, 2).exceeds_ratio(&budget));
+        assert!(ingestion(8, 3).exceeds_ratio(&budget));
+    }
+
+    #[test]
+    fn max_ratio_along_the_way() {
+        let budget = ErrorBudget { max_ratio: Some(0.25), ..Default::default() };
+        assert!(!ingestion(MIN_RATIO_ROWS - 1, MIN_RATIO_ROWS / 2).exceeds(&budget));
+        assert!(!ingestion(MIN_RATIO_ROWS, MIN_RATIO_ROWS / 4).exceeds(&budget));
+        assert!(ingestion(MIN_RATIO_ROWS, MIN_RATIO_ROWS / 4 + 1).exceeds(&budget));
+        assert!(ingestion(10, 3).exceeds(&ErrorBudget { max_errors: Some(2), ..Default::default() }));
+    }
+}
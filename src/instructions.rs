@@ -1,22 +1,29 @@
 use std::cell::Cell;
 use rust_decimal::Decimal;
-use serde::Serialize;
-use parse_display::Display;
+use serde::{Deserialize, Serialize};
+use parse_display::{Display, FromStr};
 use crate::{result::Result, errors::TransactionSystemError};
+use crate::codec::Fields;
+use crate::timestamp::Timestamp;
 
-#[derive(Debug, Display, Clone, Copy)]
+#[derive(Debug, Display, FromStr, Clone, Copy, Default, PartialEq, Eq)]
 #[display(style = "snake_case")]
 pub enum TransactionState {
+    #[default]
     Undisputed,
     Disputed,
     Resolved,
     Chargedback,
 }
 
-impl Default for TransactionState {
-    fn default() -> Self {
-        TransactionState::Undisputed
-    }
+#[derive(Debug, Display, FromStr, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[display(style = "snake_case")]
+#[serde(rename_all = "lowercase")]
+pub enum LockReason {
+    Chargeback,
+    Fraud,
+    #[default]
+    Manual,
 }
 
 #[derive(Debug)]
@@ -25,12 +32,27 @@ pub struct Transaction {
     tx: u32,
     amount: Decimal,
     state: Cell<TransactionState>,
+    timestamp: Option<Timestamp>,
 }
 
 impl Transaction {
-    #[cfg(test)]
     pub fn new(client: u16, tx: u32, amount: Decimal) -> Self {
-        Self { client, tx, amount, state: Cell::new(TransactionState::Undisputed) }
+        Self { client, tx, amount, state: Cell::new(TransactionState::Undisputed), timestamp: None }
+    }
+
+    /// Transaction restored with its state, for example from persisted account history
+    pub fn with_state(self, state: TransactionState) -> Self {
+        self.state.set(state);
+        self
+    }
+
+    pub fn with_timestamp(self, timestamp: Option<Timestamp>) -> Self {
+        Self { timestamp, ..self }
+    }
+
+    /// When the transaction happened, if the input tells
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        self.timestamp
     }
 
     pub fn amount(&self) -> Decimal {
@@ -61,7 +83,9 @@ impl Transaction {
             },
             _ => {
                 Err(TransactionSystemError::TransactionStateError{
-                    oldstate: self.state.get(),
+                    client: self.client,
+                    tx: self.tx,
+                    oldstate: self.state(),
                     newstate: TransactionState::Disputed}
                 )
             }
@@ -76,7 +100,9 @@ impl Transaction {
             },
             _ => {
                 Err(TransactionSystemError::TransactionStateError{
-                    oldstate: self.state.get(),
+                    client: self.client,
+                    tx: self.tx,
+                    oldstate: self.state(),
                     newstate: TransactionState::Resolved}
                 )
             }
@@ -91,7 +117,9 @@ impl Transaction {
             },
             _ => {
                 Err(TransactionSystemError::TransactionStateError{
-                    oldstate: self.state.get(),
+                    client: self.client,
+                    tx: self.tx,
+                    oldstate: self.state(),
                     newstate: TransactionState::Chargedback}
                 )
             }
@@ -103,12 +131,21 @@ impl Transaction {
 pub struct Operation {
     client: u16,
     tx: u32,
+    timestamp: Option<Timestamp>,
 }
 
 impl Operation {
-    #[cfg(test)]
     pub fn new(client: u16, tx: u32) -> Self {
-        Self { client, tx }
+        Self { client, tx, timestamp: None }
+    }
+
+    pub fn with_timestamp(self, timestamp: Option<Timestamp>) -> Self {
+        Self { timestamp, ..self }
+    }
+
+    /// When the operation happened, if the input tells
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        self.timestamp
     }
 
     pub fn client(&self) -> u16 {
@@ -120,6 +157,36 @@ impl Operation {
     }
 }
 
+#[derive(Debug, Serialize)]
+pub struct Administration {
+    client: u16,
+    tx: u32,
+    operator: u32,
+    reason: LockReason,
+}
+
+impl Administration {
+    pub fn new(client: u16, tx: u32, operator: u32, reason: LockReason) -> Self {
+        Self { client, tx, operator, reason }
+    }
+
+    pub fn client(&self) -> u16 {
+        self.client
+    }
+
+    pub fn tx(&self) -> u32 {
+        self.tx
+    }
+
+    pub fn operator(&self) -> u32 {
+        self.operator
+    }
+
+    pub fn reason(&self) -> LockReason {
+        self.reason
+    }
+}
+
 #[derive(Debug)]
 pub enum Instruction {
     /// A deposit is a credit to the client's asset account, meaning it should increase the available and
@@ -144,66 +211,159 @@ pub enum Instruction {
     /// total funds should decrease by the amount previously disputed. If a chargeback occurs the
     /// client's account should be immediately frozen.
     Chargeback(Operation),
+    /// A freeze is an administrative lock of the client's account put by an operator, recording the
+    /// reason of the lock. Funds aren't affected.
+    Freeze(Administration),
+    /// An unlock is an administrative release of the client's account lock by an operator, whatever
+    /// the reason of the lock was. Funds aren't affected.
+    Unlock(Administration),
 }
 
 impl Instruction {
+    /// Gives the transaction carried by deposits and withdrawals, these are the ones owning `tx` ids
+    pub fn transaction(&self) -> Option<&Transaction> {
+        match self {
+            Instruction::Deposit(transaction) | Instruction::Withdrawal(transaction) => Some(transaction),
+            _ => None,
+        }
+    }
+
+    /// When the instruction happened, known for transactions and operations given the input tells
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        match self {
+            Instruction::Deposit(transaction) | Instruction::Withdrawal(transaction) => transaction.timestamp(),
+            Instruction::Dispute(operation) | Instruction::Resolve(operation) | Instruction::Chargeback(operation)
+                => operation.timestamp(),
+            Instruction::Freeze(_) | Instruction::Unlock(_) => None,
+        }
+    }
+
     pub fn client(&self) -> u16 {
         match self {
             Instruction::Deposit(transaction) | Instruction::Withdrawal(transaction)
                 => transaction.client(),
             Instruction::Dispute(operation) | Instruction::Resolve(operation) | Instruction::Chargeback(operation)
                 => operation.client(),
+            Instruction::Freeze(administration) | Instruction::Unlock(administration)
+                => administration.client(),
+        }
+    }
+
+    /// Writes the instruction as space separated fields, to be read back with `decode()`;
+    /// the timestamp, if any, comes last
+    pub(crate) fn encode(&self) -> String {
+        let encoded = match self {
+            Instruction::Deposit(transaction) =>
+                format!("deposit {} {} {}", transaction.client, transaction.tx, transaction.amount),
+            Instruction::Withdrawal(transaction) =>
+                format!("withdrawal {} {} {}", transaction.client, transaction.tx, transaction.amount),
+            Instruction::Dispute(operation) => format!("dispute {} {}", operation.client, operation.tx),
+            Instruction::Resolve(operation) => format!("resolve {} {}", operation.client, operation.tx),
+            Instruction::Chargeback(operation) => format!("chargeback {} {}", operation.client, operation.tx),
+            Instruction::Freeze(administration) => format!("freeze {} {} {} {}",
+                administration.client, administration.tx, administration.operator, administration.reason),
+            Instruction::Unlock(administration) => format!("unlock {} {} {} {}",
+                administration.client, administration.tx, administration.operator, administration.reason),
+        };
+        match self.timestamp() {
+            Some(timestamp) => format!("{} {}", encoded, timestamp),
+            None => encoded,
         }
     }
+
+    /// Reads the instruction written with `encode()`
+    pub(crate) fn decode(fields: &mut Fields) -> std::result::Result<Self, TransactionSystemError> {
+        let transaction = |fields: &mut Fields| -> std::result::Result<Transaction, TransactionSystemError> {
+            Ok(Transaction::new(fields.parse()?, fields.parse()?, fields.parse()?).with_timestamp(fields.trailing()?))
+        };
+        let operation = |fields: &mut Fields| -> std::result::Result<Operation, TransactionSystemError> {
+            Ok(Operation::new(fields.parse()?, fields.parse()?).with_timestamp(fields.trailing()?))
+        };
+        Ok(match fields.next_str()? {
+            "deposit" => Instruction::Deposit(transaction(fields)?),
+            "withdrawal" => Instruction::Withdrawal(transaction(fields)?),
+            "dispute" => Instruction::Dispute(operation(fields)?),
+            "resolve" => Instruction::Resolve(operation(fields)?),
+            "chargeback" => Instruction::Chargeback(operation(fields)?),
+            "freeze" => Instruction::Freeze(Administration::new(fields.parse()?, fields.parse()?, fields.parse()?, fields.parse()?)),
+            "unlock" => Instruction::Unlock(Administration::new(fields.parse()?, fields.parse()?, fields.parse()?, fields.parse()?)),
+            typ => return Err(fields.corrupted(&format!("unknown instruction {}", typ))),
+        })
+    }
 }
 
 /// Workaround for https://github.com/BurntSushi/rust-csv/issues/211
-impl From<workaround::Instruction> for Instruction {
-    fn from(instruction: workaround::Instruction) -> Self {
+impl TryFrom<workaround::Instruction> for Instruction {
+    type Error = TransactionSystemError;
+
+    fn try_from(instruction: workaround::Instruction) -> std::result::Result<Self, Self::Error> {
         use workaround::InstructionType as WIT;
 
-        match instruction.typ {
+        Ok(match instruction.typ {
             WIT::Deposit => Instruction::Deposit(Transaction{
                 client: instruction.client,
                 tx: instruction.tx,
-                amount: instruction.amount.unwrap(),
-                state: Cell::new(TransactionState::Undisputed)
+                amount: instruction.valid_amount()?,
+                state: Cell::new(TransactionState::Undisputed),
+                timestamp: instruction.timestamp,
             }),
             WIT::Withdrawal => Instruction::Withdrawal(Transaction{
                 client: instruction.client,
                 tx: instruction.tx,
-                amount: instruction.amount.unwrap(),
-                state: Cell::new(TransactionState::Undisputed)
+                amount: instruction.valid_amount()?,
+                state: Cell::new(TransactionState::Undisputed),
+                timestamp: instruction.timestamp,
             }),
             WIT::Dispute => Instruction::Dispute(Operation{
                 client: instruction.client,
                 tx: instruction.tx,
+                timestamp: instruction.timestamp,
             }),
             WIT::Resolve => Instruction::Resolve(Operation{
                 client: instruction.client,
                 tx: instruction.tx,
+                timestamp: instruction.timestamp,
             }),
             WIT::Chargeback => Instruction::Chargeback(Operation{
                 client: instruction.client,
                 tx: instruction.tx,
+                timestamp: instruction.timestamp,
             }),
-        }
-        
+            WIT::Freeze => Instruction::Freeze(Administration{
+                client: instruction.client,
+                tx: instruction.tx,
+                operator: instruction.valid_operator()?,
+                reason: instruction.reason.unwrap_or_default(),
+            }),
+            WIT::Unlock => Instruction::Unlock(Administration{
+                client: instruction.client,
+                tx: instruction.tx,
+                operator: instruction.valid_operator()?,
+                reason: instruction.reason.unwrap_or_default(),
+            }),
+        })
     }
 }
 
 pub mod workaround {
     use rust_decimal::Decimal;
     use serde::Deserialize;
+    use parse_display::Display;
+    use crate::errors::TransactionSystemError;
+    use crate::timestamp::Timestamp;
+    use super::LockReason;
 
-    #[derive(Deserialize, Debug)]
+    #[derive(Deserialize, Debug, Display, Clone, Copy)]
     #[serde(rename_all = "lowercase")]
+    #[display(style = "lowercase")]
     pub enum InstructionType {
         Deposit,
         Withdrawal,
         Dispute,
         Resolve,
         Chargeback,
+        Freeze,
+        Unlock,
     }
 
     #[derive(Deserialize, Debug)]
@@ -213,13 +373,83 @@ pub mod workaround {
         pub (super) client: u16,
         pub (super) tx: u32,
         pub (super) amount: Option<Decimal>,
+        pub (super) operator: Option<u32>,
+        pub (super) reason: Option<LockReason>,
+        pub (super) timestamp: Option<Timestamp>,
+    }
+
+    impl InstructionType {
+        /// Recognizes the type by its name, the same as deserialized
+        pub fn from_bytes(name: &[u8]) -> Option<Self> {
+            Some(match name {
+                b"deposit" => InstructionType::Deposit,
+                b"withdrawal" => InstructionType::Withdrawal,
+                b"dispute" => InstructionType::Dispute,
+                b"resolve" => InstructionType::Resolve,
+                b"chargeback" => InstructionType::Chargeback,
+                b"freeze" => InstructionType::Freeze,
+                b"unlock" => InstructionType::Unlock,
+                _ => return None,
+            })
+        }
+    }
+
+    impl Instruction {
+        pub fn new(typ: InstructionType, client: u16, tx: u32, amount: Option<Decimal>,
+                   operator: Option<u32>, reason: Option<LockReason>, timestamp: Option<Timestamp>) -> Self {
+            Self { typ, client, tx, amount, operator, reason, timestamp }
+        }
+
+        pub fn typ(&self) -> InstructionType {
+            self.typ
+        }
+
+        pub fn client(&self) -> u16 {
+            self.client
+        }
+
+        pub fn tx(&self) -> u32 {
+            self.tx
+        }
+
+        pub fn amount(&self) -> Option<Decimal> {
+            self.amount
+        }
+
+        pub fn timestamp(&self) -> Option<Timestamp> {
+            self.timestamp
+        }
+
+        /// Amounts are positive with precision up to four places past the decimal
+        pub (super) fn valid_amount(&self) -> Result<Decimal, TransactionSystemError> {
+            let message = match self.amount {
+                None => "missing amount",
+                Some(amount) if amount <= Decimal::new(0, 0) => "amount not positive",
+                Some(amount) if amount.normalize().scale() > 4 => "amount with more than four decimal places",
+                Some(amount) => return Ok(amount),
+            };
+            Err(self.invalid(message))
+        }
+
+        pub (super) fn valid_operator(&self) -> Result<u32, TransactionSystemError> {
+            self.operator.ok_or_else(|| self.invalid("missing operator"))
+        }
+
+        fn invalid(&self, message: &str) -> TransactionSystemError {
+            TransactionSystemError::ValidationError {
+                message: message.to_owned(),
+                record: format!("{:?}", self),
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
     use rust_decimal::Decimal;
-    use super::{workaround, Instruction};
+    use crate::codec::Fields;
+    use crate::errors::TransactionSystemError;
+    use super::{workaround, Administration, Instruction, LockReason, Operation, Transaction};
 
     fn give_me_instrution() -> Instruction {
         workaround::Instruction {
@@ -227,7 +457,14 @@ mod test {
             client: 444,
             tx: 555,
             amount: Some(Decimal::new(6666, 1)),
-        }.into()
+            operator: None,
+            reason: None,
+            timestamp: None,
+        }.try_into().expect("failed to convert instruction")
+    }
+
+    fn convert(typ: workaround::InstructionType, amount: Option<Decimal>) -> Result<Instruction, TransactionSystemError> {
+        workaround::Instruction { typ, client: 1, tx: 2, amount, operator: None, reason: None, timestamp: None }.try_into()
     }
 
     #[test]
@@ -235,8 +472,8 @@ mod test {
         let instruction = give_me_instrution();
 
         if let Instruction::Deposit(transaction) = instruction {
-            assert!(matches!(transaction.try_set_disputed(), Ok(_)));
-            assert!(matches!(transaction.try_set_disputed(), Err(_)));
+            assert!(transaction.try_set_disputed().is_ok());
+            assert!(transaction.try_set_disputed().is_err());
         } else {
             panic!("unexpected wrong instruction");
         }
@@ -247,10 +484,10 @@ mod test {
         let instruction = give_me_instrution();
 
         if let Instruction::Deposit(transaction) = instruction {
-            assert!(matches!(transaction.try_set_resolved(), Err(_)));
-            assert!(matches!(transaction.try_set_disputed(), Ok(_)));
-            assert!(matches!(transaction.try_set_resolved(), Ok(_)));
-            assert!(matches!(transaction.try_set_resolved(), Err(_)));
+            assert!(transaction.try_set_resolved().is_err());
+            assert!(transaction.try_set_disputed().is_ok());
+            assert!(transaction.try_set_resolved().is_ok());
+            assert!(transaction.try_set_resolved().is_err());
         } else {
             panic!("unexpected wrong instruction");
         }
@@ -261,12 +498,53 @@ mod test {
         let instruction = give_me_instrution();
 
         if let Instruction::Deposit(transaction) = instruction {
-            assert!(matches!(transaction.try_set_chargedback(), Err(_)));
-            assert!(matches!(transaction.try_set_disputed(), Ok(_)));
-            assert!(matches!(transaction.try_set_chargedback(), Ok(_)));
-            assert!(matches!(transaction.try_set_chargedback(), Err(_)));
+            assert!(transaction.try_set_chargedback().is_err());
+            assert!(transaction.try_set_disputed().is_ok());
+            assert!(transaction.try_set_chargedback().is_ok());
+            assert!(transaction.try_set_chargedback().is_err());
         } else {
             panic!("unexpected wrong instruction");
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn amount_validation() {
+        use workaround::InstructionType as WIT;
+
+        assert!(convert(WIT::Deposit, Some(Decimal::new(12345, 4))).is_ok());
+        assert!(convert(WIT::Withdrawal, Some(Decimal::new(123450, 5))).is_ok());
+        assert!(convert(WIT::Dispute, None).is_ok());
+
+        for amount in [None, Some(Decimal::new(0, 0)), Some(Decimal::new(-1, 0)), Some(Decimal::new(123456, 5))] {
+            assert!(matches!(convert(WIT::Deposit, amount), Err(TransactionSystemError::ValidationError{..})));
+            assert!(matches!(convert(WIT::Withdrawal, amount), Err(TransactionSystemError::ValidationError{..})));
+        }
+    }
+
+    #[test]
+    fn operator_validation() {
+        use workaround::InstructionType as WIT;
+
+        assert!(matches!(convert(WIT::Freeze, None), Err(TransactionSystemError::ValidationError{..})));
+        assert!(matches!(convert(WIT::Unlock, None), Err(TransactionSystemError::ValidationError{..})));
+    }
+
+    #[test]
+    fn encoding() {
+        for instruction in [
+            give_me_instrution(),
+            Instruction::Chargeback(Operation::new(7, 8)),
+            Instruction::Dispute(Operation::new(7, 8).with_timestamp("2023-04-01T10:30:00Z".parse().ok())),
+            Instruction::Freeze(Administration::new(1, 9, 42, LockReason::Fraud)),
+        ] {
+            let encoded = instruction.encode();
+            let decoded = Instruction::decode(&mut Fields::new(&encoded)).expect("failed to decode");
+            assert_eq!(decoded.encode(), encoded);
+        }
+        assert_eq!(give_me_instrution().encode(), "deposit 444 555 666.6");
+        let instruction = Instruction::Deposit(Transaction::new(1, 2, Decimal::new(3, 0)).with_timestamp("1680345000".parse().ok()));
+        assert_eq!(instruction.encode(), "deposit 1 2 3 2023-04-01T10:30:00Z");
+        assert!(Instruction::decode(&mut Fields::new("deposit 1 2")).is_err());
+        assert!(Instruction::decode(&mut Fields::new("transfer 1 2 3")).is_err());
+    }
+}
@@ -0,0 +1,28 @@
+//! Engine of the transaction system: the `Register` executes instructions against the clients'
+//! accounts, the binary is a command line wrapper around it.
+
+pub mod account;
+pub mod balances;
+mod codec;
+pub mod compression;
+pub mod errors;
+mod expiry;
+mod history;
+pub mod ingestion;
+pub mod instructions;
+pub mod output;
+mod owners;
+mod parallel;
+pub mod register;
+pub mod rejects;
+pub mod result;
+pub mod snapshot;
+pub mod source;
+pub mod store;
+pub mod timestamp;
+pub mod wal;
+
+pub use account::{Account, Policy};
+pub use errors::{ErrorKind, TransactionSystemError};
+pub use instructions::{Administration, Instruction, Operation, Transaction};
+pub use register::{Register, RegisterBuilder};
@@ -1,234 +1,102 @@
-use std::io::Write;
-use std::{collections::HashMap, io};
+use std::io::{BufReader, BufWriter};
+use std::{fs::{self, File}, io};
 use std::env;
 use std::path::Path;
-use account::Account;
-use csv::{ReaderBuilder, Trim};
-#[cfg(test)]
-use itertools::Itertools;
-use log::{info, debug, error};
-
-mod instructions;
-mod account;
-mod output;
-mod errors;
-mod result;
-
-use crate::result::Result;
-use crate::instructions::Instruction;
-
-#[derive(Debug, Default)]
-struct Register {
-    thebook: HashMap<u16, account::Account>,
-}
-
-impl Register {
-    pub fn execute(&mut self, instruction: Instruction) {
-        debug!("Processing account for client {}", instruction.client());
-        let account = self.thebook.entry(instruction.client()).or_insert_with(|| {
-            Account::default()
-        });
-
-        account.apply(instruction).unwrap_or_else(|error| {
-            error!("Account instruction error: {}", error);
-        })
+use std::process::ExitCode;
+use log::info;
+
+use transation_system::errors::TransactionSystemError;
+use transation_system::balances;
+use transation_system::rejects::{RejectsFormat, RejectsWriter};
+use transation_system::source;
+use transation_system::store::DiskStore;
+use transation_system::wal::WriteAheadLog;
+use transation_system::Register;
+
+mod cli;
+
+/// Exit code of the run which skipped some bad input rows
+const EXIT_BAD_ROWS: u8 = 2;
+
+/// Number of accounts kept in memory by the disk store unless told otherwise
+const DEFAULT_STORE_CACHE: usize = 10_000;
+
+/// Index of transaction owners in the directory of the disk store
+const TX_INDEX_FILE: &str = "owners.index";
+
+/// Number of instructions between write-ahead log checkpoints unless told otherwise
+const DEFAULT_CHECKPOINT_INTERVAL: u64 = 100_000;
+
+fn main() -> std::result::Result<ExitCode, TransactionSystemError> {
+    let arguments = cli::Arguments::parse(env::args().skip(1))?;
+
+    let rejects = match arguments.rejects {
+        Some(rejectsfile) => {
+            let path = Path::new(&rejectsfile);
+            let format = arguments.rejects_format.unwrap_or_else(|| RejectsFormat::from_path(path));
+            Some(RejectsWriter::create(path, format, arguments.wal.is_some())?)
+        },
+        None => None,
+    };
+
+    let mut builder = Register::builder()
+        .bad_rows(Box::new(io::stderr()))
+        .policy(arguments.policy)
+        .output_format(arguments.output_format)
+        .decimal_format(arguments.decimals);
+    if let Some(budget) = arguments.budget {
+        builder = builder.error_budget(budget);
     }
-
-    pub fn process(&mut self, inputfilename: &Path) -> Result {
-        let mut reader = ReaderBuilder::new()
-            .flexible(true)
-            .trim(Trim::All)
-            .from_path(inputfilename)?;
-    
-        debug!("Consuming input data...");
-        for result in reader.deserialize() {
-            let record: instructions::workaround::Instruction = result?;
-            let record: Instruction = record.into();
-
-            self.execute(record);
-        }
-        debug!("...consuption of input data finished.");
-    
-        Ok(())
+    if let Some(rejects) = rejects {
+        builder = builder.rejects(rejects);
     }
-
-    fn inner_dump(thebook_iter: impl IntoIterator<Item = (u16, Account)>, sink: &mut impl Write) -> Result {
-        let mut writer = csv::Writer::from_writer(sink);
-
-        debug!("Dumping the book state...");
-        for (client, account) in thebook_iter {
-            let record = output::Output::convert_from(client, account);
-            writer.serialize(record)?
-        }
-        debug!("...dumping the book finished.");
-        
-        writer.flush()?;
-        debug!("Output writer flushed.");
-
-        Ok(())
+    if let Some(directory) = arguments.store {
+        let store = DiskStore::open(&directory, arguments.store_cache.unwrap_or(DEFAULT_STORE_CACHE))?;
+        // Owners of transactions are kept on disk along the accounts, so the store is attached without reading them
+        builder = builder.tx_index(Path::new(&directory).join(TX_INDEX_FILE))?.store(store)?;
     }
-
-    pub fn dump(self, sink: &mut impl Write) -> Result {
-        let thebook_iter = self.thebook.into_iter();
-        Self::inner_dump(thebook_iter, sink)
+    if let Some(directory) = arguments.wal {
+        let interval = arguments.checkpoint_interval.unwrap_or(DEFAULT_CHECKPOINT_INTERVAL);
+        builder = builder.write_ahead_log(WriteAheadLog::open(directory, interval)?);
     }
-
-    #[cfg(test)] // Outside test leave unsorted for performance reasons
-    pub fn dump_sorted(self, sink: &mut impl Write) -> Result {
-        let thebook_iter = self.thebook.into_iter().sorted_by_key(|x| x.0);
-        Self::inner_dump(thebook_iter, sink)
+    let mut register = builder.build();
+    if let Some(snapshotfile) = &arguments.restore {
+        info!("Restoring state from {} snapshot.", snapshotfile);
+        register.restore(&mut BufReader::new(File::open(snapshotfile)?))?;
     }
-}
-
-fn main() -> Result {
-    use errors::TransactionSystemError::ArgumentsError;
-
-    let inputfile = env::args().nth(1).ok_or_else(|| ArgumentsError("no input file provided".to_owned()))?;
-
-    let mut register = Register::default();
-    info!("Processing for {} file started.", inputfile);
-    register.process(Path::new(&inputfile))?;
-    register.dump(&mut io::stdout())?;
-    info!("Processing for {} file finished.", inputfile);
-
-    Ok(())
-}
-
-#[cfg(test)]
-mod test {
-    use std::io::{Write, self};
-    use indoc::*;
-    use tempfile::NamedTempFile;
-
-    fn test_instructions_batch(feed: &str, expectation: &str) {
-        let mut file = NamedTempFile::new().expect("failed to create temporary file");
-        write!(file, "{}", feed).expect("failed to write test data");
-
-        let mut register = super::Register::default();
-        register.process(file.path()).expect("failed to batch process");
-
-        let mut sink = io::Cursor::new(Vec::<u8>::new());
-        register.dump_sorted(&mut sink).expect("failed to dump");
-
-        let output: String = std::str::from_utf8(&sink.into_inner()).expect("faile to strigify the buffer").to_string();
-        assert_eq!(output, expectation);
+    if let Some(balancesfile) = &arguments.opening_balances {
+        info!("Opening balances from {} file.", balancesfile);
+        register.open_balances(&mut balances::open(balancesfile)?)?;
     }
-
-    #[test]
-    fn basic_transactions_batch() {
-        const TEST_FEED: &str = indoc!("
-            type,   client, tx, amount
-            deposit,     1,  1,   11.1
-            deposit,     2,  2,   22.2
-            deposit,     1,  3,   33.3
-            deposit,     2,  4,   44.4
-            deposit,     3,  5,   55.5
-            withdrawal,  2,  6,   11.1
-            withdrawal,  3,  7,   22.2
-            withdrawal,  1,  8,   33.3
-        ");
-
-        const TEST_EXPECTATION: &str = indoc!("
-            client,available,held,total,locked
-            1,11.1,0,11.1,false
-            2,55.5,0,55.5,false
-            3,33.3,0,33.3,false
-        ");
-
-        test_instructions_batch(TEST_FEED, TEST_EXPECTATION)
+    // The state left by an interrupted run supersedes the one set up above
+    register.recover()?;
+
+    for inputfile in &arguments.inputfiles {
+        info!("Processing for {} input started.", inputfile);
+        let mut source = source::open(inputfile, arguments.input_format)?;
+        match arguments.threads {
+            Some(threads) => register.process_parallel(source.as_mut(), threads)?,
+            None => register.process_source(source.as_mut())?,
+        }
+        info!("Processing for {} input finished.", inputfile);
     }
 
-    #[test]
-    fn dispute_operations_batch() {
-        const TEST_FEED: &str = indoc!("
-            type, client, tx, amount
-            deposit,   1,  1, 11.1
-            deposit,   2,  2, 22.2
-            deposit,   1,  3, 33.3
-            deposit,   2,  4, 44.4
-            deposit,   3,  5, 55.5
-            withdrawal,2,  6, 11.1
-            withdrawal,3,  7, 22.2
-            withdrawal,1,  8, 33.3
-            dispute,   2,  2,
-            dispute,   3,  5,
-        ");
-
-        const TEST_EXPECTATION: &str = indoc!("
-            client,available,held,total,locked
-            1,11.1,0,11.1,false
-            2,33.3,22.2,55.5,false
-            3,-22.2,55.5,33.3,false
-        ");
-
-        test_instructions_batch(TEST_FEED, TEST_EXPECTATION)
+    if let Some(snapshotfile) = &arguments.snapshot {
+        // Written aside and renamed, not to leave a half-written snapshot behind
+        let temporary = format!("{}.tmp", snapshotfile);
+        register.snapshot(&mut BufWriter::new(File::create(&temporary)?))?;
+        fs::rename(temporary, snapshotfile)?;
+        info!("State saved in {} snapshot.", snapshotfile);
     }
 
-    #[test]
-    fn resolve_operations_batch() {
-        const TEST_FEED: &str = indoc!("
-            type, client,  tx,  amount
-            deposit,   1,  1, 100.1234
-            deposit,   2,  2, 200.2345
-            deposit,   1,  3, 300.3456
-            deposit,   2,  4, 400.4567
-            deposit,   3,  5, 500.7891
-            withdrawal,2,  6, 123.4567
-            withdrawal,3,  7, 234.5678
-            withdrawal,1,  8,  99.9999
-            dispute,   2,  2,
-            dispute,   1,  3,
-            resolve,   1,  3,
-            deposit,   4,  9, 600.8912
-            deposit,   5, 10, 350.9123
-            deposit,   5, 11, 350.1234
-            dispute,   5, 10,
-            resolve,   5, 10,
-        ");
+    let errors = register.ingestion().errors();
 
-        const TEST_EXPECTATION: &str = indoc!("
-            client,available,held,total,locked
-            1,300.4691,0.0000,300.4691,false
-            2,277.0000,200.2345,477.2345,false
-            3,266.2213,0,266.2213,false
-            4,600.8912,0,600.8912,false
-            5,701.0357,0.0000,701.0357,false
-        ");
-
-        test_instructions_batch(TEST_FEED, TEST_EXPECTATION)
+    if arguments.sorted {
+        register.dump_sorted(&mut io::stdout())?;
+    } else {
+        register.dump(&mut io::stdout())?;
     }
+    info!("Processing finished with {} bad rows.", errors);
 
-    #[test]
-    fn chargeback_operations_batch() {
-        const TEST_FEED: &str = indoc!("
-            type, client,  tx,  amount
-            deposit,   1,  1, 100.1234
-            deposit,   2,  2, 200.2345
-            deposit,   1,  3, 300.3456
-            deposit,   2,  4, 400.4567
-            deposit,   3,  5, 500.7891
-            withdrawal,2,  6, 123.4567
-            withdrawal,3,  7, 234.5678
-            withdrawal,1,  8,  99.9999
-            dispute,   2,  2,
-            dispute,   1,  3,
-            chargeback,1,  3,
-            deposit,   4,  9, 600.8912
-            deposit,   5, 10, 350.9123
-            deposit,   5, 11, 350.1234
-            dispute,   5, 10,
-            chargeback,5, 10,
-        ");
-
-        const TEST_EXPECTATION: &str = indoc!("
-            client,available,held,total,locked
-            1,0.1235,0.0000,0.1235,true
-            2,277.0000,200.2345,477.2345,false
-            3,266.2213,0,266.2213,false
-            4,600.8912,0,600.8912,false
-            5,350.1234,0.0000,350.1234,true
-        ");
-
-        test_instructions_batch(TEST_FEED, TEST_EXPECTATION)
-    }
+    Ok(if errors == 0 { ExitCode::SUCCESS } else { ExitCode::from(EXIT_BAD_ROWS) })
 }
@@ -1,7 +1,52 @@
+use std::io::Write;
+use parse_display::{Display, FromStr};
 use rust_decimal::Decimal;
 use serde::Serialize;
 use crate::account::Account;
+use crate::result::Result;
 
+#[derive(Debug, Display, FromStr, Default, Clone, Copy, PartialEq, Eq)]
+#[display(style = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    Json,
+    Jsonl,
+}
+
+/// Formatting of the amounts; by default they keep the scale they got from the input and operations
+#[derive(Debug, Display, FromStr, Default, Clone, Copy, PartialEq, Eq)]
+#[display(style = "kebab-case")]
+pub enum DecimalFormat {
+    #[default]
+    AsIs,
+    /// Exactly four decimal places
+    Fixed,
+    /// No trailing zeros
+    Normalized,
+}
+
+impl DecimalFormat {
+    fn apply(&self, amount: Decimal) -> Decimal {
+        let mut amount = match self {
+            DecimalFormat::AsIs => return amount,
+            DecimalFormat::Fixed => {
+                let mut amount = amount;
+                amount.rescale(4);
+                amount
+            },
+            DecimalFormat::Normalized => amount.normalize(),
+        };
+        // Zero is written without a sign, whatever operations led to it
+        if amount.is_zero() {
+            amount.set_sign_positive(true);
+        }
+        amount
+    }
+}
+
+/// State of an account as written out, to CSV and JSON alike; amounts are serialized by `rust_decimal`
+/// as strings, keeping the scale given by `formatted()`
 #[derive(Debug, Serialize)]
 pub struct Output {
     client: u16,
@@ -12,7 +57,7 @@ pub struct Output {
 }
 
 impl Output {
-    pub fn convert_from(client: u16, account: Account) -> Self {
+    pub fn convert_from(client: u16, account: &Account) -> Self {
         Self {
             client,
             available: account.available(),
@@ -21,4 +66,149 @@ impl Output {
             locked: account.locked(),
         }
     }
-}
\ No newline at end of file
+
+    pub fn formatted(self, format: DecimalFormat) -> Self {
+        Self {
+            available: format.apply(self.available),
+            held: format.apply(self.held),
+            total: format.apply(self.total),
+            ..self
+        }
+    }
+
+    pub fn client(&self) -> u16 {
+        self.client
+    }
+}
+
+enum Sink<W: Write> {
+    Csv(Box<csv::Writer<W>>),
+    Json { sink: W, first: bool },
+    Jsonl(W),
+}
+
+/// Writer of the accounts' state in one of the output formats
+pub struct OutputWriter<W: Write> {
+    sink: Sink<W>,
+}
+
+impl<W: Write> OutputWriter<W> {
+    pub fn new(format: OutputFormat, sink: W) -> Self {
+        let sink = match format {
+            OutputFormat::Csv => Sink::Csv(Box::new(csv::Writer::from_writer(sink))),
+            OutputFormat::Json => Sink::Json { sink, first: true },
+            OutputFormat::Jsonl => Sink::Jsonl(sink),
+        };
+        Self { sink }
+    }
+
+    pub fn write(&mut self, output: &Output) -> Result {
+        match &mut self.sink {
+            Sink::Csv(writer) => writer.serialize(output)?,
+            Sink::Json { sink, first } => {
+                sink.write_all(if *first { b"[\n" } else { b",\n" })?;
+                *first = false;
+                serde_json::to_writer(&mut *sink, output).map_err(std::io::Error::from)?;
+            },
+            Sink::Jsonl(sink) => {
+                serde_json::to_writer(&mut *sink, output).map_err(std::io::Error::from)?;
+                sink.write_all(b"\n")?;
+            },
+        }
+        Ok(())
+    }
+
+    /// Completes the output, closing JSON array, and flushes it
+    pub fn finish(&mut self) -> Result {
+        match &mut self.sink {
+            Sink::Csv(writer) => writer.flush()?,
+            Sink::Json { sink, first } => {
+                sink.write_all(if *first { b"[]\n" } else { b"\n]\n" })?;
+                sink.flush()?;
+            },
+            Sink::Jsonl(sink) => sink.flush()?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+    use indoc::indoc;
+    use rust_decimal::Decimal;
+    use crate::account::{Account, Policy};
+    use crate::instructions::{Instruction, Transaction};
+    use super::{DecimalFormat, Output, OutputFormat, OutputWriter};
+
+    fn write_outputs(format: OutputFormat, count: usize) -> String {
+        write_formatted_outputs(format, DecimalFormat::AsIs, count)
+    }
+
+    fn write_formatted_outputs(format: OutputFormat, decimals: DecimalFormat, count: usize) -> String {
+        let mut accounts = [Account::default(), Account::default()];
+        let deposit = Instruction::Deposit(Transaction::new(2, 1, Decimal::new(15000, 4)));
+        accounts[1].apply(deposit, &Policy::default()).expect("failed to deposit");
+        let mut buffer = Vec::new();
+        let mut writer = OutputWriter::new(format, &mut buffer);
+        for (client, account) in accounts.iter().enumerate().take(count) {
+            writer.write(&Output::convert_from(client as u16 + 1, account).formatted(decimals)).expect("failed to write");
+        }
+        writer.finish().expect("failed to finish");
+        drop(writer);
+
+        String::from_utf8(buffer).expect("failed to stringify the buffer")
+    }
+
+    #[test]
+    fn json_output() {
+        assert_eq!(write_outputs(OutputFormat::Json, 2), indoc!(r#"
+            [
+            {"client":1,"available":"0","held":"0","total":"0","locked":false},
+            {"client":2,"available":"1.5000","held":"0","total":"1.5000","locked":false}
+            ]
+        "#));
+        assert_eq!(write_outputs(OutputFormat::Json, 0), "[]\n");
+    }
+
+    #[test]
+    fn same_keys() {
+        let csv = write_outputs(OutputFormat::Csv, 1);
+        let columns: BTreeSet<&str> = csv.lines().next().expect("missing header").split(',').collect();
+        let jsonl = write_outputs(OutputFormat::Jsonl, 1);
+        let object: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&jsonl).expect("failed to parse JSON");
+        assert_eq!(object.keys().map(String::as_str).collect::<BTreeSet<_>>(), columns);
+    }
+
+    #[test]
+    fn jsonl_output() {
+        assert_eq!(write_outputs(OutputFormat::Jsonl, 2), indoc!(r#"
+            {"client":1,"available":"0","held":"0","total":"0","locked":false}
+            {"client":2,"available":"1.5000","held":"0","total":"1.5000","locked":false}
+        "#));
+    }
+
+    #[test]
+    fn decimal_formats() {
+        assert_eq!(write_formatted_outputs(OutputFormat::Csv, DecimalFormat::Fixed, 2), indoc!("
+            client,available,held,total,locked
+            1,0.0000,0.0000,0.0000,false
+            2,1.5000,0.0000,1.5000,false
+        "));
+        assert_eq!(write_formatted_outputs(OutputFormat::Csv, DecimalFormat::Normalized, 2), indoc!("
+            client,available,held,total,locked
+            1,0,0,0,false
+            2,1.5,0,1.5,false
+        "));
+        assert_eq!(write_formatted_outputs(OutputFormat::Jsonl, DecimalFormat::Normalized, 2), indoc!(r#"
+            {"client":1,"available":"0","held":"0","total":"0","locked":false}
+            {"client":2,"available":"1.5","held":"0","total":"1.5","locked":false}
+        "#));
+        assert_eq!(DecimalFormat::Fixed.apply(Decimal::new(-123, 1)).to_string(), "-12.3000");
+        let mut zero = Decimal::new(0, 3);
+        zero.set_sign_negative(true);
+        assert_eq!(DecimalFormat::Normalized.apply(zero).to_string(), "0");
+        assert_eq!(DecimalFormat::Fixed.apply(zero).to_string(), "0.0000");
+        assert_eq!("as-is".parse::<DecimalFormat>().ok(), Some(DecimalFormat::AsIs));
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use log::debug;
+
+use crate::errors::TransactionSystemError;
+use crate::result::Result;
+
+/// Owners in a page of the index, 4 bytes each
+const SLOTS_PER_PAGE: usize = 1024;
+/// Pages of the index held in memory, 1 MiB altogether
+const CACHED_PAGES: usize = 256;
+/// Bits of the slot of an id: reserved at all, reserved by an applied transaction, and the client
+const PRESENT: u32 = 0x8000_0000;
+const APPLIED: u32 = 0x0001_0000;
+
+/// Client a transaction id is reserved for, and whether a transaction of it was applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Owner {
+    pub client: u16,
+    pub applied: bool,
+}
+
+impl Owner {
+    /// Whether a deposit or withdrawal of the client with the id is a duplicate
+    pub fn refuses(&self, client: u16) -> bool {
+        self.client != client || self.applied
+    }
+
+    fn encode(&self) -> u32 {
+        PRESENT | if self.applied { APPLIED } else { 0 } | u32::from(self.client)
+    }
+
+    fn decode(slot: u32) -> Option<Self> {
+        (slot & PRESENT != 0).then_some(Self { client: slot as u16, applied: slot & APPLIED != 0 })
+    }
+}
+
+/// Owners of transaction ids, enforcing their uniqueness across all clients
+///
+/// An id is reserved for the first client submitting a deposit or withdrawal with it, whether that's
+/// applied or rejected, so it's refused to any other client in the order of the input, which the reader
+/// thread sees the same way when processing in parallel. The client itself may submit it again until
+/// a transaction of it is applied. Owners are kept in memory unless they're given an index on disk,
+/// which keeps them across runs once they're persisted.
+#[derive(Debug, Default)]
+pub(crate) struct TxOwners {
+    owners: HashMap<u32, Owner>,
+    index: Option<OwnersIndex>,
+}
+
+impl TxOwners {
+    /// Keeps the owners in the index file instead of memory, with the ones it kept from earlier runs,
+    /// moving the ones known so far there
+    pub fn index(&mut self, path: impl Into<PathBuf>) -> Result {
+        let mut index = OwnersIndex::open(path.into())?;
+        for (tx, owner) in self.owners.drain() {
+            index.set(tx, owner)?;
+        }
+        self.owners.shrink_to_fit();
+        self.index = Some(index);
+        Ok(())
+    }
+
+    /// Whether the owners are taken from an index kept by earlier runs
+    pub fn kept(&self) -> bool {
+        self.index.as_ref().is_some_and(|index| index.kept)
+    }
+
+    /// Writes the owners out to the index, if they're given one
+    pub fn persist(&mut self) -> Result {
+        self.index.as_mut().map_or(Ok(()), OwnersIndex::persist)
+    }
+
+    pub fn get(&mut self, tx: u32) -> std::result::Result<Option<Owner>, TransactionSystemError> {
+        match &mut self.index {
+            Some(index) => index.get(tx),
+            None => Ok(self.owners.get(&tx).copied()),
+        }
+    }
+
+    fn set(&mut self, tx: u32, owner: Owner) -> Result {
+        match &mut self.index {
+            Some(index) => index.set(tx, owner),
+            None => {
+                self.owners.insert(tx, owner);
+                Ok(())
+            },
+        }
+    }
+
+    /// Reserves the id for the client unless it's reserved already, giving its owner either way
+    pub fn claim(&mut self, tx: u32, client: u16) -> std::result::Result<Owner, TransactionSystemError> {
+        if let Some(owner) = self.get(tx)? {
+            return Ok(owner);
+        }
+        let owner = Owner { client, applied: false };
+        self.set(tx, owner)?;
+        Ok(owner)
+    }
+
+    /// Notes the transaction of the client was applied
+    pub fn apply(&mut self, tx: u32, client: u16) -> Result {
+        self.set(tx, Owner { client, applied: true })
+    }
+
+    /// Whether the transaction was applied to the client, though its account may not know it anymore
+    pub fn applied_by(&mut self, tx: u32, client: u16) -> std::result::Result<bool, TransactionSystemError> {
+        Ok(self.get(tx)? == Some(Owner { client, applied: true }))
+    }
+
+    /// Takes over the owners of the other ones, recorded later
+    pub fn merge(&mut self, other: TxOwners) -> Result {
+        other.owners.into_iter().try_for_each(|(tx, owner)| self.set(tx, owner))
+    }
+
+    pub fn clear(&mut self) -> Result {
+        self.owners.clear();
+        self.index.as_mut().map_or(Ok(()), OwnersIndex::clear)
+    }
+}
+
+/// Owners in memory are compared, as tests do
+#[cfg(test)]
+impl PartialEq for TxOwners {
+    fn eq(&self, other: &Self) -> bool {
+        self.index.is_none() && other.index.is_none() && self.owners == other.owners
+    }
+}
+
+struct Page {
+    slots: Vec<u32>,
+    dirty: bool,
+    used: u64,
+}
+
+/// Owners in a file of 4 bytes per id at the offset of the id times 4, left sparse where ids aren't
+/// used, with the least recently used pages written back when more than `CACHED_PAGES` are needed
+/// and all of them by `persist()`; the file is kept for the next run
+struct OwnersIndex {
+    path: PathBuf,
+    file: File,
+    pages: HashMap<u64, Page>,
+    clock: u64,
+    kept: bool,
+}
+
+impl fmt::Debug for OwnersIndex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OwnersIndex").field("path", &self.path).field("pages", &self.pages.len()).finish()
+    }
+}
+
+impl OwnersIndex {
+    fn open(path: PathBuf) -> std::result::Result<Self, TransactionSystemError> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+        let kept = file.metadata()?.len() > 0;
+        Ok(Self { path, file, pages: HashMap::new(), clock: 0, kept })
+    }
+
+    fn get(&mut self, tx: u32) -> std::result::Result<Option<Owner>, TransactionSystemError> {
+        let (page, slot) = self.page(tx)?;
+        Ok(Owner::decode(page.slots[slot]))
+    }
+
+    fn set(&mut self, tx: u32, owner: Owner) -> Result {
+        let (page, slot) = self.page(tx)?;
+        page.slots[slot] = owner.encode();
+        page.dirty = true;
+        Ok(())
+    }
+
+    fn page(&mut self, tx: u32) -> std::result::Result<(&mut Page, usize), TransactionSystemError> {
+        let number = u64::from(tx) / SLOTS_PER_PAGE as u64;
+        if !self.pages.contains_key(&number) && self.pages.len() >= CACHED_PAGES {
+            self.evict()?;
+        }
+        self.clock += 1;
+        let page = match self.pages.entry(number) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(Self::read(&mut self.file, number)?),
+        };
+        page.used = self.clock;
+        Ok((page, tx as usize % SLOTS_PER_PAGE))
+    }
+
+    /// Reads the page, with zeroes past the end of the file
+    fn read(file: &mut File, number: u64) -> std::result::Result<Page, TransactionSystemError> {
+        let mut bytes = Vec::with_capacity(SLOTS_PER_PAGE * 4);
+        file.seek(SeekFrom::Start(number * (SLOTS_PER_PAGE * 4) as u64))?;
+        Read::by_ref(file).take((SLOTS_PER_PAGE * 4) as u64).read_to_end(&mut bytes)?;
+        bytes.resize(SLOTS_PER_PAGE * 4, 0);
+        let slots = bytes.chunks_exact(4).map(|slot| u32::from_le_bytes([slot[0], slot[1], slot[2], slot[3]])).collect();
+        Ok(Page { slots, dirty: false, used: 0 })
+    }
+
+    fn evict(&mut self) -> Result {
+        let Some(number) = self.pages.iter().min_by_key(|(_, page)| page.used).map(|(number, _)| *number) else {
+            return Ok(());
+        };
+        if let Some(page) = self.pages.remove(&number).filter(|page| page.dirty) {
+            Self::write(&mut self.file, number, &page)?;
+        }
+        Ok(())
+    }
+
+    fn write(file: &mut File, number: u64, page: &Page) -> Result {
+        let bytes: Vec<u8> = page.slots.iter().flat_map(|slot| slot.to_le_bytes()).collect();
+        file.seek(SeekFrom::Start(number * bytes.len() as u64))?;
+        file.write_all(&bytes)?;
+        Ok(())
+    }
+
+    fn persist(&mut self) -> Result {
+        for (number, page) in self.pages.iter_mut().filter(|(_, page)| page.dirty) {
+            Self::write(&mut self.file, *number, page)?;
+            page.dirty = false;
+        }
+        self.file.sync_data()?;
+        debug!("Index of owners {} persisted", self.path.display());
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result {
+        self.pages.clear();
+        self.file.set_len(0)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Owner, TxOwners, CACHED_PAGES, SLOTS_PER_PAGE};
+
+    #[test]
+    fn reservations() {
+        let mut owners = TxOwners::default();
+        let claim = |owners: &mut TxOwners, tx, client| owners.claim(tx, client).expect("failed to claim");
+        assert_eq!(claim(&mut owners, 1, 2), Owner { client: 2, applied: false });
+        assert_eq!(claim(&mut owners, 1, 3), Owner { client: 2, applied: false });
+        assert!(!owners.applied_by(1, 2).expect("failed to look up"));
+        assert!(!claim(&mut owners, 1, 2).refuses(2));
+        assert!(claim(&mut owners, 1, 3).refuses(3));
+
+        owners.apply(1, 2).expect("failed to apply");
+        assert_eq!(claim(&mut owners, 1, 2), Owner { client: 2, applied: true });
+        assert!(owners.applied_by(1, 2).expect("failed to look up"));
+        assert!(!owners.applied_by(1, 3).expect("failed to look up"));
+        assert!(claim(&mut owners, 1, 2).refuses(2));
+        assert_eq!(owners.get(4).expect("failed to look up"), None);
+
+        let mut applied = TxOwners::default();
+        applied.apply(4, 5).expect("failed to apply");
+        owners.merge(applied).expect("failed to merge");
+        assert!(owners.applied_by(4, 5).expect("failed to look up"));
+    }
+
+    #[test]
+    fn index_on_disk() {
+        let directory = tempfile::tempdir().expect("failed to create temporary directory");
+        let path = directory.path().join("owners.index");
+        let mut owners = TxOwners::default();
+        owners.apply(7, 1).expect("failed to apply");
+        owners.index(&path).expect("failed to create index");
+        assert!(!owners.kept());
+        assert!(owners.applied_by(7, 1).expect("failed to look up"));
+
+        // More pages than cached, so the first ones are written out and read back
+        let txs: Vec<u32> = (0..=CACHED_PAGES as u32 * 2).map(|page| page * SLOTS_PER_PAGE as u32 + 3).chain([u32::MAX]).collect();
+        for (n, &tx) in txs.iter().enumerate() {
+            owners.claim(tx, n as u16).expect("failed to claim");
+        }
+        owners.apply(3, 0).expect("failed to apply");
+        for (n, &tx) in txs.iter().enumerate() {
+            assert_eq!(owners.get(tx).expect("failed to look up"), Some(Owner { client: n as u16, applied: tx == 3 }));
+        }
+        assert_eq!(owners.get(4).expect("failed to look up"), None);
+        assert!(owners.owners.is_empty());
+
+        // Kept for the next run once persisted
+        owners.persist().expect("failed to persist");
+        drop(owners);
+        let mut owners = TxOwners::default();
+        owners.index(&path).expect("failed to open index");
+        assert!(owners.kept());
+        assert!(owners.applied_by(3, 0).expect("failed to look up"));
+        assert_eq!(owners.get(u32::MAX).expect("failed to look up"), Some(Owner { client: (txs.len() - 1) as u16, applied: false }));
+
+        owners.clear().expect("failed to clear");
+        assert_eq!(owners.get(7).expect("failed to look up"), None);
+    }
+}
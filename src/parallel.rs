@@ -0,0 +1,99 @@
+use std::sync::mpsc::{Receiver, Sender};
+use log::error;
+use rust_decimal::Decimal;
+
+use crate::account::{Outcome, Policy};
+use crate::errors::TransactionSystemError;
+use crate::expiry::Expiry;
+use crate::instructions::{Instruction, workaround::InstructionType};
+use crate::owners::{Owner, TxOwners};
+use crate::register::recognize_evicted;
+use crate::rejects::Reject;
+use crate::store::{AccountStore, MemoryStore};
+
+/// Instructions sent to a shard at once, so the channel isn't paid for every single one
+pub(crate) const BATCH_SIZE: usize = 256;
+
+/// Batches queued for a shard before the reader waits for it to catch up
+pub(crate) const CHANNEL_CAPACITY: usize = 16;
+
+/// Instruction dispatched to a shard, with the row details needed to report its rejection
+pub(crate) struct Job {
+    pub instruction: Instruction,
+    pub typ: InstructionType,
+    pub tx: u32,
+    pub amount: Option<Decimal>,
+    pub line: u64,
+    /// Owner of the `tx` when the instruction was read, reserved by then for the client of a transaction
+    pub owner: Option<Owner>,
+    /// Sequence of the instruction among all the instructions, as when processed sequentially
+    pub sequence: u64,
+}
+
+/// Worker owning the accounts of clients assigned to it; `tx` uniqueness is still enforced across
+/// all clients, as the reader reserves the ids for their clients before dispatching the instructions
+pub(crate) struct Shard<'a> {
+    book: MemoryStore,
+    policy: Policy,
+    /// Transactions applied by the shard, unknown to the reader until it's done
+    applied: TxOwners,
+    /// Transactions of the shard's clients to evict as the clock advances
+    expiry: Expiry,
+    rejects: Option<Sender<Reject>>,
+    input: &'a str,
+}
+
+impl<'a> Shard<'a> {
+    pub fn new(book: MemoryStore, policy: Policy, rejects: Option<Sender<Reject>>, input: &'a str) -> Self {
+        Self { book, policy, applied: TxOwners::default(), expiry: Expiry::default(), rejects, input }
+    }
+
+    /// Applies the instructions until the reader hangs up, giving back the book and the transactions applied
+    pub fn run(mut self, jobs: Receiver<Vec<Job>>) -> (MemoryStore, TxOwners) {
+        for batch in jobs {
+            for job in batch {
+                let client = job.instruction.client();
+                let reject = match self.execute(job.instruction, job.owner, job.sequence) {
+                    Ok(outcome) => outcome.code().map(|code| {
+                        Reject::new(job.typ.to_string(), client, job.tx, job.amount, self.input, job.line, code).with_outcome(outcome)
+                    }),
+                    Err(error) => {
+                        error!("Account instruction error: {}", error);
+                        Some(Reject::new(job.typ.to_string(), client, job.tx, job.amount, self.input, job.line, error.code()))
+                    },
+                };
+                if let (Some(rejects), Some(reject)) = (&self.rejects, reject) {
+                    // The reader gone means processing is aborted anyway
+                    let _ = rejects.send(reject);
+                }
+            }
+        }
+        (self.book, self.applied)
+    }
+
+    fn execute(&mut self, instruction: Instruction, owner: Option<Owner>, sequence: u64) -> std::result::Result<Outcome, TransactionSystemError> {
+        self.expiry.expire(&mut self.book, &self.policy, sequence)?;
+        let applied = &mut self.applied;
+        let mut applied_by = |tx: u32, client: u16| match owner {
+            Some(owner) if owner.client == client => Ok(owner.applied || applied.applied_by(tx, client)?),
+            _ => Ok(false),
+        };
+        let txowner = instruction.transaction().map(|transaction| (transaction.tx(), transaction.client()));
+        if let (Some((tx, client)), Some(owner)) = (txowner, owner) {
+            if owner.client != client || applied_by(tx, client)? {
+                if let Instruction::Deposit(transaction) | Instruction::Withdrawal(transaction) = instruction {
+                    return Err(TransactionSystemError::DuplicateTransactionError { client: owner.client, transaction });
+                }
+            }
+        }
+
+        let account = self.book.get_or_create(instruction.client())?;
+        let outcome = account.apply_at(instruction, &self.policy, sequence)
+            .map_err(|error| recognize_evicted(error, &mut applied_by))?;
+        if let Some((tx, client)) = txowner {
+            self.applied.apply(tx, client)?;
+            self.expiry.record(&self.policy, sequence, client);
+        }
+        Ok(outcome)
+    }
+}
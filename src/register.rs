@@ -0,0 +1,1638 @@
+use std::io::{BufRead, Read, Write};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread;
+use csv::Position;
+use log::{debug, error};
+
+use crate::account::{Outcome, Policy, Retention};
+use crate::balances::{Balance, BalancesReader};
+use crate::result::Result;
+use crate::errors::TransactionSystemError;
+use crate::expiry::Expiry;
+use crate::ingestion::{BadRow, ErrorBudget, Ingestion};
+use crate::instructions::{Instruction, workaround};
+use crate::output::{DecimalFormat, Output, OutputFormat, OutputWriter};
+use crate::owners::TxOwners;
+use crate::parallel::{Job, Shard, BATCH_SIZE, CHANNEL_CAPACITY};
+use crate::rejects::{Reject, RejectsWriter};
+use crate::snapshot;
+use crate::source::{self, InstructionSource};
+use crate::store::{AccountStore, MemoryStore};
+use crate::wal::{Tally, WriteAheadLog};
+
+/// The book of all clients' accounts executing the instructions
+#[derive(Debug)]
+pub struct Register {
+    thebook: Box<dyn AccountStore>,
+    /// Clients owning every transaction id submitted, kept to enforce `tx` uniqueness across all clients
+    txowners: TxOwners,
+    policy: Policy,
+    /// Resilient mode skips unparsable rows instead of aborting, as long as the budget allows
+    budget: Option<ErrorBudget>,
+    ingestion: Ingestion,
+    rejects: Option<RejectsWriter>,
+    format: OutputFormat,
+    decimals: DecimalFormat,
+    wal: Option<WriteAheadLog>,
+    /// Number of valid instructions executed, applied or rejected, the clock of `Retention::Instructions`
+    sequence: u64,
+    expiry: Expiry,
+}
+
+impl Default for Register {
+    fn default() -> Self {
+        Self {
+            thebook: Box::new(MemoryStore::default()),
+            txowners: TxOwners::default(),
+            policy: Policy::default(),
+            budget: None,
+            ingestion: Ingestion::default(),
+            rejects: None,
+            format: OutputFormat::default(),
+            decimals: DecimalFormat::default(),
+            wal: None,
+            sequence: 0,
+            expiry: Expiry::default(),
+        }
+    }
+}
+
+impl Register {
+    pub fn new(policy: Policy) -> Self {
+        Self { policy, ..Default::default() }
+    }
+
+    pub fn builder() -> RegisterBuilder {
+        RegisterBuilder::default()
+    }
+
+    pub fn ingestion(&self) -> &Ingestion {
+        &self.ingestion
+    }
+
+    pub fn execute(&mut self, instruction: Instruction) -> std::result::Result<Outcome, TransactionSystemError> {
+        debug!("Processing account for client {}", instruction.client());
+        self.try_execute(instruction).inspect_err(|error| {
+            error!("Account instruction error: {}", error);
+        })
+    }
+
+    fn try_execute(&mut self, instruction: Instruction) -> std::result::Result<Outcome, TransactionSystemError> {
+        // Counted whether applied or rejected, as the write-ahead log records it and the shards count it
+        let txowner = instruction.transaction().map(|transaction| (transaction.tx(), transaction.client()));
+        if let Some((tx, client)) = txowner {
+            let owner = self.txowners.claim(tx, client)?;
+            if owner.refuses(client) {
+                if let Instruction::Deposit(transaction) | Instruction::Withdrawal(transaction) = instruction {
+                    self.expiry.expire(self.thebook.as_mut(), &self.policy, self.sequence + 1)?;
+                    self.sequence += 1;
+                    return Err(TransactionSystemError::DuplicateTransactionError { client: owner.client, transaction });
+                }
+            }
+        }
+
+        self.expiry.expire(self.thebook.as_mut(), &self.policy, self.sequence + 1)?;
+        // Deposits and withdrawals look only at the funds, unless older transactions are evicted
+        let account = match (&instruction, self.policy.retention) {
+            (Instruction::Deposit(_) | Instruction::Withdrawal(_), Retention::Unlimited) => self.thebook.get_or_create_funds(instruction.client())?,
+            _ => self.thebook.get_or_create(instruction.client())?,
+        };
+        self.sequence += 1;
+        let txowners = &mut self.txowners;
+        let outcome = account.apply_at(instruction, &self.policy, self.sequence)
+            .map_err(|error| recognize_evicted(error, |tx, client| txowners.applied_by(tx, client)))?;
+
+        if let Some((tx, client)) = txowner {
+            self.txowners.apply(tx, client)?;
+            self.expiry.record(&self.policy, self.sequence, client);
+        }
+        Ok(outcome)
+    }
+
+    /// Processes the file in the format recognized from its extension; compressed files are refused
+    pub fn process(&mut self, inputfilename: &Path) -> Result {
+        let mut source = source::open(&inputfilename.to_string_lossy(), None)?;
+        self.process_source(source.as_mut())
+    }
+
+    /// Processes the input on top of the state left by previous inputs; with the write-ahead log
+    /// the rows of the input consumed before are skipped
+    pub fn process_source(&mut self, source: &mut dyn InstructionSource) -> Result {
+        if self.wal.as_ref().is_some_and(|wal| !wal.recovered()) {
+            self.recover()?;
+        }
+        let skip = self.wal.as_ref().map_or(0, |wal| wal.progress(source.name()));
+
+        debug!("Consuming input data of {}...", source.name());
+        let consumed = self.consume(source, skip);
+        debug!("...consuption of input data finished.");
+        // Written back and reported whether the input was consumed or not, to keep what was applied
+        let persisted = self.persist();
+        let flushed = self.rejects.as_mut().map_or(Ok(()), RejectsWriter::flush);
+        let rows = consumed?;
+        persisted?;
+        flushed?;
+        if let Some(wal) = &mut self.wal {
+            wal.complete(source.name(), rows)?;
+        }
+
+        match self.budget {
+            Some(budget) if self.ingestion.exceeds_ratio(&budget) => Err(self.budget_exceeded()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Processes the rows of the input past the ones to skip, giving the number of all the rows
+    fn consume(&mut self, source: &mut dyn InstructionSource, skip: u64) -> std::result::Result<u64, TransactionSystemError> {
+        let mut rows = 0;
+        while let Some(row) = source.next_row()? {
+            rows += 1;
+            if rows <= skip {
+                continue;
+            }
+            self.ingestion.count_row();
+            match row.instruction {
+                Ok(instruction) => self.process_row(source.name(), rows, instruction, row.position.as_ref())?,
+                Err(error) if self.budget.is_some() => {
+                    self.skip_row(source.name(), row.position.as_ref(), error)?;
+                    if let Some(wal) = &mut self.wal {
+                        wal.skip(source.name(), rows, None)?;
+                    }
+                },
+                Err(error) => return Err(error),
+            }
+            if self.wal.as_ref().is_some_and(WriteAheadLog::checkpoint_due) {
+                self.checkpoint(Some((source.name(), rows)))?;
+            }
+        }
+        if skip > 0 {
+            debug!("Skipped {} rows of {} consumed before", skip.min(rows), source.name());
+        }
+        Ok(rows)
+    }
+
+    /// Processes the input like `process_source()`, with instructions applied by `shards` worker threads,
+    /// each owning the accounts of the clients assigned to it by client id, while this thread reads
+    /// the input and reserves the ids of transactions for their clients; instructions of every client are
+    /// applied in their order, but instructions of different clients aren't ordered, so rejects are reported
+    /// out of order. The accounts are held in memory while processing, so a store which doesn't hold all
+    /// of them there is refused.
+    pub fn process_parallel(&mut self, source: &mut dyn InstructionSource, shards: usize) -> Result {
+        if self.wal.is_some() {
+            return Err(TransactionSystemError::ArgumentsError("write-ahead log requires sequential processing".to_owned()));
+        }
+        if !self.thebook.in_memory() {
+            return Err(TransactionSystemError::ArgumentsError("store of accounts on disk requires sequential processing".to_owned()));
+        }
+        let shards = shards.max(1);
+
+        let mut books: Vec<MemoryStore> = (0..shards).map(|_| MemoryStore::default()).collect();
+        self.thebook.drain(&mut |client, account| {
+            *books[client as usize % shards].get_or_create(client)? = account;
+            Ok(())
+        })?;
+        let input = source.name().to_owned();
+        let policy = self.policy;
+        let reporting = self.rejects.is_some();
+        let (rejects, rejected) = mpsc::channel();
+
+        debug!("Consuming input data of {} with {} shards...", input, shards);
+        let (consumed, books) = thread::scope(|scope| {
+            let mut senders = Vec::with_capacity(shards);
+            let mut workers = Vec::with_capacity(shards);
+            for book in books {
+                let (sender, jobs) = mpsc::sync_channel(CHANNEL_CAPACITY);
+                let shard = Shard::new(book, policy, reporting.then(|| rejects.clone()), &input);
+                workers.push(scope.spawn(move || shard.run(jobs)));
+                senders.push(sender);
+            }
+            drop(rejects);
+
+            let consumed = self.dispatch(source, &senders, &rejected);
+            // Hanging up lets the shards finish
+            drop(senders);
+            let books: Vec<(MemoryStore, TxOwners)> = workers.into_iter()
+                .map(|worker| worker.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+                .collect();
+            (consumed, books)
+        });
+        debug!("...consuption of input data finished.");
+
+        for (mut book, applied) in books {
+            self.txowners.merge(applied)?;
+            let thebook = &mut self.thebook;
+            book.drain(&mut |client, account| {
+                *thebook.get_or_create(client)? = account;
+                Ok(())
+            })?;
+        }
+        // Transactions of the shards' clients expire as of the last instruction of any shard
+        self.expiry.reset();
+        let expired = self.expiry.expire(self.thebook.as_mut(), &self.policy, self.sequence);
+        // Written back and reported whether the input was consumed or not, as by `process_source()`
+        let persisted = self.persist();
+        let reported = match &mut self.rejects {
+            Some(rejects) => rejected.try_iter().try_for_each(|reject| rejects.write(&reject)).and_then(|()| rejects.flush()),
+            None => Ok(()),
+        };
+        consumed?;
+        expired?;
+        persisted?;
+        reported?;
+
+        match self.budget {
+            Some(budget) if self.ingestion.exceeds_ratio(&budget) => Err(self.budget_exceeded()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reads the input, sending valid instructions to the shards in batches and reporting the rest
+    fn dispatch(&mut self, source: &mut dyn InstructionSource, shards: &[SyncSender<Vec<Job>>], rejected: &Receiver<Reject>) -> Result {
+        let mut batches: Vec<Vec<Job>> = shards.iter().map(|_| Vec::with_capacity(BATCH_SIZE)).collect();
+        let send = |shard: usize, batch: Vec<Job>| {
+            // A shard gone only after panicking, which is brought up when it's joined
+            let _ = shards[shard].send(batch);
+        };
+
+        let mut read = || -> Result {
+            while let Some(row) = source.next_row()? {
+                self.ingestion.count_row();
+                let (row, position) = match row.instruction {
+                    Ok(instruction) => (instruction, row.position),
+                    Err(error) if self.budget.is_some() => {
+                        self.skip_row(source.name(), row.position.as_ref(), error)?;
+                        continue;
+                    },
+                    Err(error) => return Err(error),
+                };
+                let line = position.as_ref().map_or(0, |position| position.line());
+                let (typ, client, tx, amount) = (row.typ(), row.client(), row.tx(), row.amount());
+
+                match Instruction::try_from(row) {
+                    Ok(instruction) => {
+                        let shard = client as usize % shards.len();
+                        // Reserved here, in the order of the input, so the shards refuse duplicates as if sequentially
+                        let owner = match instruction.transaction() {
+                            Some(transaction) => Some(self.txowners.claim(transaction.tx(), transaction.client())?),
+                            None => self.txowners.get(tx)?,
+                        };
+                        self.sequence += 1;
+                        batches[shard].push(Job { instruction, typ, tx, amount, line, owner, sequence: self.sequence });
+                        if batches[shard].len() == BATCH_SIZE {
+                            send(shard, std::mem::replace(&mut batches[shard], Vec::with_capacity(BATCH_SIZE)));
+                            if let Some(rejects) = &mut self.rejects {
+                                for reject in rejected.try_iter() {
+                                    rejects.write(&reject)?;
+                                }
+                            }
+                        }
+                    },
+                    Err(error) => {
+                        let reject = Reject::new(typ.to_string(), client, tx, amount, source.name(), line, error.code());
+                        self.skip_row(source.name(), position.as_ref(), error)?;
+                        if let Some(rejects) = &mut self.rejects {
+                            rejects.write(&reject)?;
+                        }
+                    },
+                }
+            }
+            Ok(())
+        };
+        let read = read();
+
+        // Instructions read before a failure are applied all the same, as they are sequentially
+        for (shard, batch) in batches.into_iter().enumerate() {
+            if !batch.is_empty() {
+                send(shard, batch);
+            }
+        }
+        read
+    }
+
+    fn process_row(&mut self, input: &str, rownumber: u64, row: workaround::Instruction, position: Option<&Position>) -> Result {
+        let line = position.map_or(0, |position| position.line());
+        let (typ, client, tx, amount) = (row.typ(), row.client(), row.tx(), row.amount());
+        let reject = |error: &TransactionSystemError| {
+            Reject::new(typ.to_string(), client, tx, amount, input, line, error.code())
+        };
+        let notice = |outcome: Outcome| {
+            outcome.code().map(|code| Reject::new(typ.to_string(), client, tx, amount, input, line, code).with_outcome(outcome))
+        };
+
+        let rejected = match Instruction::try_from(row) {
+            Ok(instruction) => {
+                let encoded = self.wal.is_some().then(|| instruction.encode());
+                let sequence = self.sequence;
+                let executed = self.execute(instruction);
+                let applied = executed.is_ok();
+                let rejected = match executed {
+                    Ok(outcome) => notice(outcome),
+                    Err(error) => Some(reject(&error)),
+                };
+                // Logged once it advanced the clock, rather than failed to get at the account
+                if let (Some(wal), Some(encoded)) = (&mut self.wal, encoded.filter(|_| self.sequence > sequence)) {
+                    wal.append(input, rownumber, &encoded, applied, rejected.as_ref().filter(|_| self.rejects.is_some()))?;
+                }
+                rejected
+            },
+            Err(error) => {
+                let rejected = reject(&error);
+                self.skip_row(input, position, error)?;
+                if let Some(wal) = &mut self.wal {
+                    wal.skip(input, rownumber, Some(&rejected).filter(|_| self.rejects.is_some()))?;
+                }
+                Some(rejected)
+            },
+        };
+
+        match (rejected, &mut self.rejects) {
+            (Some(rejected), Some(rejects)) => rejects.write(&rejected),
+            _ => Ok(()),
+        }
+    }
+
+    /// Makes the accounts and the owners of their transactions durable, as far as their stores are
+    fn persist(&mut self) -> Result {
+        self.thebook.persist()?;
+        self.txowners.persist()
+    }
+
+    /// Rebuilds the state from the last checkpoint and the tail of the write-ahead log, superseding
+    /// the state set up so far; a fresh log starts with the checkpoint of the current state instead
+    pub fn recover(&mut self) -> Result {
+        let Some(mut wal) = self.wal.take().filter(|wal| !wal.recovered()) else {
+            return Ok(())
+        };
+        let recovered = self.recover_from(&mut wal);
+        self.wal = Some(wal);
+        recovered
+    }
+
+    /// The rows, bad rows and rejects reported are recovered as well, the report cut back to the ones
+    /// the checkpoint covers and the ones of the log reported again; a fresh log starts a new report
+    fn recover_from(&mut self, wal: &mut WriteAheadLog) -> Result {
+        let tally = wal.read_checkpoint(|mut source| {
+            self.thebook.clear()?;
+            self.txowners.clear()?;
+            self.restore(&mut source)
+        })?;
+        if let Some(tally) = tally {
+            self.ingestion.resume(tally.rows, tally.errors);
+        }
+        if let (Some(rejects), Some(position)) = (&mut self.rejects, tally.map_or(Some(0), |tally| tally.report)) {
+            rejects.truncate(position)?;
+        }
+
+        let replayed = wal.replay(&mut |instruction| self.try_execute(instruction).map(drop))?;
+        self.ingestion.resume(self.ingestion.rows() + replayed.rows, self.ingestion.errors() + replayed.errors);
+        if let Some(rejects) = &mut self.rejects {
+            replayed.rejects.iter().try_for_each(|reject| rejects.write(reject))?;
+        }
+        if tally.is_none() {
+            self.checkpoint_to(wal, None)?;
+        }
+        Ok(())
+    }
+
+    fn checkpoint(&mut self, input: Option<(&str, u64)>) -> Result {
+        if let Some(mut wal) = self.wal.take() {
+            let checkpointed = self.checkpoint_to(&mut wal, input);
+            self.wal = Some(wal);
+            checkpointed?;
+        }
+        Ok(())
+    }
+
+    fn checkpoint_to(&mut self, wal: &mut WriteAheadLog, input: Option<(&str, u64)>) -> Result {
+        let report = self.rejects.as_mut().map_or(Ok(None), RejectsWriter::position)?;
+        let tally = Tally { rows: self.ingestion.rows(), errors: self.ingestion.errors(), report };
+        let (thebook, sequence) = (&mut self.thebook, self.sequence);
+        wal.checkpoint(input, tally, |mut sink| snapshot::write(thebook.as_mut(), sequence, &mut sink))
+    }
+
+    fn skip_row(&mut self, input: &str, position: Option<&Position>, error: TransactionSystemError) -> Result {
+        let badrow = BadRow::new(input, position, error.to_string());
+        error!("Skipped input record of {} at line {} (byte {}): {}", badrow.input(), badrow.line(), badrow.byte(), badrow.message());
+        self.ingestion.record(&badrow)?;
+
+        match self.budget {
+            Some(budget) if self.ingestion.exceeds(&budget) => Err(self.budget_exceeded()),
+            _ => Ok(()),
+        }
+    }
+
+    fn budget_exceeded(&self) -> TransactionSystemError {
+        TransactionSystemError::ErrorBudgetError {
+            errors: self.ingestion.errors(),
+            rows: self.ingestion.rows(),
+        }
+    }
+
+    /// Seeds the accounts with opening balances; inconsistent rows are skipped as invalid instructions,
+    /// malformed ones as well in resilient mode
+    pub fn open_balances<R: Read>(&mut self, balances: &mut BalancesReader<R>) -> Result {
+        debug!("Consuming opening balances of {}...", balances.name());
+        let mut opened = HashSet::new();
+        while let Some(row) = balances.next_row()? {
+            self.ingestion.count_row();
+            let error = match row.balance {
+                Ok(balance) => match self.open_account(balance, &mut opened) {
+                    Ok(()) => continue,
+                    Err(error) => error,
+                },
+                Err(error) if self.budget.is_some() => error,
+                Err(error) => return Err(error),
+            };
+            self.skip_row(balances.name(), row.position.as_ref(), error)?;
+        }
+        debug!("...consuption of opening balances finished.");
+
+        self.persist()
+    }
+
+    fn open_account(&mut self, balance: Balance, opened: &mut HashSet<u16>) -> Result {
+        let client = balance.client();
+        if !opened.insert(client) {
+            return Err(TransactionSystemError::ValidationError {
+                message: "repeated opening balance of the client".to_owned(),
+                record: format!("{:?}", balance),
+            });
+        }
+        *self.thebook.get_or_create(client)? = balance.into_account()?;
+        Ok(())
+    }
+
+    /// Writes the versioned snapshot of all the accounts, with their transaction histories and states
+    pub fn snapshot(&mut self, sink: &mut impl Write) -> Result {
+        snapshot::write(self.thebook.as_mut(), self.sequence, sink)
+    }
+
+    /// Restores the accounts from the snapshot, so the processing continues where it stopped
+    pub fn restore(&mut self, source: &mut impl BufRead) -> Result {
+        self.expiry.reset();
+        let (thebook, txowners) = (&mut self.thebook, &mut self.txowners);
+        self.sequence = snapshot::read(source, &mut |client, account| {
+            for tx in account.transactions() {
+                txowners.apply(tx, client)?;
+            }
+            *thebook.get_or_create(client)? = account;
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    fn inner_dump(mut self, sorted: bool, sink: &mut impl Write) -> Result {
+        let mut writer = OutputWriter::new(self.format, sink);
+        let decimals = self.decimals;
+
+        debug!("Dumping the book state...");
+        if sorted {
+            let mut records = Vec::new();
+            self.thebook.for_each(&mut |client, account| {
+                records.push(Output::convert_from(client, account).formatted(decimals));
+                Ok(())
+            })?;
+            records.sort_by_key(Output::client);
+            for record in records {
+                writer.write(&record)?
+            }
+        } else {
+            self.thebook.for_each(&mut |client, account| {
+                writer.write(&Output::convert_from(client, account).formatted(decimals))
+            })?;
+        }
+        debug!("...dumping the book finished.");
+        
+        writer.finish()?;
+        debug!("Output writer flushed.");
+
+        // The run is complete, nothing is left to recover
+        if let Some(wal) = &mut self.wal {
+            wal.retire()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn dump(self, sink: &mut impl Write) -> Result {
+        self.inner_dump(false, sink)
+    }
+
+    /// Dumps the accounts ordered by client for reproducible output, at the cost of collecting them first
+    pub fn dump_sorted(self, sink: &mut impl Write) -> Result {
+        self.inner_dump(true, sink)
+    }
+}
+
+/// Transactions applied to the client but its account doesn't know have been evicted from the history,
+/// so operations on them fail with `EvictedTransactionError` rather than as unknown; failing to find
+/// out fails them with that failure
+pub(crate) fn recognize_evicted(error: TransactionSystemError,
+                                applied: impl FnOnce(u32, u16) -> std::result::Result<bool, TransactionSystemError>) -> TransactionSystemError {
+    match error {
+        TransactionSystemError::OperationError { message, operation } => match applied(operation.tx(), operation.client()) {
+            Ok(true) => TransactionSystemError::EvictedTransactionError { operation },
+            Ok(false) => TransactionSystemError::OperationError { message, operation },
+            Err(failure) => failure,
+        },
+        error => error,
+    }
+}
+
+/// Builder of the `Register` with non-default settings
+#[derive(Debug, Default)]
+pub struct RegisterBuilder {
+    register: Register,
+}
+
+impl RegisterBuilder {
+    /// Keeps the accounts in the store; the transactions found there keep their owners, so their ids
+    /// stay unique across runs. The owners are read from all its accounts unless the index kept them.
+    pub fn store(mut self, mut store: impl AccountStore + 'static) -> std::result::Result<Self, TransactionSystemError> {
+        let txowners = &mut self.register.txowners;
+        if !txowners.kept() {
+            store.for_each(&mut |client, account| {
+                for tx in account.transactions() {
+                    txowners.apply(tx, client)?;
+                }
+                Ok(())
+            })?;
+        }
+        self.register.thebook = Box::new(store);
+        Ok(self)
+    }
+
+    /// Keeps the owners of transaction ids in the index file instead of memory, so they don't grow
+    /// with the transactions; the file is kept for the next run along the store, to be given before it
+    pub fn tx_index(mut self, path: impl Into<PathBuf>) -> std::result::Result<Self, TransactionSystemError> {
+        self.register.txowners.index(path)?;
+        Ok(self)
+    }
+
+    pub fn policy(mut self, policy: Policy) -> Self {
+        self.register.policy = policy;
+        self
+    }
+
+    /// Turns on resilient mode, skipping unparsable rows as long as the budget allows
+    pub fn error_budget(mut self, budget: ErrorBudget) -> Self {
+        self.register.budget = Some(budget);
+        self
+    }
+
+    pub fn rejects(mut self, rejects: RejectsWriter) -> Self {
+        self.register.rejects = Some(rejects);
+        self
+    }
+
+    /// Writes every skipped row out to the sink as soon as it's found, a line each
+    pub fn bad_rows(mut self, sink: Box<dyn Write>) -> Self {
+        self.register.ingestion = Ingestion::with_sink(sink);
+        self
+    }
+
+    pub fn output_format(mut self, format: OutputFormat) -> Self {
+        self.register.format = format;
+        self
+    }
+
+    /// Logs applied instructions to recover from, when the processing was interrupted
+    pub fn write_ahead_log(mut self, wal: WriteAheadLog) -> Self {
+        self.register.wal = Some(wal);
+        self
+    }
+
+    pub fn decimal_format(mut self, decimals: DecimalFormat) -> Self {
+        self.register.decimals = decimals;
+        self
+    }
+
+    pub fn build(self) -> Register {
+        self.register
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Write, self};
+    use indoc::*;
+    use tempfile::NamedTempFile;
+
+    use crate::account::{LockedPolicy, Policy};
+    use crate::errors::TransactionSystemError;
+    use crate::ingestion::{ErrorBudget, MIN_RATIO_ROWS};
+    use crate::rejects::{RejectsFormat, RejectsWriter};
+    use crate::source::{CsvSource, JsonLinesSource};
+
+    fn test_instructions_batch(feed: &str, expectation: &str) {
+        test_instructions_batch_with_policy(feed, expectation, Policy::default())
+    }
+
+    fn test_instructions_batch_with_policy(feed: &str, expectation: &str, policy: Policy) {
+        let mut file = NamedTempFile::new().expect("failed to create temporary file");
+        write!(file, "{}", feed).expect("failed to write test data");
+
+        let mut register = super::Register::new(policy);
+        register.process(file.path()).expect("failed to batch process");
+
+        assert_eq!(dump_to_string(register), expectation);
+    }
+
+    fn dump_to_string(register: super::Register) -> String {
+        let mut sink = io::Cursor::new(Vec::<u8>::new());
+        register.dump_sorted(&mut sink).expect("failed to dump");
+        String::from_utf8(sink.into_inner()).expect("failed to stringify the buffer")
+    }
+
+    #[test]
+    fn basic_transactions_batch() {
+        const TEST_FEED: &str = indoc!("
+            type,   client, tx, amount
+            deposit,     1,  1,   11.1
+            deposit,     2,  2,   22.2
+            deposit,     1,  3,   33.3
+            deposit,     2,  4,   44.4
+            deposit,     3,  5,   55.5
+            withdrawal,  2,  6,   11.1
+            withdrawal,  3,  7,   22.2
+            withdrawal,  1,  8,   33.3
+        ");
+
+        const TEST_EXPECTATION: &str = indoc!("
+            client,available,held,total,locked
+            1,11.1,0,11.1,false
+            2,55.5,0,55.5,false
+            3,33.3,0,33.3,false
+        ");
+
+        test_instructions_batch(TEST_FEED, TEST_EXPECTATION)
+    }
+
+    #[test]
+    fn dispute_operations_batch() {
+        const TEST_FEED: &str = indoc!("
+            type, client, tx, amount
+            deposit,   1,  1, 11.1
+            deposit,   2,  2, 22.2
+            deposit,   1,  3, 33.3
+            deposit,   2,  4, 44.4
+            deposit,   3,  5, 55.5
+            withdrawal,2,  6, 11.1
+            withdrawal,3,  7, 22.2
+            withdrawal,1,  8, 33.3
+            dispute,   2,  2,
+            dispute,   3,  5,
+        ");
+
+        const TEST_EXPECTATION: &str = indoc!("
+            client,available,held,total,locked
+            1,11.1,0,11.1,false
+            2,33.3,22.2,55.5,false
+            3,-22.2,55.5,33.3,false
+        ");
+
+        test_instructions_batch(TEST_FEED, TEST_EXPECTATION)
+    }
+
+    #[test]
+    fn resolve_operations_batch() {
+        const TEST_FEED: &str = indoc!("
+            type, client,  tx,  amount
+            deposit,   1,  1, 100.1234
+            deposit,   2,  2, 200.2345
+            deposit,   1,  3, 300.3456
+            deposit,   2,  4, 400.4567
+            deposit,   3,  5, 500.7891
+            withdrawal,2,  6, 123.4567
+            withdrawal,3,  7, 234.5678
+            withdrawal,1,  8,  99.9999
+            dispute,   2,  2,
+            dispute,   1,  3,
+            resolve,   1,  3,
+            deposit,   4,  9, 600.8912
+            deposit,   5, 10, 350.9123
+            deposit,   5, 11, 350.1234
+            dispute,   5, 10,
+            resolve,   5, 10,
+        ");
+
+        const TEST_EXPECTATION: &str = indoc!("
+            client,available,held,total,locked
+            1,300.4691,0.0000,300.4691,false
+            2,277.0000,200.2345,477.2345,false
+            3,266.2213,0,266.2213,false
+            4,600.8912,0,600.8912,false
+            5,701.0357,0.0000,701.0357,false
+        ");
+
+        test_instructions_batch(TEST_FEED, TEST_EXPECTATION)
+    }
+
+    #[test]
+    fn chargeback_operations_batch() {
+        const TEST_FEED: &str = indoc!("
+            type, client,  tx,  amount
+            deposit,   1,  1, 100.1234
+            deposit,   2,  2, 200.2345
+            deposit,   1,  3, 300.3456
+            deposit,   2,  4, 400.4567
+            deposit,   3,  5, 500.7891
+            withdrawal,2,  6, 123.4567
+            withdrawal,3,  7, 234.5678
+            withdrawal,1,  8,  99.9999
+            dispute,   2,  2,
+            dispute,   1,  3,
+            chargeback,1,  3,
+            deposit,   4,  9, 600.8912
+            deposit,   5, 10, 350.9123
+            deposit,   5, 11, 350.1234
+            dispute,   5, 10,
+            chargeback,5, 10,
+        ");
+
+        const TEST_EXPECTATION: &str = indoc!("
+            client,available,held,total,locked
+            1,0.1235,0.0000,0.1235,true
+            2,277.0000,200.2345,477.2345,false
+            3,266.2213,0,266.2213,false
+            4,600.8912,0,600.8912,false
+            5,350.1234,0.0000,350.1234,true
+        ");
+
+        test_instructions_batch(TEST_FEED, TEST_EXPECTATION)
+    }
+
+    #[test]
+    fn locked_account_batch() {
+        const TEST_FEED: &str = indoc!("
+            type, client,  tx,  amount
+            deposit,   1,  1, 100.0
+            deposit,   1,  2,  50.0
+            deposit,   2,  3,  70.0
+            dispute,   1,  2,
+            dispute,   2,  3,
+            chargeback,1,  2,
+            deposit,   1,  4,  20.0
+            withdrawal,1,  5,  10.0
+            dispute,   1,  1,
+            chargeback,2,  3,
+            deposit,   2,  6,  30.0
+            withdrawal,2,  7,  30.0
+        ");
+
+        const TEST_EXPECTATION: &str = indoc!("
+            client,available,held,total,locked
+            1,100,0,100,true
+            2,0,0,0,true
+        ");
+
+        test_instructions_batch(TEST_FEED, TEST_EXPECTATION);
+
+        const TEST_EXPECTATION_DEPOSITS_ALLOWED: &str = indoc!("
+            client,available,held,total,locked
+            1,120,0,120,true
+            2,30,0,30,true
+        ");
+
+        let policy = Policy { locked: LockedPolicy { deposit: true, ..Default::default() }, ..Default::default() };
+        test_instructions_batch_with_policy(TEST_FEED, TEST_EXPECTATION_DEPOSITS_ALLOWED, policy)
+    }
+
+    #[test]
+    fn administration_batch() {
+        const TEST_FEED: &str = indoc!("
+            type,   client, tx, amount, operator, reason
+            deposit,     1,  1,   10.5
+            deposit,     2,  2,   20.5
+            freeze,      1,  3,       ,       42, fraud
+            deposit,     1,  4,    5.0
+            deposit,     2,  5,    1.0
+            dispute,     2,  2,
+            chargeback,  2,  2,
+            unlock,      2,  6,       ,       42, manual
+            deposit,     2,  7,    3.0
+        ");
+
+        const TEST_EXPECTATION: &str = indoc!("
+            client,available,held,total,locked
+            1,10.5,0,10.5,true
+            2,4.0,0.0,4.0,false
+        ");
+
+        test_instructions_batch(TEST_FEED, TEST_EXPECTATION)
+    }
+
+    #[test]
+    fn duplicate_transactions_batch() {
+        const TEST_FEED: &str = indoc!("
+            type,   client, tx, amount
+            deposit,     1,  1,   10.0
+            deposit,     1,  1,   99.0
+            deposit,     2,  1,   55.0
+            withdrawal,  2,  1,    1.0
+            deposit,     2,  2,    5.5
+            withdrawal,  2,  3,    9.9
+            withdrawal,  2,  3,    1.5
+            dispute,     1,  1,
+        ");
+
+        const TEST_EXPECTATION: &str = indoc!("
+            client,available,held,total,locked
+            1,0,10,10,false
+            2,4.0,0,4.0,false
+        ");
+
+        test_instructions_batch(TEST_FEED, TEST_EXPECTATION)
+    }
+
+    #[test]
+    fn invalid_amounts_batch() {
+        const TEST_FEED: &str = indoc!("
+            type,   client, tx, amount
+            deposit,     1,  1,   10.0
+            deposit,     1,  2,
+            deposit,     1,  3,      0
+            withdrawal,  1,  4,   -1.0
+            withdrawal,  1,  5, 1.00001
+            withdrawal,  1,  6,    2.5
+            freeze,      1,  7,
+        ");
+
+        const TEST_EXPECTATION: &str = indoc!("
+            client,available,held,total,locked
+            1,7.5,0,7.5,false
+        ");
+
+        test_instructions_batch(TEST_FEED, TEST_EXPECTATION)
+    }
+
+    const MALFORMED_FEED: &str = indoc!("
+        type,   client, tx, amount
+        deposit,     1,  1,   10.0
+        deposit,   one,  2,    5.0
+        payment,     1,  3,    5.0
+        deposit,     1,  4,    1.5
+        deposit,     1,  5,
+        withdrawal,  1,  6,    2.5
+    ");
+
+    /// Register after processing the malformed feed, the result and the bad rows reported meanwhile
+    fn process_malformed(budget: Option<ErrorBudget>) -> (super::Register, crate::result::Result, String) {
+        let mut file = NamedTempFile::new().expect("failed to create temporary file");
+        write!(file, "{}", MALFORMED_FEED).expect("failed to write test data");
+        let mut badrows = NamedTempFile::new().expect("failed to create temporary file");
+
+        let mut builder = super::Register::builder().bad_rows(Box::new(badrows.reopen().expect("failed to reopen")));
+        if let Some(budget) = budget {
+            builder = builder.error_budget(budget);
+        }
+        let mut register = builder.build();
+        let result = register.process(file.path());
+        let mut report = String::new();
+        io::Read::read_to_string(&mut badrows, &mut report).expect("failed to read bad rows");
+        (register, result, report)
+    }
+
+    #[test]
+    fn malformed_rows_abort_by_default() {
+        let (register, result, _) = process_malformed(None);
+        assert!(matches!(result, Err(TransactionSystemError::RowError { .. })));
+        assert_eq!(register.ingestion().rows(), 2);
+    }
+
+    #[test]
+    fn malformed_rows_skipped_within_budget() {
+        let (register, result, badrows) = process_malformed(Some(ErrorBudget::default()));
+        assert!(result.is_ok());
+        assert_eq!(register.ingestion().rows(), 6);
+        assert_eq!(register.ingestion().errors(), 3);
+
+        let lines: Vec<_> = badrows.lines().map(|line| line.split_once(": ").expect("no message").0.rsplit_once(':').expect("no line").1).collect();
+        assert_eq!(lines, ["3 (byte 54)", "4 (byte 81)", "6 (byte 135)"]);
+
+        let output = dump_to_string(register);
+        assert_eq!(output, "client,available,held,total,locked\n1,9.0,0,9.0,false\n");
+    }
+
+    #[test]
+    fn malformed_rows_exceed_budget() {
+        let (register, result, badrows) = process_malformed(Some(ErrorBudget { max_errors: Some(1), ..Default::default() }));
+        assert!(matches!(result, Err(TransactionSystemError::ErrorBudgetError{ errors: 2, rows: 3 })));
+        assert_eq!(register.ingestion().errors(), 2);
+        // Reported even though the processing is aborted
+        assert_eq!(badrows.lines().count(), 2);
+
+        let (_, result, _) = process_malformed(Some(ErrorBudget { max_ratio: Some(0.4), ..Default::default() }));
+        assert!(matches!(result, Err(TransactionSystemError::ErrorBudgetError{ errors: 3, rows: 6 })));
+
+        let (_, result, _) = process_malformed(Some(ErrorBudget { max_ratio: Some(0.5), ..Default::default() }));
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn malformed_rows_exceed_ratio_along_the_way() {
+        let mut file = NamedTempFile::new().expect("failed to create temporary file");
+        writeln!(file, "type,client,tx,amount").expect("failed to write test data");
+        for row in 1..=3 * MIN_RATIO_ROWS {
+            match row % 2 {
+                0 => writeln!(file, "deposit,1,x,1"),
+                _ => writeln!(file, "deposit,1,{},1", row),
+            }.expect("failed to write test data");
+        }
+
+        // Stopped at the first bad row once the minimum of rows is read, rather than at the end
+        let mut register = super::Register::builder().error_budget(ErrorBudget { max_ratio: Some(0.4), ..Default::default() }).build();
+        let result = register.process(file.path());
+        assert!(matches!(result, Err(TransactionSystemError::ErrorBudgetError{ errors, rows }) if rows == MIN_RATIO_ROWS && errors == MIN_RATIO_ROWS / 2));
+    }
+
+    #[test]
+    fn rejects_report() {
+        const TEST_FEED: &str = indoc!("
+            type,   client, tx, amount
+            deposit,     1,  1,   10.0
+            withdrawal,  1,  2,   20.0
+            deposit,     2,  1,    5.0
+            dispute,     1,  9,
+            deposit,     1,  3,
+            resolve,     1,  1,
+        ");
+
+        const TEST_REJECTS: &str = indoc!("
+            type,client,tx,amount,input,line,reason,receivable
+            withdrawal,1,2,20,feed.csv,3,INSUFFICIENT_FUNDS,
+            deposit,2,1,5,feed.csv,4,DUPLICATE_TX,
+            dispute,1,9,,feed.csv,5,UNKNOWN_TX,
+            deposit,1,3,,feed.csv,6,INVALID_INSTRUCTION,
+            resolve,1,1,,feed.csv,7,ILLEGAL_STATE_TRANSITION,
+        ");
+
+        let mut source = CsvSource::new("feed.csv", TEST_FEED.as_bytes()).expect("failed to read headers");
+        let rejectsfile = NamedTempFile::new().expect("failed to create temporary file");
+        let sink = rejectsfile.reopen().expect("failed to open rejects file");
+
+        let mut badrows = NamedTempFile::new().expect("failed to create temporary file");
+        let mut register = super::Register::builder()
+            .rejects(RejectsWriter::new(RejectsFormat::Csv, Box::new(sink)))
+            .bad_rows(Box::new(badrows.reopen().expect("failed to reopen")))
+            .build();
+        register.process_source(&mut source).expect("failed to batch process");
+        assert_eq!(register.ingestion().errors(), 1);
+        let mut report = String::new();
+        io::Read::read_to_string(&mut badrows, &mut report).expect("failed to read bad rows");
+        assert!(report.starts_with("feed.csv:6 "), "unexpected {}", report);
+
+        let rejects = std::fs::read_to_string(rejectsfile.path()).expect("failed to read rejects");
+        assert_eq!(rejects, TEST_REJECTS);
+    }
+
+    #[test]
+    fn overdraft_notices() {
+        use crate::account::OverdraftPolicy;
+
+        const TEST_FEED: &str = indoc!("
+            type,       client, tx, amount
+            deposit,         1,  1,   10.0
+            withdrawal,      1,  2,    7.5
+            dispute,         1,  1,
+            deposit,         2,  3,    4.0
+            withdrawal,      2,  4,    4.0
+            dispute,         2,  3,
+        ");
+
+        for (overdraft, reason, receivables) in [(OverdraftPolicy::AllowNegative, "NEGATIVE_AVAILABLE", ["", ""]),
+                                                 (OverdraftPolicy::HoldPartial, "HELD_PARTIALLY", ["7.5", "4"])] {
+            for parallel in [false, true] {
+                let mut rejects = NamedTempFile::new().expect("failed to create temporary file");
+                let mut register = super::Register::builder()
+                    .policy(Policy { overdraft, ..Default::default() })
+                    .rejects(RejectsWriter::new(RejectsFormat::Csv, Box::new(rejects.reopen().expect("failed to reopen"))))
+                    .build();
+                let mut source = CsvSource::new("feed.csv", TEST_FEED.as_bytes()).expect("failed to read headers");
+                if parallel {
+                    register.process_parallel(&mut source, 2).expect("failed to batch process");
+                } else {
+                    register.process_source(&mut source).expect("failed to batch process");
+                }
+                assert_eq!(register.ingestion().errors(), 0);
+
+                drop(register.rejects.take());
+                let mut report = String::new();
+                io::Read::read_to_string(&mut rejects, &mut report).expect("failed to read rejects");
+                let mut report: Vec<String> = report.lines().skip(1).map(str::to_owned).collect();
+                report.sort();
+                assert_eq!(report, [
+                    format!("dispute,1,1,,feed.csv,4,{},{}", reason, receivables[0]),
+                    format!("dispute,2,3,,feed.csv,7,{},{}", reason, receivables[1]),
+                ]);
+            }
+        }
+    }
+
+    #[test]
+    fn execute_instructions() {
+        use rust_decimal::Decimal;
+        use crate::account::OverdraftPolicy;
+        use crate::instructions::{Instruction, Operation, Transaction};
+
+        let policy = Policy { overdraft: OverdraftPolicy::RejectIfInsufficient, ..Default::default() };
+        let mut register = super::Register::builder().policy(policy).build();
+
+        assert!(register.execute(Instruction::Deposit(Transaction::new(1, 1, Decimal::new(100, 0)))).is_ok());
+        assert!(register.execute(Instruction::Withdrawal(Transaction::new(1, 2, Decimal::new(60, 0)))).is_ok());
+        let result = register.execute(Instruction::Dispute(Operation::new(1, 1)));
+        assert!(matches!(result, Err(TransactionSystemError::InsufficientFundsError{..})));
+
+        let output = dump_to_string(register);
+        assert_eq!(output, "client,available,held,total,locked\n1,40,0,40,false\n");
+    }
+
+    #[test]
+    fn disk_store_batch() {
+        let directory = tempfile::tempdir().expect("failed to create temporary directory");
+        let mut file = NamedTempFile::new().expect("failed to create temporary file");
+        write!(file, "{}", indoc!("
+            type,   client, tx, amount
+            deposit,     1,  1,   10.0
+            deposit,     2,  2,   20.0
+            deposit,     3,  3,   30.0
+            dispute,     1,  1,
+            withdrawal,  2,  4,    5.0
+            chargeback,  1,  1,
+        ")).expect("failed to write test data");
+
+        let store = crate::store::DiskStore::open(directory.path(), 1).expect("failed to open store");
+        let mut register = super::Register::builder().store(store).expect("failed to attach store").build();
+        register.process(file.path()).expect("failed to batch process");
+
+        let output = dump_to_string(register);
+        assert_eq!(output, indoc!("
+            client,available,held,total,locked
+            1,0,0,0,true
+            2,15,0,15,false
+            3,30,0,30,false
+        "));
+    }
+
+    #[test]
+    fn disk_store_reopened() {
+        let directory = tempfile::tempdir().expect("failed to create temporary directory");
+        let feeds = ["type,client,tx,amount\ndeposit,1,1,10\n", "type,client,tx,amount\ndeposit,1,1,7\ndispute,1,1,\n"];
+        let mut register = super::Register::default();
+        for feed in feeds {
+            let store = crate::store::DiskStore::open(directory.path(), 10).expect("failed to open store");
+            register = super::Register::builder().store(store).expect("failed to attach store").build();
+            register.process_source(&mut CsvSource::new("feed.csv", feed.as_bytes()).expect("failed to read headers"))
+                .expect("failed to batch process");
+        }
+        assert!(register.txowners.applied_by(1, 1).expect("failed to look up owner"));
+        assert_eq!(dump_to_string(register), "client,available,held,total,locked\n1,0,10,10,false\n");
+    }
+
+    #[test]
+    fn disk_store_kept_owners() {
+        // Owners come from the index kept along the store, with the reservation of the refused withdrawal
+        let directory = tempfile::tempdir().expect("failed to create temporary directory");
+        let feeds = ["type,client,tx,amount\ndeposit,1,1,10\nwithdrawal,2,5,1\n", "type,client,tx,amount\ndeposit,3,5,7\ndeposit,3,1,7\n"];
+        let mut register = super::Register::default();
+        for feed in feeds {
+            let store = crate::store::DiskStore::open(directory.path(), 10).expect("failed to open store");
+            register = super::Register::builder().tx_index(directory.path().join("owners.index")).expect("failed to open index")
+                .store(store).expect("failed to attach store").build();
+            register.process_source(&mut CsvSource::new("feed.csv", feed.as_bytes()).expect("failed to read headers"))
+                .expect("failed to batch process");
+        }
+        assert!(register.txowners.kept());
+        assert_eq!(dump_to_string(register), "client,available,held,total,locked\n1,10,0,10,false\n2,0,0,0,false\n");
+    }
+
+    #[test]
+    fn jsonl_batch() {
+        let mut file = tempfile::Builder::new().suffix(".jsonl").tempfile().expect("failed to create temporary file");
+        write!(file, "{}", indoc!(r#"
+            {"type": "deposit", "client": 1, "tx": 1, "amount": 10.0}
+            {"type": "deposit", "client": 2, "tx": 2, "amount": "2.5"}
+            {"type": "withdrawal", "client": 1, "tx": 3, "amount": 4}
+            {"type": "dispute", "client": 2, "tx": 2}
+        "#)).expect("failed to write test data");
+
+        let mut register = super::Register::default();
+        register.process(file.path()).expect("failed to batch process");
+
+        let output = dump_to_string(register);
+        assert_eq!(output, indoc!("
+            client,available,held,total,locked
+            1,6,0,6,false
+            2,0.0,2.5,2.5,false
+        "));
+    }
+
+    #[test]
+    fn multiple_inputs() {
+        let mut first = CsvSource::new("first.csv", indoc!("
+            type,   client, tx, amount
+            deposit,     1,  1,   10.0
+            deposit,     2,  2,    3.0
+        ").as_bytes()).expect("failed to read headers");
+        let mut second = JsonLinesSource::new("second.jsonl", indoc!(r#"
+            {"type": "dispute", "client": 1, "tx": 1}
+            {"type": "deposit", "client": 2, "tx": 1, "amount": 1.0}
+            {"type": "withdrawal", "client": 2, "tx": 3, "amount": 1.0}
+        "#).as_bytes());
+
+        let mut register = super::Register::default();
+        register.process_source(&mut first).expect("failed to process first input");
+        register.process_source(&mut second).expect("failed to process second input");
+        assert_eq!(register.ingestion().rows(), 5);
+
+        let output = dump_to_string(register);
+        assert_eq!(output, indoc!("
+            client,available,held,total,locked
+            1,0,10,10,false
+            2,2,0,2,false
+        "));
+    }
+
+    #[test]
+    fn json_output() {
+        use crate::output::OutputFormat;
+
+        let mut source = CsvSource::new("feed.csv", indoc!("
+            type,   client, tx, amount
+            deposit,     2,  1,   10.0
+            deposit,     1,  2,  0.0001
+            dispute,     2,  1,
+            chargeback,  2,  1,
+        ").as_bytes()).expect("failed to read headers");
+
+        let mut register = super::Register::builder().output_format(OutputFormat::Json).build();
+        register.process_source(&mut source).expect("failed to batch process");
+
+        let output = dump_to_string(register);
+        assert_eq!(output, indoc!(r#"
+            [
+            {"client":1,"available":"0.0001","held":"0","total":"0.0001","locked":false},
+            {"client":2,"available":"0","held":"0","total":"0","locked":true}
+            ]
+        "#));
+    }
+
+    #[test]
+    fn snapshot_and_restore() {
+        let mut source = CsvSource::new("today.csv", indoc!("
+            type,   client, tx, amount
+            deposit,     1,  1,   10.0
+            deposit,     1,  2,    5.0
+            dispute,     1,  2,
+            deposit,     2,  3,    7.5
+            dispute,     2,  3,
+            chargeback,  2,  3,
+        ").as_bytes()).expect("failed to read headers");
+        let mut register = super::Register::default();
+        register.process_source(&mut source).expect("failed to batch process");
+
+        let mut snapshot = Vec::new();
+        register.snapshot(&mut snapshot).expect("failed to write snapshot");
+
+        let mut source = CsvSource::new("tomorrow.csv", indoc!("
+            type,   client, tx, amount
+            resolve,     1,  2,
+            dispute,     1,  1,
+            deposit,     3,  1,    1.0
+            resolve,     2,  3,
+        ").as_bytes()).expect("failed to read headers");
+        let mut register = super::Register::default();
+        register.restore(&mut snapshot.as_slice()).expect("failed to restore snapshot");
+        register.process_source(&mut source).expect("failed to batch process");
+
+        let output = dump_to_string(register);
+        assert_eq!(output, indoc!("
+            client,available,held,total,locked
+            1,5,10,15,false
+            2,0.0,0.0,0.0,true
+        "));
+    }
+
+    #[test]
+    fn restore_corrupted_snapshot() {
+        let mut register = super::Register::default();
+        register.process_source(&mut CsvSource::new("feed.csv", indoc!("
+            type,   client, tx, amount
+            deposit,     1,  1,   10.0
+            deposit,     2,  2,    5.0
+        ").as_bytes()).expect("failed to read headers")).expect("failed to batch process");
+
+        let mut snapshot = Vec::new();
+        register.snapshot(&mut snapshot).expect("failed to write snapshot");
+        let snapshot = String::from_utf8(snapshot).expect("failed to stringify the buffer");
+        assert!(snapshot.starts_with("snapshot 2 2\n"));
+        assert!(snapshot.ends_with("snapshot-end 2\n"));
+
+        let truncated = &snapshot[..snapshot.rfind("account").unwrap()];
+        let error = super::Register::default().restore(&mut truncated.as_bytes()).expect_err("truncated snapshot restored");
+        assert_eq!(error.code(), "CORRUPTED_DATA");
+
+        let miscounted = snapshot.replace("snapshot-end 2", "snapshot-end 3");
+        assert!(super::Register::default().restore(&mut miscounted.as_bytes()).is_err());
+
+        let future = snapshot.replace("snapshot 2 2", "snapshot 3 2");
+        assert!(super::Register::default().restore(&mut future.as_bytes()).is_err());
+
+        let former = snapshot.replace("snapshot 2 2", "snapshot 1");
+        let mut register = super::Register::default();
+        assert!(register.restore(&mut former.as_bytes()).is_ok());
+        assert_eq!(register.sequence, 0);
+    }
+
+    #[test]
+    fn opening_balances() {
+        use crate::balances::BalancesReader;
+
+        let mut balances = BalancesReader::new("balances.csv", indoc!("
+            client, available, held, total, locked
+                 1,      10.5,    0,  10.5,  false
+                 2,         3,    2,     5,   true
+                 3,         1,    1,     3,  false
+                 1,         1,    0,     1,  false
+        ").as_bytes()).expect("failed to read headers");
+        let mut source = CsvSource::new("feed.csv", indoc!("
+            type,   client, tx, amount
+            withdrawal,  1,  1,    0.5
+            deposit,     2,  2,    1.0
+            deposit,     3,  3,    1.0
+        ").as_bytes()).expect("failed to read headers");
+
+        let mut badrows = NamedTempFile::new().expect("failed to create temporary file");
+        let mut register = super::Register::builder().bad_rows(Box::new(badrows.reopen().expect("failed to reopen"))).build();
+        register.open_balances(&mut balances).expect("failed to open balances");
+        assert_eq!(register.ingestion().errors(), 2);
+        let mut report = String::new();
+        io::Read::read_to_string(&mut badrows, &mut report).expect("failed to read bad rows");
+        assert!(report.starts_with("balances.csv:4 "), "unexpected {}", report);
+        register.process_source(&mut source).expect("failed to batch process");
+
+        let output = dump_to_string(register);
+        assert_eq!(output, indoc!("
+            client,available,held,total,locked
+            1,10.0,0,10.0,false
+            2,3,2,5,true
+            3,1,0,1,false
+        "));
+    }
+
+    /// Source failing after given number of rows, as if the process died there
+    struct Interrupted<'a> {
+        source: CsvSource<&'a [u8]>,
+        rows: usize,
+    }
+
+    impl crate::source::InstructionSource for Interrupted<'_> {
+        fn name(&self) -> &str {
+            self.source.name()
+        }
+
+        fn next_row(&mut self) -> Result<Option<crate::source::Row>, TransactionSystemError> {
+            if self.rows == 0 {
+                return Err(io::Error::other("interrupted").into());
+            }
+            self.rows -= 1;
+            self.source.next_row()
+        }
+    }
+
+    #[test]
+    fn write_ahead_log_recovery() {
+        const TEST_FEED: &str = indoc!("
+            type,   client, tx, amount
+            deposit,     1,  1,   10.0
+            deposit,     2,  2,   20.0
+            withdrawal,  1,  3,    4.0
+            dispute,     2,  2,
+            deposit,     1,  4,    1.0
+            resolve,     2,  2,
+            dispute,     1,  4,
+        ");
+        let directory = tempfile::tempdir().expect("failed to create temporary directory");
+        let open = || crate::wal::WriteAheadLog::open(directory.path(), 2).expect("failed to open write-ahead log");
+
+        let mut register = super::Register::builder().write_ahead_log(open()).build();
+        let mut source = Interrupted { source: CsvSource::new("feed.csv", TEST_FEED.as_bytes()).expect("failed to read headers"), rows: 5 };
+        assert!(register.process_source(&mut source).is_err());
+        drop(register);
+
+        // Last log line written only partially by the dying process
+        let mut log = std::fs::OpenOptions::new().append(true).open(directory.path().join("wal.log")).expect("failed to open log");
+        write!(log, "apply 99 6 resolve 2").expect("failed to tear the log");
+
+        let mut register = super::Register::builder().write_ahead_log(open()).build();
+        register.recover().expect("failed to recover");
+        assert_eq!(register.wal.as_ref().map(|wal| wal.progress("feed.csv")), Some(5));
+        register.process_source(&mut CsvSource::new("feed.csv", TEST_FEED.as_bytes()).expect("failed to read headers"))
+            .expect("failed to batch process");
+        assert_eq!(register.ingestion().errors(), 0);
+
+        let output = dump_to_string(register);
+        assert_eq!(output, indoc!("
+            client,available,held,total,locked
+            1,6,1,7,false
+            2,20,0,20,false
+        "));
+
+        // Run completed with the output retires the log, so another input of the same name is read in full
+        assert!(!directory.path().join("checkpoint").exists());
+        let mut register = super::Register::builder().write_ahead_log(open()).build();
+        register.process_source(&mut CsvSource::new("feed.csv", "type,client,tx,amount\ndeposit,3,1,5.0\n".as_bytes())
+            .expect("failed to read headers")).expect("failed to batch process");
+        assert_eq!(register.wal.as_ref().map(|wal| wal.progress("feed.csv")), Some(1));
+        assert_eq!(dump_to_string(register), "client,available,held,total,locked\n3,5,0,5,false\n");
+    }
+
+    #[test]
+    fn write_ahead_log_rejects() {
+        // Rejects and bad rows of the run that died are neither lost nor reported twice once recovered
+        const TEST_FEED: &str = indoc!("
+            type,   client, tx, amount
+            deposit,     1,  1,   10.0
+            withdrawal,  1,  2,   50.0
+            withdrawal,  1,  x,    1.0
+            deposit,     2,  4,    5.0
+            dispute,     2,  9,
+            deposit,     2,  5,
+            deposit,     1,  6,    1.0
+            dispute,     1,  7,
+        ");
+        const TEST_REJECTS: &str = indoc!("
+            type,client,tx,amount,input,line,reason,receivable
+            withdrawal,1,2,50,feed.csv,3,INSUFFICIENT_FUNDS,
+            dispute,2,9,,feed.csv,6,UNKNOWN_TX,
+            deposit,2,5,,feed.csv,7,INVALID_INSTRUCTION,
+            dispute,1,7,,feed.csv,9,UNKNOWN_TX,
+        ");
+        let directory = tempfile::tempdir().expect("failed to create temporary directory");
+        let rejectsfile = directory.path().join("rejects.csv");
+        let open = |interval| {
+            super::Register::builder()
+                .write_ahead_log(crate::wal::WriteAheadLog::open(directory.path(), interval).expect("failed to open write-ahead log"))
+                .rejects(RejectsWriter::create(&rejectsfile, RejectsFormat::Csv, true).expect("failed to create rejects"))
+                .error_budget(ErrorBudget::default())
+                .build()
+        };
+
+        for rows in 1..=8 {
+            std::fs::write(&rejectsfile, "stale rejects of the previous run\n").expect("failed to write rejects");
+            let mut register = open(2);
+            let mut source = Interrupted { source: CsvSource::new("feed.csv", TEST_FEED.as_bytes()).expect("failed to read headers"), rows };
+            assert!(register.process_source(&mut source).is_err());
+            drop(register);
+
+            // Report of the last row logged only partially, and rejects written past the log
+            let mut log = std::fs::OpenOptions::new().append(true).open(directory.path().join("wal.log")).expect("failed to open log");
+            writeln!(log, r#"report {{"type":"deposit","client":3,"tx":8,"amount":"1","input":"feed.csv","line":99,"reason":"DUPLICATE_TX","receivable":null}}"#)
+                .expect("failed to tear the log");
+            write!(log, "apply 99 99 deposit 3 8 1").expect("failed to tear the log");
+            let mut report = std::fs::OpenOptions::new().append(true).open(&rejectsfile).expect("failed to open rejects");
+            writeln!(report, "deposit,3,8,1,feed.csv,99,DUPLICATE_TX,").expect("failed to tear the rejects");
+
+            let mut register = open(2);
+            register.process_source(&mut CsvSource::new("feed.csv", TEST_FEED.as_bytes()).expect("failed to read headers"))
+                .expect("failed to batch process");
+            assert_eq!((register.ingestion().rows(), register.ingestion().errors()), (8, 2), "interrupted after {} rows", rows);
+            std::fs::write(directory.path().join("checkpoint.tmp"), "").expect("failed to write checkpoint");
+            dump_to_string(register);
+            assert_eq!(std::fs::read_to_string(&rejectsfile).expect("failed to read rejects"), TEST_REJECTS, "interrupted after {} rows", rows);
+            assert!(!directory.path().join("checkpoint.tmp").exists());
+        }
+    }
+
+    #[test]
+    fn write_ahead_log_clock() {
+        // Rejected instructions advance the clock of the retention as applied ones, also when replayed
+        const TEST_FEED: &str = indoc!("
+            type,   client, tx, amount
+            deposit,     1,  1,   10.0
+            deposit,     2,  1,    5.0
+            withdrawal,  2,  2,    9.0
+            deposit,     2,  3,    1.0
+            dispute,     1,  1,
+        ");
+        let policy = Policy { retention: crate::account::Retention::Instructions(2), ..Default::default() };
+        let expectation = indoc!("
+            client,available,held,total,locked
+            1,10,0,10,false
+            2,1,0,1,false
+        ");
+        let source = || CsvSource::new("feed.csv", TEST_FEED.as_bytes()).expect("failed to read headers");
+
+        let mut sequential = super::Register::new(policy);
+        sequential.process_source(&mut source()).expect("failed to batch process");
+        let mut parallel = super::Register::new(policy);
+        parallel.process_parallel(&mut source(), 2).expect("failed to batch process");
+
+        let directory = tempfile::tempdir().expect("failed to create temporary directory");
+        let open = || crate::wal::WriteAheadLog::open(directory.path(), 100).expect("failed to open write-ahead log");
+        let mut register = super::Register::builder().policy(policy).write_ahead_log(open()).build();
+        assert!(register.process_source(&mut Interrupted { source: source(), rows: 4 }).is_err());
+        drop(register);
+        let mut recovered = super::Register::builder().policy(policy).write_ahead_log(open()).build();
+        recovered.process_source(&mut source()).expect("failed to batch process");
+
+        for register in [sequential, parallel, recovered] {
+            assert_eq!(register.sequence, 5);
+            assert_eq!(dump_to_string(register), expectation);
+        }
+    }
+
+    #[test]
+    fn parallel_batch() {
+        // Reused ids go to their first client either way, even when its transaction is rejected
+        let mut feed = String::from("type,client,tx,amount\n");
+        for group in 1..400u32 {
+            let (client, previous) = (group % 37, (group - 1) % 37);
+            let settlement = if group % 3 == 0 { "chargeback" } else { "resolve" };
+            feed.push_str(&format!("deposit,{},{},10.0\n", client, group * 5));
+            feed.push_str(&format!("withdrawal,{},{},1.5\n", client, group * 5 + 1));
+            feed.push_str(&format!("dispute,{},{},\n", client, group * 5));
+            feed.push_str(&format!("{},{},{},\n", settlement, previous, (group - 1) * 5));
+            feed.push_str(&format!("deposit,{},{},2.25\n", client, group * 5 + 1));
+            feed.push_str(&format!("deposit,{},{},1.0\n", previous, group * 5));
+            feed.push_str(&format!("withdrawal,{},{},1000\n", client, group * 5 + 2));
+            feed.push_str(&format!("deposit,{},{},1.0\n", previous, group * 5 + 2));
+            feed.push_str(&format!("deposit,{},{},3\n", client, group * 5 + 2));
+        }
+
+        let mut sequential = super::Register::default();
+        sequential.process_source(&mut CsvSource::new("feed.csv", feed.as_bytes()).expect("failed to read headers"))
+            .expect("failed to batch process");
+        let mut parallel = super::Register::default();
+        parallel.process_parallel(&mut CsvSource::new("feed.csv", feed.as_bytes()).expect("failed to read headers"), 4)
+            .expect("failed to batch process");
+
+        assert_eq!(parallel.txowners, sequential.txowners);
+        assert_eq!(dump_to_string(parallel), dump_to_string(sequential));
+    }
+
+    #[test]
+    fn failure_keeps_disk_store() {
+        for parallel in [false, true] {
+            let directory = tempfile::tempdir().expect("failed to create temporary directory");
+            let open = || crate::store::DiskStore::open(directory.path(), 10).expect("failed to open store");
+            let mut register = super::Register::builder().store(open()).expect("failed to attach store").build();
+            register.process_source(&mut CsvSource::new("monday.csv", "type,client,tx,amount\ndeposit,1,1,10\n".as_bytes())
+                .expect("failed to read headers")).expect("failed to batch process");
+
+            let mut register = super::Register::builder()
+                .store(open()).expect("failed to attach store")
+                .error_budget(ErrorBudget { max_errors: Some(0), ..Default::default() })
+                .build();
+            let mut source = CsvSource::new("tuesday.csv", indoc!("
+                type,   client, tx, amount
+                deposit,     2,  2,    5
+                deposit,     1,  3,    1
+                deposit,     x,  4,    1
+                deposit,     3,  5,    1
+            ").as_bytes()).expect("failed to read headers");
+            // Threads would take all the accounts into memory, so they're refused before any is touched
+            if parallel {
+                assert!(matches!(register.process_parallel(&mut source, 2), Err(TransactionSystemError::ArgumentsError(_))));
+                drop(register);
+                let register = super::Register::builder().store(open()).expect("failed to attach store").build();
+                assert_eq!(dump_to_string(register), "client,available,held,total,locked\n1,10,0,10,false\n");
+                continue;
+            }
+            let result = register.process_source(&mut source);
+            assert!(matches!(result, Err(TransactionSystemError::ErrorBudgetError{ errors: 1, rows: 3 })));
+            drop(register);
+
+            let register = super::Register::builder().store(open()).expect("failed to attach store").build();
+            assert_eq!(dump_to_string(register), "client,available,held,total,locked\n1,11,0,11,false\n2,5,0,5,false\n");
+        }
+    }
+
+    #[test]
+    fn parallel_batch_on_restored_state() {
+        let mut register = super::Register::default();
+        register.process_source(&mut CsvSource::new("monday.csv", indoc!("
+            type,   client, tx, amount
+            deposit,     1,  1,   10.0
+            deposit,     2,  2,   20.0
+        ").as_bytes()).expect("failed to read headers")).expect("failed to batch process");
+        let mut snapshot = Vec::new();
+        register.snapshot(&mut snapshot).expect("failed to write snapshot");
+
+        let mut rejects = NamedTempFile::new().expect("failed to create temporary file");
+        let mut register = super::Register::builder()
+            .rejects(RejectsWriter::new(RejectsFormat::Csv, Box::new(rejects.reopen().expect("failed to reopen"))))
+            .error_budget(ErrorBudget::default())
+            .build();
+        register.restore(&mut snapshot.as_slice()).expect("failed to restore snapshot");
+        register.process_parallel(&mut CsvSource::new("tuesday.csv", indoc!("
+            type,   client, tx, amount
+            dispute,     1,  1,
+            deposit,     3,  2,    5.0
+            withdrawal,  2,  3,   25.0
+            deposit,     3,  4,     -1
+            deposit,     3,  5,    1.0
+        ").as_bytes()).expect("failed to read headers"), 3).expect("failed to batch process");
+        assert_eq!(register.ingestion().errors(), 1);
+
+        drop(register.rejects.take());
+        let mut report = String::new();
+        io::Read::read_to_string(&mut rejects, &mut report).expect("failed to read rejects");
+        let mut report: Vec<&str> = report.lines().skip(1).collect();
+        report.sort();
+        assert_eq!(report, [
+            "deposit,3,2,5,tuesday.csv,3,DUPLICATE_TX,",
+            "deposit,3,4,-1,tuesday.csv,5,INVALID_INSTRUCTION,",
+            "withdrawal,2,3,25,tuesday.csv,4,INSUFFICIENT_FUNDS,",
+        ]);
+
+        assert_eq!(dump_to_string(register), indoc!("
+            client,available,held,total,locked
+            1,0,10,10,false
+            2,20,0,20,false
+            3,1,0,1,false
+        "));
+    }
+
+    #[test]
+    fn idle_accounts_expire() {
+        const TEST_FEED: &str = indoc!("
+            type,   client, tx, amount
+            deposit,     1,  1,   10.0
+            deposit,     2,  2,    1.0
+            deposit,     2,  3,    1.0
+            deposit,     2,  2,    1.0
+            deposit,     2,  4,    1.0
+        ");
+        let policy = Policy { retention: crate::account::Retention::Instructions(2), ..Default::default() };
+
+        for parallel in [false, true] {
+            let mut register = super::Register::new(policy);
+            let mut source = CsvSource::new("feed.csv", TEST_FEED.as_bytes()).expect("failed to read headers");
+            if parallel {
+                register.process_parallel(&mut source, 2).expect("failed to batch process");
+            } else {
+                register.process_source(&mut source).expect("failed to batch process");
+            }
+
+            // Evicted though no instruction of the client came after it
+            let mut transactions = |client| -> Vec<u32> {
+                register.thebook.get_or_create(client).expect("failed to get account").transactions().collect()
+            };
+            assert_eq!(transactions(1), [] as [u32; 0], "parallel {}", parallel);
+            assert_eq!(transactions(2), [3, 4], "parallel {}", parallel);
+        }
+    }
+
+    #[test]
+    fn evicted_transactions() {
+        const TEST_FEED: &str = indoc!("
+            type,   client, tx, amount
+            deposit,     1,  1,   10.0
+            deposit,     1,  2,    5.0
+            deposit,     2,  3,    1.0
+            dispute,     1,  1,
+            dispute,     1,  3,
+            dispute,     1,  9,
+            dispute,     1,  2,
+            deposit,     1,  3,    1.0
+        ");
+        let policy = Policy { retention: crate::account::Retention::LastPerAccount(1), ..Default::default() };
+
+        for (parallel, indexed) in [(false, false), (true, false), (false, true), (true, true)] {
+            let directory = tempfile::tempdir().expect("failed to create temporary directory");
+            let mut rejects = NamedTempFile::new().expect("failed to create temporary file");
+            let mut builder = super::Register::builder()
+                .policy(policy)
+                .rejects(RejectsWriter::new(RejectsFormat::Csv, Box::new(rejects.reopen().expect("failed to reopen"))));
+            if indexed {
+                builder = builder.tx_index(directory.path().join("owners.index")).expect("failed to create index");
+            }
+            let mut register = builder.build();
+            let mut source = CsvSource::new("feed.csv", TEST_FEED.as_bytes()).expect("failed to read headers");
+            if parallel {
+                register.process_parallel(&mut source, 2).expect("failed to batch process");
+            } else {
+                register.process_source(&mut source).expect("failed to batch process");
+            }
+
+            drop(register.rejects.take());
+            let mut report = String::new();
+            io::Read::read_to_string(&mut rejects, &mut report).expect("failed to read rejects");
+            assert_eq!(report, indoc!("
+                type,client,tx,amount,input,line,reason,receivable
+                dispute,1,1,,feed.csv,5,EVICTED_TX,
+                dispute,1,3,,feed.csv,6,UNKNOWN_TX,
+                dispute,1,9,,feed.csv,7,UNKNOWN_TX,
+                deposit,1,3,1,feed.csv,9,DUPLICATE_TX,
+            "), "parallel {}, indexed {}", parallel, indexed);
+            assert_eq!(dump_to_string(register), indoc!("
+                client,available,held,total,locked
+                1,10,5,15,false
+                2,1,0,1,false
+            "));
+        }
+    }
+}
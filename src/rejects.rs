@@ -0,0 +1,241 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use parse_display::{Display, FromStr};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize, Serializer};
+use crate::account::Outcome;
+use crate::errors::TransactionSystemError;
+use crate::result::Result;
+
+#[derive(Debug, Display, FromStr, Default, Clone, Copy, PartialEq, Eq)]
+#[display(style = "lowercase")]
+pub enum RejectsFormat {
+    #[default]
+    Csv,
+    Jsonl,
+}
+
+impl RejectsFormat {
+    /// Recognizes JSON Lines by `.jsonl` or `.ndjson` extension, anything else is CSV
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("jsonl") | Some("ndjson") => RejectsFormat::Jsonl,
+            _ => RejectsFormat::Csv,
+        }
+    }
+}
+
+/// Input row refused by the validation or by the account, with the reason code of the failure,
+/// or applied with an `Outcome` deserving notice, with its code and the shortfall made receivable
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Reject {
+    #[serde(rename = "type")]
+    typ: String,
+    client: u16,
+    tx: u32,
+    #[serde(serialize_with = "serialize_amount")]
+    amount: Option<Decimal>,
+    input: String,
+    line: u64,
+    reason: Cow<'static, str>,
+    #[serde(serialize_with = "serialize_amount")]
+    receivable: Option<Decimal>,
+}
+
+/// Amounts are written as strings, to never lose precision
+fn serialize_amount<S: Serializer>(amount: &Option<Decimal>, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    match amount {
+        Some(amount) => serializer.serialize_str(&amount.to_string()),
+        None => serializer.serialize_none(),
+    }
+}
+
+impl Reject {
+    pub fn new(typ: String, client: u16, tx: u32, amount: Option<Decimal>, input: &str, line: u64, reason: &'static str) -> Self {
+        Self { typ, client, tx, amount, input: input.to_owned(), line, reason: Cow::Borrowed(reason), receivable: None }
+    }
+
+    pub fn with_outcome(self, outcome: Outcome) -> Self {
+        let receivable = match outcome {
+            Outcome::HeldPartially { shortfall } => Some(shortfall),
+            _ => None,
+        };
+        Self { receivable, ..self }
+    }
+
+    /// Writes the reject as a line of JSON, which the write-ahead log keeps as well
+    pub(crate) fn write_json(&self, sink: &mut impl Write) -> std::io::Result<()> {
+        let string = |amount: Option<Decimal>| amount.map_or_else(|| "null".to_owned(), |amount| format!("\"{}\"", amount));
+        writeln!(sink, r#"{{"type":"{}","client":{},"tx":{},"amount":{},"input":{},"line":{},"reason":"{}","receivable":{}}}"#,
+            self.typ, self.client, self.tx, string(self.amount), serde_json::to_string(&self.input)?, self.line, self.reason, string(self.receivable))
+    }
+}
+
+enum Sink {
+    Csv(Box<csv::Writer<Box<dyn Write>>>),
+    Jsonl(Box<dyn Write>),
+}
+
+/// Report of rejected input rows
+pub struct RejectsWriter {
+    sink: Sink,
+    format: RejectsFormat,
+    file: Option<File>,
+}
+
+impl fmt::Debug for RejectsWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RejectsWriter").field("format", &self.format).field("file", &self.file).finish()
+    }
+}
+
+impl RejectsWriter {
+    pub fn new(format: RejectsFormat, sink: Box<dyn Write>) -> Self {
+        Self { sink: Self::sink(format, sink, true), format, file: None }
+    }
+
+    /// Report written to the file, truncated unless it's `resumed`: then its content is kept for the
+    /// write-ahead log to cut it back to the rejects its checkpoint covers, with `truncate()`
+    pub fn create(path: &Path, format: RejectsFormat, resumed: bool) -> std::result::Result<Self, TransactionSystemError> {
+        let mut file = OpenOptions::new().write(true).create(true).truncate(!resumed).open(path)?;
+        file.seek(SeekFrom::End(0))?;
+        let headers = file.stream_position()? == 0;
+        let sink = Self::sink(format, Box::new(BufWriter::new(file.try_clone()?)), headers);
+        Ok(Self { sink, format, file: Some(file) })
+    }
+
+    fn sink(format: RejectsFormat, sink: Box<dyn Write>, headers: bool) -> Sink {
+        match format {
+            RejectsFormat::Csv => Sink::Csv(Box::new(csv::WriterBuilder::new().has_headers(headers).from_writer(sink))),
+            RejectsFormat::Jsonl => Sink::Jsonl(sink),
+        }
+    }
+
+    pub fn write(&mut self, reject: &Reject) -> Result {
+        match &mut self.sink {
+            Sink::Csv(writer) => writer.serialize(reject)?,
+            Sink::Jsonl(writer) => reject.write_json(writer)?,
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result {
+        match &mut self.sink {
+            Sink::Csv(writer) => writer.flush()?,
+            Sink::Jsonl(writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+
+    /// Length of the report written so far, known only when it's written to a file
+    pub(crate) fn position(&mut self) -> std::result::Result<Option<u64>, TransactionSystemError> {
+        self.flush()?;
+        match &mut self.file {
+            Some(file) => Ok(Some(file.stream_position()?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Cuts the report in a file back to the length, to continue writing from there
+    pub(crate) fn truncate(&mut self, position: u64) -> Result {
+        let Some(file) = &mut self.file else {
+            return Ok(());
+        };
+        let sink = std::mem::replace(&mut self.sink, Sink::Jsonl(Box::new(io::sink())));
+        let mut writer = match sink {
+            Sink::Csv(writer) => writer.into_inner().map_err(|error| io::Error::new(error.error().kind(), error.error().to_string()))?,
+            Sink::Jsonl(writer) => writer,
+        };
+        writer.flush()?;
+        drop(writer);
+        file.set_len(position)?;
+        file.seek(SeekFrom::Start(position))?;
+        self.sink = Self::sink(self.format, Box::new(BufWriter::new(file.try_clone()?)), position == 0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::path::Path;
+    use std::rc::Rc;
+    use indoc::indoc;
+    use rust_decimal::Decimal;
+    use crate::account::Outcome;
+    use super::{Reject, RejectsFormat, RejectsWriter};
+
+    /// Shares the written buffer, as the writer owns its sink
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).expect("failed to stringify the buffer")
+        }
+    }
+
+    fn write_rejects(format: RejectsFormat) -> String {
+        let buffer = SharedBuffer::default();
+        let mut writer = RejectsWriter::new(format, Box::new(buffer.clone()));
+        writer.write(&Reject::new("withdrawal".to_owned(), 1, 7, Some(Decimal::new(15000, 4)), "in.csv", 3, "INSUFFICIENT_FUNDS")).expect("failed to write");
+        writer.write(&Reject::new("dispute".to_owned(), 2, 9, None, "in.csv", 5, "UNKNOWN_TX")).expect("failed to write");
+        let outcome = Outcome::HeldPartially { shortfall: Decimal::new(25, 1) };
+        writer.write(&Reject::new("dispute".to_owned(), 2, 8, None, "in.csv", 6, "HELD_PARTIALLY").with_outcome(outcome)).expect("failed to write");
+        writer.flush().expect("failed to flush");
+        drop(writer);
+
+        buffer.contents()
+    }
+
+    #[test]
+    fn csv_rejects() {
+        assert_eq!(write_rejects(RejectsFormat::Csv), indoc!("
+            type,client,tx,amount,input,line,reason,receivable
+            withdrawal,1,7,1.5000,in.csv,3,INSUFFICIENT_FUNDS,
+            dispute,2,9,,in.csv,5,UNKNOWN_TX,
+            dispute,2,8,,in.csv,6,HELD_PARTIALLY,2.5
+        "));
+    }
+
+    #[test]
+    fn jsonl_rejects() {
+        assert_eq!(write_rejects(RejectsFormat::Jsonl), indoc!(r#"
+            {"type":"withdrawal","client":1,"tx":7,"amount":"1.5000","input":"in.csv","line":3,"reason":"INSUFFICIENT_FUNDS","receivable":null}
+            {"type":"dispute","client":2,"tx":9,"amount":null,"input":"in.csv","line":5,"reason":"UNKNOWN_TX","receivable":null}
+            {"type":"dispute","client":2,"tx":8,"amount":null,"input":"in.csv","line":6,"reason":"HELD_PARTIALLY","receivable":"2.5"}
+        "#));
+    }
+
+    #[test]
+    fn jsonl_escaped_input() {
+        let buffer = SharedBuffer::default();
+        let mut writer = RejectsWriter::new(RejectsFormat::Jsonl, Box::new(buffer.clone()));
+        writer.write(&Reject::new("dispute".to_owned(), 2, 9, None, "C:\\input \"é\"\n\u{1}", 5, "UNKNOWN_TX")).expect("failed to write");
+        drop(writer);
+
+        let report = buffer.contents();
+        assert!(report.contains(r#","input":"C:\\input \"é\"\n\u0001","#), "unexpected {}", report);
+    }
+
+    #[test]
+    fn format_from_path() {
+        assert_eq!(RejectsFormat::from_path(Path::new("rejects.jsonl")), RejectsFormat::Jsonl);
+        assert_eq!(RejectsFormat::from_path(Path::new("rejects.ndjson")), RejectsFormat::Jsonl);
+        assert_eq!(RejectsFormat::from_path(Path::new("rejects.csv")), RejectsFormat::Csv);
+    }
+}
@@ -0,0 +1,68 @@
+use std::io::{BufRead, Write};
+use log::debug;
+
+use crate::account::Account;
+use crate::codec::Fields;
+use crate::errors::TransactionSystemError;
+use crate::result::Result;
+use crate::store::AccountStore;
+
+/// Version of the snapshot format, raised on every incompatible change of it;
+/// version 2 added the number of processed instructions
+pub const SNAPSHOT_VERSION: u32 = 2;
+
+const HEADER: &str = "snapshot";
+const TRAILER: &str = "snapshot-end";
+
+/// Writes all the accounts of the store, each with its whole transaction history, between the header
+/// with the format version and the trailer with the number of accounts, which detects truncated snapshots;
+/// the header holds the number of instructions processed so far as well
+pub fn write(store: &mut dyn AccountStore, sequence: u64, sink: &mut impl Write) -> Result {
+    writeln!(sink, "{} {} {}", HEADER, SNAPSHOT_VERSION, sequence)?;
+    let mut accounts: u64 = 0;
+    store.for_each(&mut |client, account| {
+        accounts += 1;
+        account.encode(client, sink)
+    })?;
+    writeln!(sink, "{} {}", TRAILER, accounts)?;
+    sink.flush()?;
+    debug!("Snapshot of {} accounts written", accounts);
+    Ok(())
+}
+
+/// Reads the snapshot written with `write()`, handing over every account to the visitor;
+/// gives the number of instructions processed, zero for snapshots of version 1
+pub fn read(source: &mut impl BufRead, visitor: &mut dyn FnMut(u16, Account) -> Result) -> std::result::Result<u64, TransactionSystemError> {
+    let mut line = String::new();
+    source.read_line(&mut line)?;
+    let mut fields = Fields::new(&line);
+    fields.expect(HEADER)?;
+    let sequence = match fields.parse()? {
+        1 => 0,
+        SNAPSHOT_VERSION => fields.parse()?,
+        version => return Err(fields.corrupted(&format!("unsupported snapshot version {}", version))),
+    };
+
+    let mut accounts: u64 = 0;
+    loop {
+        line.clear();
+        if source.read_line(&mut line)? == 0 {
+            return Err(Fields::new(&line).corrupted("unexpected end of snapshot"));
+        }
+        if line.starts_with(TRAILER) {
+            break;
+        }
+        let (client, account) = Account::decode_rest(&line, source)?;
+        visitor(client, account)?;
+        accounts += 1;
+    }
+
+    let mut fields = Fields::new(&line);
+    fields.expect(TRAILER)?;
+    let expected: u64 = fields.parse()?;
+    if expected != accounts {
+        return Err(fields.corrupted(&format!("{} accounts read instead of {}", accounts, expected)));
+    }
+    debug!("Snapshot of {} accounts read", accounts);
+    Ok(sequence)
+}
@@ -0,0 +1,432 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::str;
+use csv::{ByteRecord, ErrorKind, Position, ReaderBuilder, Trim};
+use parse_display::{Display, FromStr};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use serde_json::value::RawValue;
+
+use crate::compression::{self, Compression};
+use crate::errors::TransactionSystemError;
+use crate::instructions::workaround;
+
+/// Input name standing for the standard input
+pub const STDIN: &str = "-";
+
+#[derive(Debug, Display, FromStr, Default, Clone, Copy, PartialEq, Eq)]
+#[display(style = "lowercase")]
+pub enum InputFormat {
+    #[default]
+    Csv,
+    Jsonl,
+}
+
+impl InputFormat {
+    /// Recognizes JSON Lines by `.jsonl` or `.ndjson` extension, anything else is CSV;
+    /// the extension of compression, like `.gz`, is looked behind
+    pub fn from_path(path: &Path) -> Self {
+        let path = match Compression::from_path(path) {
+            Compression::None => path,
+            _ => path.file_stem().map_or(path, Path::new),
+        };
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("jsonl") | Some("ndjson") => InputFormat::Jsonl,
+            _ => InputFormat::Csv,
+        }
+    }
+}
+
+/// Input row with its position, holding the instruction or the reason it couldn't be read
+#[derive(Debug)]
+pub struct Row {
+    pub position: Option<Position>,
+    pub instruction: Result<workaround::Instruction, TransactionSystemError>,
+}
+
+/// Source of the instructions read row by row
+pub trait InstructionSource {
+    /// Name of the input for logs and reports
+    fn name(&self) -> &str;
+
+    /// Gives the next row or `None` at the end of input; errors preventing further reading
+    /// are returned directly, while malformed rows are reported within the `Row`
+    fn next_row(&mut self) -> Result<Option<Row>, TransactionSystemError>;
+}
+
+/// Indexes of the columns named in the header row; any other columns are ignored
+#[derive(Debug, Default)]
+struct Columns {
+    typ: Option<usize>,
+    client: Option<usize>,
+    tx: Option<usize>,
+    amount: Option<usize>,
+    operator: Option<usize>,
+    reason: Option<usize>,
+    timestamp: Option<usize>,
+}
+
+impl Columns {
+    /// Columns of the record the members of a JSON Lines object are put into, in this order
+    const MEMBERS: Columns = Columns {
+        typ: Some(0), client: Some(1), tx: Some(2), amount: Some(3), operator: Some(4), reason: Some(5), timestamp: Some(6),
+    };
+
+    fn new(headers: &ByteRecord) -> Self {
+        let mut columns = Self::default();
+        for (index, header) in headers.iter().enumerate() {
+            let column = match header {
+                b"type" => &mut columns.typ,
+                b"client" => &mut columns.client,
+                b"tx" => &mut columns.tx,
+                b"amount" => &mut columns.amount,
+                b"operator" => &mut columns.operator,
+                b"reason" => &mut columns.reason,
+                b"timestamp" => &mut columns.timestamp,
+                _ => continue,
+            };
+            column.get_or_insert(index);
+        }
+        columns
+    }
+
+    /// Decodes the row straight from its bytes, without serde nor any allocation unless it fails;
+    /// missing and empty fields are the same, as with deserialization
+    fn decode(&self, record: &ByteRecord) -> Result<workaround::Instruction, TransactionSystemError> {
+        let typ = Self::field(record, self.typ, "type")?;
+        let typ = workaround::InstructionType::from_bytes(typ).ok_or_else(|| {
+            Self::malformed("type", typ)
+        })?;
+        Ok(workaround::Instruction::new(
+            typ,
+            Self::required(record, self.client, "client")?,
+            Self::required(record, self.tx, "tx")?,
+            Self::optional_with(record, self.amount, "amount", Self::amount)?,
+            Self::optional(record, self.operator, "operator")?,
+            Self::optional(record, self.reason, "reason")?,
+            Self::optional(record, self.timestamp, "timestamp")?,
+        ))
+    }
+
+    fn field<'r>(record: &'r ByteRecord, column: Option<usize>, name: &str) -> Result<&'r [u8], TransactionSystemError> {
+        match column.and_then(|column| record.get(column)) {
+            Some(field) if !field.is_empty() => Ok(field),
+            _ => Err(TransactionSystemError::RowError { message: format!("missing {}", name) }),
+        }
+    }
+
+    fn required<T: str::FromStr>(record: &ByteRecord, column: Option<usize>, name: &str) -> Result<T, TransactionSystemError> {
+        let field = Self::field(record, column, name)?;
+        str::from_utf8(field).ok().and_then(|text| text.parse().ok()).ok_or_else(|| Self::malformed(name, field))
+    }
+
+    fn optional<T: str::FromStr>(record: &ByteRecord, column: Option<usize>, name: &str) -> Result<Option<T>, TransactionSystemError> {
+        Self::optional_with(record, column, name, |text| text.parse().ok())
+    }
+
+    fn optional_with<T>(record: &ByteRecord, column: Option<usize>, name: &str, parse: impl Fn(&str) -> Option<T>)
+        -> Result<Option<T>, TransactionSystemError> {
+        match column.and_then(|column| record.get(column)) {
+            None | Some(b"") => Ok(None),
+            Some(field) => str::from_utf8(field).ok().and_then(parse).map(Some).ok_or_else(|| Self::malformed(name, field)),
+        }
+    }
+
+    /// Amounts are normalized, the same as they used to be through floats, though without losing precision
+    fn amount(text: &str) -> Option<Decimal> {
+        let amount = match text.parse::<Decimal>() {
+            Ok(amount) => amount,
+            Err(_) if text.contains(['e', 'E']) => Decimal::from_scientific(text).ok()?,
+            Err(_) => return None,
+        };
+        Some(amount.normalize())
+    }
+
+    fn malformed(name: &str, field: &[u8]) -> TransactionSystemError {
+        TransactionSystemError::RowError {
+            message: format!("invalid {} {:?}", name, String::from_utf8_lossy(field)),
+        }
+    }
+}
+
+/// CSV input with a header row, trimmed and tolerating rows of varying length
+pub struct CsvSource<R: Read> {
+    name: String,
+    reader: csv::Reader<R>,
+    columns: Columns,
+    record: ByteRecord,
+}
+
+impl<R: Read> CsvSource<R> {
+    pub fn new(name: impl Into<String>, input: R) -> Result<Self, TransactionSystemError> {
+        let mut reader = ReaderBuilder::new()
+            .flexible(true)
+            .trim(Trim::All)
+            .from_reader(input);
+        let columns = Columns::new(reader.byte_headers()?);
+        Ok(Self { name: name.into(), reader, columns, record: ByteRecord::new() })
+    }
+}
+
+impl<R: Read> InstructionSource for CsvSource<R> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn next_row(&mut self) -> Result<Option<Row>, TransactionSystemError> {
+        match self.reader.read_byte_record(&mut self.record) {
+            Ok(false) => Ok(None),
+            Ok(true) => Ok(Some(Row {
+                position: self.record.position().cloned(),
+                instruction: self.columns.decode(&self.record),
+            })),
+            Err(error) if matches!(error.kind(), ErrorKind::Io(_)) => Err(error.into()),
+            Err(error) => Ok(Some(Row {
+                position: error.position().cloned(),
+                instruction: Err(error.into()),
+            })),
+        }
+    }
+}
+
+/// Members of a JSON Lines object known as columns, kept as their raw JSON text; others are ignored
+#[derive(Deserialize)]
+struct Members<'a> {
+    #[serde(rename = "type", borrow)]
+    typ: Option<&'a RawValue>,
+    #[serde(borrow)]
+    client: Option<&'a RawValue>,
+    #[serde(borrow)]
+    tx: Option<&'a RawValue>,
+    #[serde(borrow)]
+    amount: Option<&'a RawValue>,
+    #[serde(borrow)]
+    operator: Option<&'a RawValue>,
+    #[serde(borrow)]
+    reason: Option<&'a RawValue>,
+    #[serde(borrow)]
+    timestamp: Option<&'a RawValue>,
+}
+
+/// JSON Lines input, one flat object per line with the same field names as the CSV header;
+/// blank lines are ignored
+pub struct JsonLinesSource<R: BufRead> {
+    name: String,
+    input: R,
+    line: String,
+    lines: u64,
+    position: Position,
+    record: ByteRecord,
+}
+
+impl<R: BufRead> JsonLinesSource<R> {
+    pub fn new(name: impl Into<String>, input: R) -> Self {
+        Self { name: name.into(), input, line: String::new(), lines: 0, position: Position::new(), record: ByteRecord::new() }
+    }
+
+    /// Puts the members into the record as the text of their values, numbers exactly as written, so
+    /// they're decoded the same as CSV fields; `null` is the same as a missing member
+    fn parse(&mut self) -> Result<workaround::Instruction, TransactionSystemError> {
+        let malformed = |error: serde_json::Error| TransactionSystemError::JSONError { message: error.to_string() };
+        let members: Members = serde_json::from_str(&self.line).map_err(malformed)?;
+
+        self.record.clear();
+        for value in [members.typ, members.client, members.tx, members.amount, members.operator, members.reason, members.timestamp] {
+            match value.map(RawValue::get) {
+                None | Some("null") => self.record.push_field(b""),
+                Some(text) if text.starts_with('"') => {
+                    let string: String = serde_json::from_str(text).map_err(malformed)?;
+                    self.record.push_field(string.as_bytes());
+                },
+                Some(text) if text.starts_with(['{', '[']) => {
+                    return Err(TransactionSystemError::JSONError { message: format!("unsupported value {}", text) });
+                },
+                Some(text) => self.record.push_field(text.as_bytes()),
+            }
+        }
+        Columns::MEMBERS.decode(&self.record)
+    }
+}
+
+impl<R: BufRead> InstructionSource for JsonLinesSource<R> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn next_row(&mut self) -> Result<Option<Row>, TransactionSystemError> {
+        loop {
+            let byte = self.position.byte();
+            self.line.clear();
+            let length = self.input.read_line(&mut self.line)?;
+            if length == 0 {
+                return Ok(None);
+            }
+
+            self.lines += 1;
+            self.position.set_line(self.lines);
+            if self.line.trim().is_empty() {
+                self.position.set_byte(byte + length as u64);
+                continue;
+            }
+
+            let instruction = self.parse();
+            let position = self.position.clone();
+            let record = self.position.record() + 1;
+            self.position.set_byte(byte + length as u64).set_record(record);
+            return Ok(Some(Row { position: Some(position), instruction }));
+        }
+    }
+}
+
+/// Opens the named input file, or the standard input for `-`, as the source of given format;
+/// without the format it's recognized from the file extension, the standard input defaults to CSV.
+/// Compressed input is decompressed on the fly
+pub fn open(input: &str, format: Option<InputFormat>) -> Result<Box<dyn InstructionSource>, TransactionSystemError> {
+    let (name, data) = if input == STDIN {
+        ("<stdin>", compression::decompress(input, io::stdin())?)
+    } else {
+        (input, compression::decompress(input, File::open(input)?)?)
+    };
+
+    Ok(match format.unwrap_or_else(|| InputFormat::from_path(Path::new(input))) {
+        InputFormat::Csv => Box::new(CsvSource::new(name, data)?),
+        InputFormat::Jsonl => Box::new(JsonLinesSource::new(name, BufReader::new(data))),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use std::path::Path;
+    use indoc::indoc;
+
+    use super::{CsvSource, InputFormat, InstructionSource, JsonLinesSource};
+
+    #[test]
+    fn input_format() {
+        assert_eq!(InputFormat::from_path(Path::new("transactions.ndjson")), InputFormat::Jsonl);
+        assert_eq!(InputFormat::from_path(Path::new("transactions.jsonl")), InputFormat::Jsonl);
+        assert_eq!(InputFormat::from_path(Path::new("transactions.csv")), InputFormat::Csv);
+        assert_eq!(InputFormat::from_path(Path::new("transactions.ndjson.zst")), InputFormat::Jsonl);
+        assert_eq!(InputFormat::from_path(Path::new("transactions.csv.gz")), InputFormat::Csv);
+        assert_eq!("jsonl".parse::<InputFormat>().ok(), Some(InputFormat::Jsonl));
+    }
+
+    #[test]
+    fn json_lines() {
+        let mut source = JsonLinesSource::new("test.jsonl", Cursor::new(indoc!(r#"
+            {"type": "deposit", "client": 1, "tx": 1, "amount": 1.5}
+
+            {"type": "dispute", "client": 1, "tx": 1, "amount": null}
+            {"type": "deposit", "client": 1
+            {"type": "deposit", "client": 1, "tx": "x", "amount": "2.0"}
+        "#)));
+
+        let row = source.next_row().expect("failed to read").expect("missing row");
+        let instruction = row.instruction.expect("failed to parse");
+        assert_eq!((instruction.client(), instruction.tx()), (1, 1));
+        assert_eq!(instruction.amount(), Some("1.5".parse().unwrap()));
+
+        let row = source.next_row().expect("failed to read").expect("missing row");
+        assert_eq!(row.position.map(|position| (position.line(), position.byte())), Some((3, 58)));
+        assert_eq!(row.instruction.expect("failed to parse").amount(), None);
+
+        let row = source.next_row().expect("failed to read").expect("missing row");
+        assert_eq!(row.instruction.expect_err("malformed row accepted").code(), "MALFORMED_ROW");
+
+        let row = source.next_row().expect("failed to read").expect("missing row");
+        assert_eq!(row.position.map(|position| position.line()), Some(5));
+        assert_eq!(row.instruction.expect_err("invalid row accepted").code(), "MALFORMED_ROW");
+
+        assert!(source.next_row().expect("failed to read").is_none());
+        assert_eq!(source.name(), "test.jsonl");
+    }
+
+    #[test]
+    fn json_lines_exact_amounts() {
+        let mut source = JsonLinesSource::new("test.jsonl", Cursor::new(indoc!(r#"
+            {"type": "deposit", "client": 1, "tx": 1, "amount": 1234567890123.1234, "note": {"ignored": [1]}}
+            {"type": "deposit", "client": 1, "tx": 2, "amount": "79228162514264337593543950335"}
+            {"type": "deposit", "client": 1, "tx": 3, "amount": 1.50e1}
+            {"type": "depo\u0073it", "client": 1, "tx": 4, "amount": 0.1}
+            {"type": "deposit", "client": 1, "tx": 5, "amount": [1]}
+        "#)));
+
+        let amounts: Vec<_> = std::iter::from_fn(|| source.next_row().expect("failed to read")).map(|row| match row.instruction {
+            Ok(instruction) => instruction.amount().map(|amount| amount.to_string()).unwrap_or_default(),
+            Err(error) => error.code().to_owned(),
+        }).collect();
+        assert_eq!(amounts, ["1234567890123.1234", "79228162514264337593543950335", "15", "0.1", "MALFORMED_ROW"]);
+    }
+
+    #[test]
+    fn json_lines_malformed() {
+        for line in [r#"{"type": "deposit""#, r#"{"type": ["deposit"]}"#, r#"{"type": nope}"#, r#"{"type": "deposit", "client": 1, "tx": 1} 1"#,
+                     r#"["deposit"]"#, r#"{"type": "deposit", "type": "withdrawal", "client": 1, "tx": 1}"#] {
+            let mut source = JsonLinesSource::new("test.jsonl", Cursor::new(line));
+            let row = source.next_row().expect("failed to read").expect("missing row");
+            assert_eq!(row.instruction.expect_err("malformed row accepted").code(), "MALFORMED_ROW", "accepted {}", line);
+        }
+    }
+
+    #[test]
+    fn csv_fast_path() {
+        let mut source = CsvSource::new("test.csv", indoc!("
+            amount, tx,   type, client, operator, reason
+              1.50,  1, deposit,     1
+             1e2  ,  2, deposit,     1,         ,
+                  ,  3,  freeze,     2,        7,  fraud
+               1.0,  4, Deposit,     1
+               1.0,   , deposit,     1
+               1.0,  5, deposit, 70000
+        ").as_bytes()).expect("failed to read headers");
+
+        let row = source.next_row().expect("failed to read").expect("missing row");
+        let instruction = row.instruction.expect("failed to parse");
+        assert_eq!((instruction.client(), instruction.tx()), (1, 1));
+        assert_eq!(instruction.amount().map(|amount| amount.to_string()).as_deref(), Some("1.5"));
+
+        let row = source.next_row().expect("failed to read").expect("missing row");
+        assert_eq!(row.instruction.expect("failed to parse").amount().map(|amount| amount.to_string()).as_deref(), Some("100"));
+
+        let row = source.next_row().expect("failed to read").expect("missing row");
+        let instruction = row.instruction.expect("failed to parse");
+        assert_eq!((instruction.typ().to_string().as_str(), instruction.amount()), ("freeze", None));
+
+        for line in 5..=7 {
+            let row = source.next_row().expect("failed to read").expect("missing row");
+            assert_eq!(row.position.map(|position| position.line()), Some(line));
+            assert_eq!(row.instruction.expect_err("malformed row accepted").code(), "MALFORMED_ROW");
+        }
+
+        assert!(source.next_row().expect("failed to read").is_none());
+    }
+
+    #[test]
+    fn timestamp_column() {
+        let timestamps = |source: &mut dyn InstructionSource| -> Vec<Option<String>> {
+            std::iter::from_fn(|| source.next_row().expect("failed to read")).map(|row| match row.instruction {
+                Ok(instruction) => instruction.timestamp().map(|timestamp| timestamp.to_string()),
+                Err(error) => Some(error.code().to_owned()),
+            }).collect()
+        };
+
+        let mut source = CsvSource::new("test.csv", indoc!("
+               type, client, tx, amount, timestamp
+            deposit,      1,  1,    1.0, 2023-04-01T12:30:00+02:00
+            dispute,      1,  1,       , 1680345000
+            dispute,      1,  1,       ,
+            dispute,      1,  1,       , 2023-04-31T00:00:00Z
+        ").as_bytes()).expect("failed to read headers");
+        assert_eq!(timestamps(&mut source), [Some("2023-04-01T10:30:00Z".to_owned()), Some("2023-04-01T10:30:00Z".to_owned()),
+                                             None, Some("MALFORMED_ROW".to_owned())]);
+
+        let mut source = JsonLinesSource::new("test.jsonl", Cursor::new(indoc!(r#"
+            {"type": "deposit", "client": 1, "tx": 1, "amount": 1.5, "timestamp": "2023-04-01T10:30:00Z"}
+            {"type": "dispute", "client": 1, "tx": 1, "timestamp": 1680345000}
+            {"type": "dispute", "client": 1, "tx": 1}
+        "#)));
+        assert_eq!(timestamps(&mut source), [Some("2023-04-01T10:30:00Z".to_owned()), Some("2023-04-01T10:30:00Z".to_owned()), None]);
+    }
+}
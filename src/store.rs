@@ -0,0 +1,554 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use log::debug;
+
+use crate::account::Account;
+use crate::codec::Fields;
+use crate::errors::TransactionSystemError;
+use crate::result::Result;
+
+/// Storage of clients' accounts behind the `Register`
+pub trait AccountStore: Debug + Send {
+    /// Gives the account of the client, created if it doesn't exist yet
+    fn get_or_create(&mut self, client: u16) -> std::result::Result<&mut Account, TransactionSystemError>;
+
+    /// Gives the account of the client like `get_or_create()`, to apply a deposit or withdrawal to; as they
+    /// don't look into the history, the store may leave it out until the account is asked for with it
+    fn get_or_create_funds(&mut self, client: u16) -> std::result::Result<&mut Account, TransactionSystemError> {
+        self.get_or_create(client)
+    }
+
+    /// Visits all the stored accounts, in no particular order
+    fn for_each(&mut self, visitor: &mut dyn FnMut(u16, &Account) -> Result) -> Result;
+
+    /// Makes the accounts durable, if the store is capable of that
+    fn persist(&mut self) -> Result;
+
+    /// Removes all the accounts
+    fn clear(&mut self) -> Result;
+
+    /// Takes all the accounts out of memory, handing each of them over to the visitor to be put back
+    /// later; a durable store persists them first and keeps them so, not to lose any if that never happens
+    fn drain(&mut self, visitor: &mut dyn FnMut(u16, Account) -> Result) -> Result;
+
+    /// Whether all the accounts are held in memory, so they can be drained without growing it
+    fn in_memory(&self) -> bool;
+}
+
+/// Store keeping all the accounts in memory
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    accounts: HashMap<u16, Account>,
+}
+
+impl AccountStore for MemoryStore {
+    fn get_or_create(&mut self, client: u16) -> std::result::Result<&mut Account, TransactionSystemError> {
+        Ok(self.accounts.entry(client).or_default())
+    }
+
+    fn for_each(&mut self, visitor: &mut dyn FnMut(u16, &Account) -> Result) -> Result {
+        self.accounts.iter().try_for_each(|(client, account)| visitor(*client, account))
+    }
+
+    fn persist(&mut self) -> Result {
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result {
+        self.accounts.clear();
+        Ok(())
+    }
+
+    fn drain(&mut self, visitor: &mut dyn FnMut(u16, Account) -> Result) -> Result {
+        self.accounts.drain().try_for_each(|(client, account)| visitor(client, account))
+    }
+
+    fn in_memory(&self) -> bool {
+        true
+    }
+}
+
+/// Segments written in the data file for a client before its whole account is written instead
+const MAX_SEGMENTS: usize = 16;
+const DATA_PREFIX: &str = "accounts-";
+const DATA_EXTENSION: &str = "data";
+const INDEX_FILE: &str = "accounts.index";
+
+/// Store keeping the accounts in an append-only data file within the directory, with a bounded number
+/// of them cached in memory
+///
+/// The least recently used account is written out when the cache is full, as a segment of the data file
+/// holding its funds and only the transactions changed since it was last written. The index gives
+/// the segments of every client, read together when its account is loaded, and the length of the data;
+/// once a client has `MAX_SEGMENTS` of them its whole account is written instead, superseding them.
+/// The index is written by `persist()`, so segments written after it are cut off when the store is
+/// opened again, and the data is rewritten into a file of the next generation without superseded
+/// segments once they take more than half of it.
+#[derive(Debug)]
+pub struct DiskStore {
+    directory: PathBuf,
+    capacity: usize,
+    data: DataFile,
+    cache: HashMap<u16, Cached>,
+    /// Cached clients by the time of their last use, the least recent first
+    recency: BTreeMap<u64, u16>,
+    clock: u64,
+}
+
+#[derive(Debug, Default)]
+struct Cached {
+    account: Account,
+    used: u64,
+    dirty: bool,
+    /// Loaded without the history, holding only the transactions added since
+    partial: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    offset: u64,
+    length: u64,
+}
+
+#[derive(Debug)]
+struct DataFile {
+    file: File,
+    generation: u64,
+    length: u64,
+    superseded: u64,
+    segments: HashMap<u16, Vec<Segment>>,
+}
+
+impl DataFile {
+    fn name(generation: u64) -> String {
+        format!("{}{}.{}", DATA_PREFIX, generation, DATA_EXTENSION)
+    }
+
+    /// Opens the data file the index refers to, reading the index of `store <generation> <length> <superseded>`
+    /// line followed by `client <client> <segments> <offset> <length>...` lines
+    fn open(directory: &Path) -> std::result::Result<Self, TransactionSystemError> {
+        let mut lines = match File::open(directory.join(INDEX_FILE)) {
+            Ok(file) => BufReader::new(file).lines(),
+            Err(error) if error.kind() == ErrorKind::NotFound => return Self::create(directory, 0),
+            Err(error) => return Err(error.into()),
+        };
+        let header = lines.next().transpose()?.unwrap_or_default();
+        let mut fields = Fields::new(&header);
+        fields.expect("store")?;
+        let (generation, length, superseded) = (fields.parse()?, fields.parse()?, fields.parse()?);
+
+        let mut segments = HashMap::new();
+        for line in lines {
+            let line = line?;
+            let mut fields = Fields::new(&line);
+            fields.expect("client")?;
+            let client: u16 = fields.parse()?;
+            let count: usize = fields.parse()?;
+            let mut stored = Vec::with_capacity(count);
+            for _ in 0..count {
+                stored.push(Segment { offset: fields.parse()?, length: fields.parse()? });
+            }
+            segments.insert(client, stored);
+        }
+
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(directory.join(Self::name(generation)))?;
+        // Segments written after the index are cut off, nothing refers to them
+        file.set_len(length)?;
+        Ok(Self { file, generation, length, superseded, segments })
+    }
+
+    /// Creates the empty data file of the generation
+    fn create(directory: &Path, generation: u64) -> std::result::Result<Self, TransactionSystemError> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(directory.join(Self::name(generation)))?;
+        Ok(Self { file, generation, length: 0, superseded: 0, segments: HashMap::new() })
+    }
+
+    /// Appends the changes of the account since it was last written, or the whole of it when it has
+    /// too many segments already, unless it's `partial`, without the history
+    fn save(&mut self, client: u16, account: &mut Account, partial: bool) -> Result {
+        let segments = self.segments.entry(client).or_default();
+        let mut bytes = Vec::new();
+        let whole = account.encode_changes(client, !partial && segments.len() >= MAX_SEGMENTS, &mut bytes)?;
+        self.file.seek(SeekFrom::Start(self.length))?;
+        self.file.write_all(&bytes)?;
+
+        if whole {
+            self.superseded += segments.iter().map(|segment| segment.length).sum::<u64>();
+            segments.clear();
+        }
+        segments.push(Segment { offset: self.length, length: bytes.len() as u64 });
+        self.length += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Reads all the segments of the account, or only the last one without the history, holding
+    /// the current funds
+    fn load(&mut self, client: u16, history: bool) -> std::result::Result<Option<Account>, TransactionSystemError> {
+        let Some(segments) = self.segments.get(&client) else {
+            return Ok(None);
+        };
+        let segments = if history { &segments[..] } else { &segments[segments.len().saturating_sub(1)..] };
+        let mut bytes = Vec::new();
+        for segment in segments {
+            self.file.seek(SeekFrom::Start(segment.offset))?;
+            Read::by_ref(&mut self.file).take(segment.length).read_to_end(&mut bytes)?;
+        }
+        let decoded = if history { Account::decode_changes(&mut bytes.as_slice())? } else { Account::decode_funds(&mut bytes.as_slice())? };
+        match decoded {
+            Some((stored, account)) if stored == client => Ok(Some(account)),
+            Some((stored, _)) => Err(TransactionSystemError::CorruptedDataError {
+                message: format!("account of client {} stored for client {}", stored, client),
+            }),
+            None => Ok(None),
+        }
+    }
+
+    /// Clients of the accounts stored, in their order
+    fn clients(&self) -> Vec<u16> {
+        let mut clients: Vec<u16> = self.segments.keys().copied().collect();
+        clients.sort_unstable();
+        clients
+    }
+}
+
+impl DiskStore {
+    pub fn open(directory: impl Into<PathBuf>, capacity: usize) -> std::result::Result<Self, TransactionSystemError> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+        let data = DataFile::open(&directory)?;
+        Ok(Self { directory, capacity: capacity.max(1), data, cache: HashMap::new(), recency: BTreeMap::new(), clock: 0 })
+    }
+
+    /// Writes the index into temporary file renamed afterwards, so the index is never half-written
+    fn write_index(&self) -> Result {
+        let path = self.directory.join(INDEX_FILE);
+        let temporary = path.with_extension("tmp");
+        let mut sink = BufWriter::new(File::create(&temporary)?);
+        writeln!(sink, "store {} {} {}", self.data.generation, self.data.length, self.data.superseded)?;
+        for client in self.data.clients() {
+            write!(sink, "client {} {}", client, self.data.segments[&client].len())?;
+            for segment in &self.data.segments[&client] {
+                write!(sink, " {} {}", segment.offset, segment.length)?;
+            }
+            writeln!(sink)?;
+        }
+        sink.flush()?;
+        sink.get_ref().sync_all()?;
+        fs::rename(temporary, path)?;
+        Ok(())
+    }
+
+    /// Copies the segments not superseded into the data file of the next generation, which the index
+    /// written next refers to, and removes the current one afterwards
+    fn compact(&mut self) -> std::result::Result<PathBuf, TransactionSystemError> {
+        let mut compacted = DataFile::create(&self.directory, self.data.generation + 1)?;
+        let mut sink = BufWriter::new(&mut compacted.file);
+        for client in self.data.clients() {
+            let mut segments = Vec::new();
+            for segment in &self.data.segments[&client] {
+                self.data.file.seek(SeekFrom::Start(segment.offset))?;
+                io::copy(&mut Read::by_ref(&mut self.data.file).take(segment.length), &mut sink)?;
+                segments.push(Segment { offset: compacted.length, length: segment.length });
+                compacted.length += segment.length;
+            }
+            compacted.segments.insert(client, segments);
+        }
+        sink.flush()?;
+        drop(sink);
+        debug!("Store compacted from {} to {} bytes", self.data.length, compacted.length);
+
+        let previous = self.directory.join(DataFile::name(self.data.generation));
+        self.data = compacted;
+        Ok(previous)
+    }
+
+    fn evict(&mut self) -> Result {
+        let Some((_, client)) = self.recency.pop_first() else {
+            return Ok(());
+        };
+        if let Some(mut cached) = self.cache.remove(&client).filter(|cached| cached.dirty) {
+            debug!("Evicting account of client {} to disk", client);
+            self.data.save(client, &mut cached.account, cached.partial)?;
+        }
+        Ok(())
+    }
+
+    /// Gives the cached account of the client, loading it first, with or without the history
+    fn cached(&mut self, client: u16, history: bool) -> std::result::Result<&mut Account, TransactionSystemError> {
+        self.clock += 1;
+        if let Some(cached) = self.cache.get_mut(&client) {
+            self.recency.remove(&cached.used);
+            cached.used = self.clock;
+        } else {
+            while self.cache.len() >= self.capacity {
+                self.evict()?;
+            }
+            let account = self.data.load(client, history)?;
+            let partial = !history && account.is_some();
+            self.cache.insert(client, Cached { account: account.unwrap_or_default(), used: self.clock, dirty: false, partial });
+        }
+        self.recency.insert(self.clock, client);
+        if history {
+            self.complete(client)?;
+        }
+        let cached = self.cache.entry(client).or_default();
+        // Handed over to be changed, so it's written out once evicted
+        cached.dirty = true;
+        Ok(&mut cached.account)
+    }
+
+    /// Loads the history of the account cached without it, writing out the transactions added since
+    /// to be read back along the rest
+    fn complete(&mut self, client: u16) -> Result {
+        if let Some(cached) = self.cache.get_mut(&client).filter(|cached| cached.partial) {
+            if cached.dirty {
+                self.data.save(client, &mut cached.account, true)?;
+            }
+            cached.account = self.data.load(client, true)?.unwrap_or_default();
+            cached.dirty = false;
+            cached.partial = false;
+        }
+        Ok(())
+    }
+}
+
+impl AccountStore for DiskStore {
+    fn get_or_create(&mut self, client: u16) -> std::result::Result<&mut Account, TransactionSystemError> {
+        self.cached(client, true)
+    }
+
+    fn get_or_create_funds(&mut self, client: u16) -> std::result::Result<&mut Account, TransactionSystemError> {
+        self.cached(client, false)
+    }
+
+    fn for_each(&mut self, visitor: &mut dyn FnMut(u16, &Account) -> Result) -> Result {
+        let partial: Vec<u16> = self.cache.iter().filter(|(_, cached)| cached.partial).map(|(client, _)| *client).collect();
+        for client in partial {
+            self.complete(client)?;
+        }
+        for (client, cached) in &self.cache {
+            visitor(*client, &cached.account)?;
+        }
+        for client in self.data.clients() {
+            if !self.cache.contains_key(&client) {
+                if let Some(account) = self.data.load(client, true)? {
+                    visitor(client, &account)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn persist(&mut self) -> Result {
+        for (client, cached) in self.cache.iter_mut().filter(|(_, cached)| cached.dirty) {
+            self.data.save(*client, &mut cached.account, cached.partial)?;
+            cached.dirty = false;
+        }
+        let superseded = if self.data.superseded > self.data.length / 2 { Some(self.compact()?) } else { None };
+        self.data.file.sync_all()?;
+        self.write_index()?;
+        if let Some(superseded) = superseded {
+            fs::remove_file(superseded)?;
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result {
+        self.cache.clear();
+        self.recency.clear();
+        self.data.file.set_len(0)?;
+        self.data.length = 0;
+        self.data.superseded = 0;
+        self.data.segments.clear();
+        self.write_index()
+    }
+
+    fn drain(&mut self, visitor: &mut dyn FnMut(u16, Account) -> Result) -> Result {
+        self.persist()?;
+        self.cache.clear();
+        self.recency.clear();
+        for client in self.data.clients() {
+            if let Some(account) = self.data.load(client, true)? {
+                visitor(client, account)?;
+            }
+        }
+        Ok(())
+    }
+    fn in_memory(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::Decimal;
+    use crate::account::Policy;
+    use crate::instructions::{Instruction, Operation, Transaction};
+    use std::io::{Read, Seek, SeekFrom};
+    use super::{AccountStore, DiskStore, MemoryStore, MAX_SEGMENTS};
+
+    fn deposit(store: &mut dyn AccountStore, client: u16, tx: u32, amount: i64) {
+        let account = store.get_or_create(client).expect("failed to get account");
+        let instruction = Instruction::Deposit(Transaction::new(client, tx, Decimal::new(amount, 0)));
+        account.apply(instruction, &Policy::default()).expect("failed to deposit");
+    }
+
+    fn balances(store: &mut dyn AccountStore) -> Vec<(u16, Decimal)> {
+        let mut balances = Vec::new();
+        store.for_each(&mut |client, account| {
+            balances.push((client, account.total()));
+            Ok(())
+        }).expect("failed to iterate");
+        balances.sort();
+        balances
+    }
+
+    #[test]
+    fn memory_store() {
+        let mut store = MemoryStore::default();
+        deposit(&mut store, 1, 1, 10);
+        deposit(&mut store, 2, 2, 20);
+        deposit(&mut store, 1, 3, 5);
+        assert!(store.persist().is_ok());
+
+        assert_eq!(balances(&mut store), vec![(1, Decimal::new(15, 0)), (2, Decimal::new(20, 0))]);
+        let mut drained = Vec::new();
+        store.drain(&mut |client, account| {
+            drained.push((client, account.total()));
+            Ok(())
+        }).expect("failed to drain");
+        drained.sort();
+        assert_eq!(drained, vec![(1, Decimal::new(15, 0)), (2, Decimal::new(20, 0))]);
+        assert_eq!(balances(&mut store), vec![]);
+
+        deposit(&mut store, 3, 4, 30);
+        assert!(store.clear().is_ok());
+        assert_eq!(balances(&mut store), vec![]);
+    }
+
+    #[test]
+    fn disk_store_evicts_and_reloads() {
+        let directory = tempfile::tempdir().expect("failed to create temporary directory");
+        let mut store = DiskStore::open(directory.path(), 2).expect("failed to open store");
+        deposit(&mut store, 1, 1, 10);
+        deposit(&mut store, 2, 2, 20);
+        deposit(&mut store, 3, 3, 30);
+        assert_eq!(store.cache.len(), 2);
+        assert_eq!(store.data.clients(), vec![1]);
+
+        deposit(&mut store, 1, 4, 5);
+        assert_eq!(balances(&mut store), vec![
+            (1, Decimal::new(15, 0)), (2, Decimal::new(20, 0)), (3, Decimal::new(30, 0))
+        ]);
+
+        assert!(store.persist().is_ok());
+        let mut store = DiskStore::open(directory.path(), 1).expect("failed to reopen store");
+        assert_eq!(balances(&mut store), vec![
+            (1, Decimal::new(15, 0)), (2, Decimal::new(20, 0)), (3, Decimal::new(30, 0))
+        ]);
+
+        // History is restored as well, so the deposit can be disputed after reload
+        let account = store.get_or_create(1).expect("failed to get account");
+        let instruction = Instruction::Dispute(crate::instructions::Operation::new(1, 4));
+        assert!(account.apply(instruction, &Policy::default()).is_ok());
+        assert_eq!(account.held(), Decimal::new(5, 0));
+
+        let mut drained = 0;
+        store.drain(&mut |_, _| {
+            drained += 1;
+            Ok(())
+        }).expect("failed to drain");
+        assert_eq!(drained, 3);
+        assert!(store.cache.is_empty());
+        // Drained accounts stay persisted, with the dispute made before
+        assert_eq!(store.get_or_create(1).expect("failed to get account").held(), Decimal::new(5, 0));
+
+        deposit(&mut store, 4, 5, 40);
+        store.persist().expect("failed to persist");
+        assert!(store.clear().is_ok());
+        assert_eq!(balances(&mut store), vec![]);
+        let mut store = DiskStore::open(directory.path(), 1).expect("failed to reopen store");
+        assert_eq!(balances(&mut store), vec![]);
+    }
+
+    #[test]
+    fn disk_store_least_recently_used() {
+        let directory = tempfile::tempdir().expect("failed to create temporary directory");
+        let mut store = DiskStore::open(directory.path(), 2).expect("failed to open store");
+        deposit(&mut store, 1, 1, 10);
+        deposit(&mut store, 2, 2, 20);
+        deposit(&mut store, 1, 3, 5);
+        deposit(&mut store, 3, 4, 30);
+        // Client 1 was used after client 2, which is the one written out
+        assert_eq!(store.data.clients(), vec![2]);
+        let mut cached: Vec<u16> = store.cache.keys().copied().collect();
+        cached.sort();
+        assert_eq!(cached, vec![1, 3]);
+    }
+
+    #[test]
+    fn disk_store_funds_only() {
+        let directory = tempfile::tempdir().expect("failed to create temporary directory");
+        let mut store = DiskStore::open(directory.path(), 1).expect("failed to open store");
+        deposit(&mut store, 1, 1, 10);
+        deposit(&mut store, 2, 2, 20);
+
+        // Deposit made without loading the history, which is loaded along it once it's asked for
+        let account = store.get_or_create_funds(1).expect("failed to get account");
+        assert_eq!(account.transactions().count(), 0);
+        account.apply(Instruction::Deposit(Transaction::new(1, 3, Decimal::new(5, 0))), &Policy::default()).expect("failed to deposit");
+        assert!(store.cache[&1].partial);
+        let account = store.get_or_create(1).expect("failed to get account");
+        assert_eq!(account.transactions().collect::<Vec<_>>(), vec![1, 3]);
+        assert!(account.apply(Instruction::Dispute(Operation::new(1, 1)), &Policy::default()).is_ok());
+
+        store.get_or_create_funds(2).expect("failed to get account");
+        assert!(store.cache[&2].partial);
+        assert_eq!(balances(&mut store), vec![(1, Decimal::new(15, 0)), (2, Decimal::new(20, 0))]);
+        assert!(!store.cache[&2].partial);
+        assert_eq!(store.get_or_create(1).expect("failed to get account").held(), Decimal::new(10, 0));
+    }
+
+    #[test]
+    fn disk_store_appends_changes() {
+        let directory = tempfile::tempdir().expect("failed to create temporary directory");
+        let mut store = DiskStore::open(directory.path(), 1).expect("failed to open store");
+        for tx in 1..=3 {
+            deposit(&mut store, 1, tx, 10);
+        }
+        deposit(&mut store, 2, 9, 1);
+        deposit(&mut store, 1, 4, 10);
+        deposit(&mut store, 2, 10, 1);
+
+        // The account written again holds its funds and the deposit made since only
+        let segments = &store.data.segments[&1];
+        assert_eq!(segments.len(), 2);
+        let mut segment = Vec::new();
+        store.data.file.seek(SeekFrom::Start(segments[1].offset)).expect("failed to seek");
+        Read::by_ref(&mut store.data.file).take(segments[1].length).read_to_end(&mut segment).expect("failed to read");
+        assert_eq!(String::from_utf8(segment).expect("failed to stringify the segment"), "account 1 40 0 40 0 -\ntx 4 10 undisputed 0\nend\n");
+        assert_eq!(balances(&mut store), vec![(1, Decimal::new(40, 0)), (2, Decimal::new(2, 0))]);
+
+        // Segments of a client are superseded by its whole account once there are too many of them
+        for tx in 0..MAX_SEGMENTS as u32 * 2 {
+            deposit(&mut store, 1, 100 + tx, 1);
+            deposit(&mut store, 2, 200 + tx, 1);
+        }
+        assert!(store.data.segments[&1].len() <= MAX_SEGMENTS);
+        assert!(store.data.superseded > 0);
+        store.persist().expect("failed to persist");
+        assert_eq!(store.data.superseded, 0);
+        assert!(!directory.path().join("accounts-0.data").exists());
+        let total = Decimal::from(40 + MAX_SEGMENTS as i64 * 2);
+
+        // Segments written after the store was last persisted are cut off when it's opened again
+        deposit(&mut store, 3, 7, 1);
+        deposit(&mut store, 1, 8, 1);
+        drop(store);
+        let mut store = DiskStore::open(directory.path(), 1).expect("failed to reopen store");
+        assert_eq!(balances(&mut store), vec![(1, total), (2, Decimal::from(2 + MAX_SEGMENTS as i64 * 2))]);
+    }
+}
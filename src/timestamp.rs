@@ -0,0 +1,199 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::errors::TransactionSystemError;
+
+const SECONDS_PER_DAY: i64 = 86_400;
+/// Range of instants written with four-digit years, 0000-01-01T00:00:00Z to 9999-12-31T23:59:59Z
+const EARLIEST: i64 = -62_167_219_200;
+const LATEST: i64 = 253_402_300_799;
+
+/// Instant of an instruction, in whole seconds since the Unix epoch; fractions of seconds are dropped
+///
+/// Read either as RFC 3339 date and time with an offset, like `2023-04-01T12:30:00+02:00`, or as
+/// the number of seconds since the epoch, like `1680345000`. Written as RFC 3339 in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(i64);
+
+impl Timestamp {
+    pub fn from_unix(seconds: i64) -> Option<Self> {
+        (EARLIEST..=LATEST).contains(&seconds).then_some(Self(seconds))
+    }
+
+    pub fn unix(&self) -> i64 {
+        self.0
+    }
+
+    /// Time elapsed since the earlier instant, `None` when it's later actually
+    pub fn since(&self, earlier: Timestamp) -> Option<Duration> {
+        u64::try_from(self.0 - earlier.0).ok().map(Duration::from_secs)
+    }
+
+    fn parse_rfc3339(text: &[u8]) -> Option<Self> {
+        // Shortest form is `YYYY-MM-DDTHH:MM:SSZ`
+        if text.len() < 20 || text[4] != b'-' || text[7] != b'-' || text[13] != b':' || text[16] != b':'
+            || !matches!(text[10], b'T' | b't' | b' ') {
+            return None;
+        }
+        let (year, month, day) = (number(&text[0..4])?, number(&text[5..7])?, number(&text[8..10])?);
+        let (hour, minute, second) = (number(&text[11..13])?, number(&text[14..16])?, number(&text[17..19])?);
+        // Leap second is let through as the first second of the next minute
+        if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) || hour > 23 || minute > 59 || second > 60 {
+            return None;
+        }
+
+        let mut rest = &text[19..];
+        if let [b'.', fraction @ ..] = rest {
+            let digits = fraction.iter().take_while(|c| c.is_ascii_digit()).count();
+            if digits == 0 {
+                return None;
+            }
+            rest = &fraction[digits..];
+        }
+        let offset = match rest {
+            [b'Z' | b'z'] => 0,
+            [sign @ (b'+' | b'-'), hours @ .., b':', _, _] if hours.len() == 2 => {
+                let (hours, minutes) = (number(hours)?, number(&rest[4..6])?);
+                if hours > 23 || minutes > 59 {
+                    return None;
+                }
+                let offset = hours * 3600 + minutes * 60;
+                if *sign == b'-' { -offset } else { offset }
+            },
+            _ => return None,
+        };
+
+        let days = days_from_civil(year, month, day);
+        Self::from_unix(days * SECONDS_PER_DAY + hour * 3600 + minute * 60 + second - offset)
+    }
+}
+
+/// Decimal number of the ASCII digits, all of them
+fn number(digits: &[u8]) -> Option<i64> {
+    digits.iter().try_fold(0, |number, &digit| {
+        digit.is_ascii_digit().then(|| number * 10 + i64::from(digit - b'0'))
+    })
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since the epoch of the date in the proleptic Gregorian calendar, after Howard Hinnant's
+/// `days_from_civil`, counting years from March so the leap day comes last
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Date of the days since the epoch, the inverse of `days_from_civil()`
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+impl FromStr for Timestamp {
+    type Err = TransactionSystemError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let digits = text.strip_prefix('-').unwrap_or(text);
+        let timestamp = if !digits.is_empty() && digits.bytes().all(|c| c.is_ascii_digit()) {
+            text.parse().ok().and_then(Self::from_unix)
+        } else {
+            Self::parse_rfc3339(text.as_bytes())
+        };
+        timestamp.ok_or_else(|| TransactionSystemError::RowError { message: format!("invalid timestamp {:?}", text) })
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (year, month, day) = civil_from_days(self.0.div_euclid(SECONDS_PER_DAY));
+        let seconds = self.0.rem_euclid(SECONDS_PER_DAY);
+        write!(f, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, seconds / 3600, seconds / 60 % 60, seconds % 60)
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Parsed from its text, as CSV fields and JSON Lines values alike are handed over as text
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use super::Timestamp;
+
+    fn unix(text: &str) -> Option<i64> {
+        text.parse::<Timestamp>().ok().map(|timestamp| timestamp.unix())
+    }
+
+    #[test]
+    fn rfc3339() {
+        assert_eq!(unix("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(unix("2023-04-01T12:30:00+02:00"), Some(1_680_345_000));
+        assert_eq!(unix("2023-04-01t10:30:00.999z"), Some(1_680_345_000));
+        assert_eq!(unix("2023-04-01 05:30:00-05:00"), Some(1_680_345_000));
+        assert_eq!(unix("2024-02-29T00:00:00Z"), Some(1_709_164_800));
+        assert_eq!(unix("1969-12-31T23:59:59Z"), Some(-1));
+        assert_eq!(unix("2016-12-31T23:59:60Z"), unix("2017-01-01T00:00:00Z"));
+        assert_eq!(unix("0000-01-01T00:00:00Z"), Some(-62_167_219_200));
+
+        for text in ["2023-02-29T00:00:00Z", "2023-13-01T00:00:00Z", "2023-04-01T24:00:00Z", "2023-04-01T12:30:00",
+                     "2023-04-01T12:30:00.Z", "2023-04-01T12:30:00+0200", "2023-04-01", "0000-01-01T00:00:00+00:01", ""] {
+            assert_eq!(unix(text), None, "parsed {}", text);
+        }
+    }
+
+    #[test]
+    fn unix_epoch() {
+        assert_eq!(unix("1680345000"), Some(1_680_345_000));
+        assert_eq!(unix("-1"), Some(-1));
+        assert_eq!(unix("253402300800"), None);
+        assert_eq!(unix("1680345000.5"), None);
+        assert_eq!(unix("-"), None);
+    }
+
+    #[test]
+    fn display() {
+        for text in ["1970-01-01T00:00:00Z", "2024-02-29T23:59:59Z", "1969-12-31T23:59:59Z", "0000-03-01T00:00:00Z", "9999-12-31T23:59:59Z"] {
+            assert_eq!(text.parse::<Timestamp>().expect("failed to parse").to_string(), text);
+        }
+        assert_eq!("1680345000".parse::<Timestamp>().expect("failed to parse").to_string(), "2023-04-01T10:30:00Z");
+    }
+
+    #[test]
+    fn since() {
+        let (earlier, later) = (Timestamp::from_unix(100).unwrap(), Timestamp::from_unix(160).unwrap());
+        assert_eq!(later.since(earlier), Some(Duration::from_secs(60)));
+        assert_eq!(earlier.since(later), None);
+    }
+}
@@ -0,0 +1,308 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::path::PathBuf;
+use log::{debug, info};
+
+use crate::codec::{optional, Fields};
+use crate::errors::TransactionSystemError;
+use crate::instructions::Instruction;
+use crate::rejects::Reject;
+use crate::result::Result;
+
+const CHECKPOINT_FILE: &str = "checkpoint";
+const LOG_FILE: &str = "wal.log";
+
+/// Append-only log of executed instructions with periodic checkpoints of the whole state, kept in
+/// a directory to recover from after the process died; the log is truncated on every checkpoint
+///
+/// The log holds `input <name>` lines switching the input the following lines come from,
+/// `apply <sequence> <row> <instruction>` lines of applied instructions, `reject <sequence> <row> <instruction>`
+/// lines of rejected ones, replayed as well since they reserve ids and advance the clock of the retention,
+/// `skip <row>` lines of bad rows and `progress <rows>` lines of inputs consumed to the end. A row reported
+/// as rejected is preceded by the `report <json>` line of its reject, which counts only along with the row.
+/// The checkpoint holds the sequence of the last instruction it covers, the `Tally` of the ingestion,
+/// rows consumed of every input and the snapshot of the accounts.
+#[derive(Debug)]
+pub struct WriteAheadLog {
+    directory: PathBuf,
+    interval: u64,
+    log: Option<BufWriter<File>>,
+    sequence: u64,
+    checkpointed: u64,
+    progress: HashMap<String, u64>,
+    input: Option<String>,
+}
+
+/// Rows and bad rows counted by the ingestion, and the length of the rejects report when it's
+/// written to a file, as of a checkpoint
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Tally {
+    pub rows: u64,
+    pub errors: u64,
+    pub report: Option<u64>,
+}
+
+/// What the replay of the log adds to the `Tally` of the checkpoint, with the rejects to report again
+#[derive(Debug, Default)]
+pub(crate) struct Replayed {
+    pub rows: u64,
+    pub errors: u64,
+    pub rejects: Vec<Reject>,
+}
+
+impl WriteAheadLog {
+    /// Opens the log in the directory, to be recovered before any new instruction is logged;
+    /// checkpoint is made after every `interval` instructions
+    pub fn open(directory: impl Into<PathBuf>, interval: u64) -> std::result::Result<Self, TransactionSystemError> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+        Ok(Self {
+            directory,
+            interval: interval.max(1),
+            log: None,
+            sequence: 0,
+            checkpointed: 0,
+            progress: HashMap::new(),
+            input: None,
+        })
+    }
+
+    pub fn recovered(&self) -> bool {
+        self.log.is_some()
+    }
+
+    /// Rows of the input consumed already, to be skipped
+    pub fn progress(&self, input: &str) -> u64 {
+        self.progress.get(input).copied().unwrap_or(0)
+    }
+
+    fn path(&self, file: &str) -> PathBuf {
+        self.directory.join(file)
+    }
+
+    /// Reads the checkpoint header and hands over the rest of it, the snapshot, to be restored;
+    /// gives `None` when there's no checkpoint yet
+    pub(crate) fn read_checkpoint(&mut self, restore: impl FnOnce(&mut dyn BufRead) -> Result) -> std::result::Result<Option<Tally>, TransactionSystemError> {
+        let file = match File::open(self.path(CHECKPOINT_FILE)) {
+            Ok(file) => file,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        let mut source = BufReader::new(file);
+
+        let mut line = String::new();
+        source.read_line(&mut line)?;
+        let mut fields = Fields::new(&line);
+        fields.expect("checkpoint")?;
+        self.checkpointed = fields.parse()?;
+        self.sequence = self.checkpointed;
+        let inputs: usize = fields.parse()?;
+        let tally = Tally { rows: fields.parse()?, errors: fields.parse()?, report: fields.optional()? };
+        for _ in 0..inputs {
+            line.clear();
+            source.read_line(&mut line)?;
+            let mut fields = Fields::new(&line);
+            fields.expect("progress")?;
+            let rows = fields.parse()?;
+            self.progress.insert(Self::rest(&line, 2, &fields)?, rows);
+        }
+
+        restore(&mut source)?;
+        debug!("Checkpoint at sequence {} read", self.checkpointed);
+        Ok(Some(tally))
+    }
+
+    /// Replays instructions logged after the checkpoint and opens the log for appending, cutting off
+    /// the last line when it was written only partially, along with a report line left without its row
+    pub(crate) fn replay(&mut self, execute: &mut dyn FnMut(Instruction) -> Result) -> std::result::Result<Replayed, TransactionSystemError> {
+        let path = self.path(LOG_FILE);
+        let consumed: u64 = self.progress.values().sum();
+        let mut replayed = Replayed::default();
+        let mut valid = 0;
+        if let Ok(file) = File::open(&path) {
+            let mut source = BufReader::new(file);
+            let mut input = None;
+            let mut reported = None;
+            let mut read = 0;
+            let mut line = String::new();
+            loop {
+                line.clear();
+                if source.read_line(&mut line)? == 0 || !line.ends_with('\n') {
+                    break;
+                }
+                read += line.len() as u64;
+
+                let mut fields = Fields::new(&line);
+                let tag = fields.next_str()?;
+                if reported.is_some() && !matches!(tag, "apply" | "reject" | "skip") {
+                    return Err(fields.corrupted("report without its row"));
+                }
+                match tag {
+                    "input" => input = Some(Self::rest(&line, 1, &fields)?),
+                    "report" => {
+                        let reject = serde_json::from_str(&Self::rest(&line, 1, &fields)?)
+                            .map_err(|error| fields.corrupted(&format!("invalid report {}", error)))?;
+                        reported = Some(reject);
+                        continue;
+                    },
+                    tag @ ("apply" | "reject") => {
+                        let sequence: u64 = fields.parse()?;
+                        let row: u64 = fields.parse()?;
+                        let instruction = Instruction::decode(&mut fields)?;
+                        if sequence > self.checkpointed {
+                            let input = input.clone().ok_or_else(|| fields.corrupted("instruction of unknown input"))?;
+                            match (tag, execute(instruction)) {
+                                ("apply", Err(error)) => return Err(fields.corrupted(&format!("replay failure {}", error))),
+                                ("reject", Ok(())) => return Err(fields.corrupted("replay of rejected instruction applied")),
+                                (_, Err(error @ TransactionSystemError::IOError(_))) => return Err(error),
+                                _ => (),
+                            }
+                            self.sequence = sequence;
+                            self.progress.insert(input, row);
+                            replayed.rejects.extend(reported.take());
+                        }
+                    },
+                    "skip" => {
+                        let row: u64 = fields.parse()?;
+                        let input = input.clone().ok_or_else(|| fields.corrupted("bad row of unknown input"))?;
+                        let progress = self.progress.entry(input).or_default();
+                        // Covered by the checkpoint unless it's past the rows consumed by then
+                        if row > *progress {
+                            *progress = row;
+                            replayed.errors += 1;
+                            replayed.rejects.extend(reported.take());
+                        }
+                    },
+                    "progress" => {
+                        let rows: u64 = fields.parse()?;
+                        let input = input.clone().ok_or_else(|| fields.corrupted("progress of unknown input"))?;
+                        let progress = self.progress.entry(input).or_default();
+                        *progress = rows.max(*progress);
+                    },
+                    tag => return Err(fields.corrupted(&format!("unexpected {}", tag))),
+                }
+                reported = None;
+                valid = read;
+            }
+            replayed.rows = self.progress.values().sum::<u64>() - consumed;
+            info!("Replayed log up to sequence {}", self.sequence);
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        file.set_len(valid)?;
+        self.log = Some(BufWriter::new(file));
+        Ok(replayed)
+    }
+
+    /// The rest of the line after given number of fields, used for names which may contain spaces
+    fn rest(line: &str, skip: usize, fields: &Fields) -> std::result::Result<String, TransactionSystemError> {
+        line.trim_end_matches('\n').splitn(skip + 1, ' ').nth(skip).map(str::to_owned)
+            .ok_or_else(|| fields.corrupted("missing name"))
+    }
+
+    fn writer(&mut self, input: &str) -> std::result::Result<&mut BufWriter<File>, TransactionSystemError> {
+        let log = self.log.as_mut().ok_or_else(|| TransactionSystemError::CorruptedDataError {
+            message: "write-ahead log used before recovery".to_owned(),
+        })?;
+        if self.input.as_deref() != Some(input) {
+            writeln!(log, "input {}", input)?;
+            self.input = Some(input.to_owned());
+        }
+        Ok(log)
+    }
+
+    /// Logs the instruction, encoded with `Instruction::encode()`, executed from the row of the input,
+    /// whether it was applied or rejected, and the reject reported for the row
+    pub(crate) fn append(&mut self, input: &str, row: u64, instruction: &str, applied: bool, reported: Option<&Reject>) -> Result {
+        self.sequence += 1;
+        let sequence = self.sequence;
+        let tag = if applied { "apply" } else { "reject" };
+        let log = self.writer(input)?;
+        Self::report(log, reported)?;
+        writeln!(log, "{} {} {} {}", tag, sequence, row, instruction)?;
+        Ok(())
+    }
+
+    /// Logs the row of the input was skipped as a bad one, and the reject reported for it
+    pub(crate) fn skip(&mut self, input: &str, row: u64, reported: Option<&Reject>) -> Result {
+        let log = self.writer(input)?;
+        Self::report(log, reported)?;
+        writeln!(log, "skip {}", row)?;
+        Ok(())
+    }
+
+    fn report(log: &mut BufWriter<File>, reported: Option<&Reject>) -> Result {
+        if let Some(reject) = reported {
+            write!(log, "report ")?;
+            reject.write_json(log)?;
+        }
+        Ok(())
+    }
+
+    /// Logs the input was consumed to the end
+    pub(crate) fn complete(&mut self, input: &str, rows: u64) -> Result {
+        writeln!(self.writer(input)?, "progress {}", rows)?;
+        self.progress.insert(input.to_owned(), rows);
+        self.flush()
+    }
+
+    pub(crate) fn flush(&mut self) -> Result {
+        if let Some(log) = self.log.as_mut() {
+            log.flush()?;
+            log.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Removes the checkpoint and the log once the run is complete, so the next one starts afresh
+    /// rather than skipping inputs of the same names
+    pub(crate) fn retire(&mut self) -> Result {
+        self.log = None;
+        let temporary = self.path(CHECKPOINT_FILE).with_extension("tmp");
+        for path in [self.path(LOG_FILE), self.path(CHECKPOINT_FILE), temporary] {
+            match fs::remove_file(path) {
+                Err(error) if error.kind() != ErrorKind::NotFound => return Err(error.into()),
+                _ => (),
+            }
+        }
+        self.sequence = 0;
+        self.checkpointed = 0;
+        self.progress.clear();
+        self.input = None;
+        debug!("Write-ahead log retired");
+        Ok(())
+    }
+
+    pub(crate) fn checkpoint_due(&self) -> bool {
+        self.sequence - self.checkpointed >= self.interval
+    }
+
+    /// Writes the checkpoint with the tally and the snapshot of the state, current input consumed up
+    /// to the row, and truncates the log
+    pub(crate) fn checkpoint(&mut self, input: Option<(&str, u64)>, tally: Tally, snapshot: impl FnOnce(&mut dyn Write) -> Result) -> Result {
+        if let Some((input, row)) = input {
+            self.progress.insert(input.to_owned(), row);
+        }
+        self.flush()?;
+
+        let path = self.path(CHECKPOINT_FILE);
+        let temporary = path.with_extension("tmp");
+        let mut sink = BufWriter::new(File::create(&temporary)?);
+        writeln!(sink, "checkpoint {} {} {} {} {}", self.sequence, self.progress.len(), tally.rows, tally.errors, optional(tally.report))?;
+        for (input, rows) in &self.progress {
+            writeln!(sink, "progress {} {}", rows, input)?;
+        }
+        snapshot(&mut sink)?;
+        sink.flush()?;
+        sink.get_ref().sync_all()?;
+        fs::rename(temporary, path)?;
+
+        // Instructions left in the log by a crash right here are skipped by their sequence, bad rows by their row
+        self.log = Some(BufWriter::new(File::create(self.path(LOG_FILE))?));
+        self.input = None;
+        self.checkpointed = self.sequence;
+        debug!("Checkpoint at sequence {} written", self.checkpointed);
+        Ok(())
+    }
+}